@@ -0,0 +1,236 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use wgpull_shared::{
+    crypto::{open_with_passphrase, seal_with_passphrase},
+    file::FileAccessor,
+    state::{load_state_key, parse_versioned_state, serialize_versioned_state},
+};
+
+use super::{
+    config::LighthouseConfig,
+    state::{new_state_epoch, LighthouseState, LIGHTHOUSE_STATE_MIGRATIONS},
+    store::open_state_store,
+};
+
+/// Exports the state to a portable archive encrypted with the passphrase.
+///
+/// The archive contains the versioned state, archives of older versions are migrated
+/// on import just like state files. The archive is independent of the state key.
+pub fn export_state(state: &LighthouseState, passphrase: &str) -> Result<String> {
+    let content = serialize_versioned_state(state, LIGHTHOUSE_STATE_MIGRATIONS, None)?;
+    Ok(seal_with_passphrase(passphrase, content.as_bytes())?)
+}
+
+/// Imports the state from an archive created by `export_state`.
+///
+/// The imported state gets a new epoch, nodes may know newer revisions of the exported state.
+pub fn import_state(path: &str, archive: &str, passphrase: &str) -> Result<LighthouseState> {
+    let content = String::from_utf8(open_with_passphrase(passphrase, archive)?)?;
+    let (mut state, _): (LighthouseState, _) =
+        parse_versioned_state(path, &content, LIGHTHOUSE_STATE_MIGRATIONS, None)?;
+    state.epoch = new_state_epoch();
+    Ok(state)
+}
+
+/// Reads the passphrase from the file, ignoring surrounding whitespace.
+pub async fn read_passphrase(path: &str, accessor: &dyn FileAccessor) -> Result<String> {
+    let passphrase = accessor
+        .read(path)
+        .await
+        .map_err(|err| anyhow!("Error reading passphrase file {}: {}", path, err))?;
+    let passphrase = passphrase.trim();
+    if passphrase.is_empty() {
+        return Err(anyhow!("Passphrase file {} is empty", path));
+    }
+    Ok(passphrase.to_string())
+}
+
+/// Exports the stored state of the configured state store to an archive file.
+pub async fn export_archive_file(
+    config: &LighthouseConfig,
+    archive_path: &str,
+    passphrase_file: &str,
+    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+) -> Result<()> {
+    let key = load_state_key(config.state_key_file.as_deref(), file_accessor.as_ref()).await?;
+    let store = open_state_store(
+        &config.state_store,
+        &config.state_file,
+        file_accessor.clone(),
+        key,
+    )?;
+    let state = store
+        .load()
+        .await?
+        .ok_or_else(|| anyhow!("No lighthouse state found in {}", config.state_file))?;
+
+    let passphrase = read_passphrase(passphrase_file, file_accessor.as_ref()).await?;
+    let archive = export_state(&state, &passphrase)?;
+    file_accessor.write_atomic(archive_path, &archive).await?;
+
+    info!(
+        "Exported state with {} nodes to {}",
+        state.nodes.len(),
+        archive_path
+    );
+    Ok(())
+}
+
+/// Imports an archive file into the configured state store.
+///
+/// An existing state is only replaced if forced, the lighthouse must not be running.
+pub async fn import_archive_file(
+    config: &LighthouseConfig,
+    archive_path: &str,
+    passphrase_file: &str,
+    force: bool,
+    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+) -> Result<()> {
+    let passphrase = read_passphrase(passphrase_file, file_accessor.as_ref()).await?;
+    let archive = file_accessor
+        .read(archive_path)
+        .await
+        .map_err(|err| anyhow!("Error reading archive {}: {}", archive_path, err))?;
+    let state = import_state(archive_path, &archive, &passphrase)?;
+
+    let key = load_state_key(config.state_key_file.as_deref(), file_accessor.as_ref()).await?;
+    let store = open_state_store(
+        &config.state_store,
+        &config.state_file,
+        file_accessor.clone(),
+        key,
+    )?;
+    if !force && store.load().await?.is_some() {
+        return Err(anyhow!(
+            "State {} already exists, use --force to replace it",
+            config.state_file
+        ));
+    }
+    store.save(&state).await?;
+
+    info!(
+        "Imported state with {} nodes from {}",
+        state.nodes.len(),
+        archive_path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use wgpull_shared::file::FileAccessor;
+
+    use super::{export_archive_file, export_state, import_archive_file, import_state};
+    use crate::{
+        config::LighthouseConfig,
+        mock::MockFileAccessor,
+        peer_pair::PeerPair,
+        state::{LighthouseNodeLease, LighthouseState},
+        store::StateStoreType,
+    };
+
+    fn lighthouse_state() -> LighthouseState {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_nanos(1_720_000_000_123_456_789);
+        let lease = LighthouseNodeLease {
+            hostname: "node1".to_string(),
+            public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+            endpoint_host: "node1.example.com".to_string(),
+            endpoint_port: 52720,
+            allowed_ips: vec!["10.140.0.10/32".to_string()],
+            persistent_keepalive: 15,
+            route_allowed_ips: true,
+            last_rotation: time,
+            last_seen: time,
+            peers_revision: 3,
+            peers_digest: "digest".to_string(),
+        };
+        LighthouseState {
+            nodes: HashMap::from([("node1".to_string(), lease)]),
+            preshared_keys: HashMap::from([(
+                PeerPair::new("node1".to_string(), "node2".to_string()),
+                "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=".to_string(),
+            )]),
+            preshared_keys_created: HashMap::from([(
+                PeerPair::new("node1".to_string(), "node2".to_string()),
+                time,
+            )]),
+            last_modified: time,
+            revision: 3,
+            epoch: 1,
+        }
+    }
+
+    fn lighthouse_config() -> LighthouseConfig {
+        toml::from_str(
+            r#"
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/var/lib/lighthouse.state"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_export_import_state() {
+        let state = lighthouse_state();
+        let archive = export_state(&state, "secret").unwrap();
+        assert!(!archive.contains("node1"));
+
+        // the imported state gets a new epoch, so nodes can't reuse ETags of the old state
+        let mut imported = import_state("archive", &archive, "secret").unwrap();
+        assert_ne!(imported.epoch, state.epoch);
+        imported.epoch = state.epoch;
+        assert_eq!(imported, state);
+        assert!(import_state("archive", &archive, "wrong").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_import_archive_file() {
+        let mut config = lighthouse_config();
+        let accessor = Arc::new(MockFileAccessor::default());
+        accessor.write("/passphrase", "secret\n").await.unwrap();
+        let state = lighthouse_state();
+        state
+            .save(&config.state_file, accessor.as_ref(), None)
+            .await
+            .unwrap();
+
+        export_archive_file(&config, "/archive", "/passphrase", accessor.clone())
+            .await
+            .unwrap();
+
+        // refuses to replace an existing state unless forced
+        assert!(
+            import_archive_file(&config, "/archive", "/passphrase", false, accessor.clone())
+                .await
+                .is_err()
+        );
+
+        // import into a fresh state file
+        config.state_file = "/srv/lighthouse.state".to_string();
+        assert_eq!(config.state_store, StateStoreType::Toml);
+        import_archive_file(&config, "/archive", "/passphrase", false, accessor.clone())
+            .await
+            .unwrap();
+        let mut imported = LighthouseState::from_file(&config.state_file, accessor.as_ref(), None)
+            .await
+            .unwrap()
+            .unwrap();
+        imported.epoch = state.epoch;
+        assert_eq!(imported, state);
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use wgpull_shared::{
+    file::{check_private_file, FileAccessor},
+    state::{load_state_key, serialize_versioned_state, SecretFields},
+};
+
+use super::{
+    archive::read_passphrase,
+    config::LighthouseConfig,
+    state::{LighthouseState, LIGHTHOUSE_STATE_MIGRATIONS},
+    store::open_state_store,
+};
+
+/// Loads the stored state of the configured state store, none if there is no state yet.
+async fn load_state(
+    config: &LighthouseConfig,
+    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+) -> Result<Option<LighthouseState>> {
+    let key = load_state_key(config.state_key_file.as_deref(), file_accessor.as_ref()).await?;
+    let store = open_state_store(
+        &config.state_store,
+        &config.state_file,
+        file_accessor.clone(),
+        key,
+    )?;
+    store.load().await
+}
+
+/// Checks the configuration and everything it refers to without starting the server,
+/// returns a summary of the checked configuration.
+pub async fn check_config(
+    config: &LighthouseConfig,
+    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+) -> Result<String> {
+    if let Some(snapshot) = &config.snapshot {
+        read_passphrase(&snapshot.passphrase_file, file_accessor.as_ref()).await?;
+    }
+
+    check_private_file(&config.state_file)?;
+    let nodes = match load_state(config, file_accessor).await? {
+        Some(state) => format!("{} nodes", state.nodes.len()),
+        None => "no state yet".to_string(),
+    };
+
+    Ok(format!(
+        "Configuration OK, listening on {}, state {} ({})\n",
+        config.get_listen_addr(),
+        config.state_file,
+        nodes
+    ))
+}
+
+/// Returns the public key of the signing key, which is configured on the nodes to verify the
+/// documents signed by the lighthouse.
+pub fn public_key(config: &LighthouseConfig) -> Result<String> {
+    let key = config
+        .get_signing_key()?
+        .ok_or_else(|| anyhow!("No signing_key configured"))?;
+    Ok(format!("{}\n", key.public_key()))
+}
+
+/// Returns the stored state in the versioned TOML format, secrets are redacted unless requested.
+pub async fn dump_state(
+    config: &LighthouseConfig,
+    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+    show_secrets: bool,
+) -> Result<String> {
+    let mut state = load_state(config, file_accessor)
+        .await?
+        .ok_or_else(|| anyhow!("No lighthouse state found in {}", config.state_file))?;
+    if !show_secrets {
+        state.redact_secrets();
+    }
+    Ok(serialize_versioned_state(
+        &state,
+        LIGHTHOUSE_STATE_MIGRATIONS,
+        None,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use wgpull_shared::{file::FileAccessor, state::REDACTED_SECRET};
+
+    use super::{check_config, dump_state, public_key};
+    use crate::{
+        config::LighthouseConfig, mock::MockFileAccessor, peer_pair::PeerPair,
+        state::LighthouseState,
+    };
+
+    fn lighthouse_config(extra: &str) -> LighthouseConfig {
+        toml::from_str(&format!(
+            r#"
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/nonexistent/lighthouse.state"
+            {}
+            "#,
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_check_config() {
+        let accessor = Arc::new(MockFileAccessor::default());
+        let summary = check_config(&lighthouse_config(""), accessor.clone())
+            .await
+            .unwrap();
+        assert!(summary.contains("127.0.0.1:2001"));
+        assert!(summary.contains("no state yet"));
+
+        let config = lighthouse_config(
+            r#"
+            [snapshot]
+            passphrase_file = "/etc/passphrase"
+            "#,
+        );
+        assert!(check_config(&config, accessor.clone()).await.is_err());
+        accessor.write("/etc/passphrase", "secret").await.unwrap();
+        assert!(check_config(&config, accessor).await.is_ok());
+    }
+
+    #[test]
+    fn test_public_key() {
+        assert!(public_key(&lighthouse_config("")).is_err());
+        let key = public_key(&lighthouse_config("signing_key = \"signing\"")).unwrap();
+        assert_eq!(key.trim().len(), 44);
+        assert_eq!(
+            key,
+            public_key(&lighthouse_config("signing_key = \"signing\"")).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dump_state_redacts_secrets() {
+        let config = lighthouse_config("");
+        let accessor = Arc::new(MockFileAccessor::default());
+        assert!(dump_state(&config, accessor.clone(), false).await.is_err());
+
+        let pair = PeerPair::new("node1".to_string(), "node2".to_string());
+        let state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::from([(
+                pair,
+                "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=".to_string(),
+            )]),
+            preshared_keys_created: HashMap::new(),
+            last_modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+            revision: 1,
+            epoch: 1,
+        };
+        state
+            .save(&config.state_file, accessor.as_ref(), None)
+            .await
+            .unwrap();
+
+        let dump = dump_state(&config, accessor.clone(), false).await.unwrap();
+        assert!(dump.contains(REDACTED_SECRET));
+        assert!(!dump.contains("KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ="));
+
+        let dump = dump_state(&config, accessor, true).await.unwrap();
+        assert!(dump.contains("KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ="));
+    }
+}
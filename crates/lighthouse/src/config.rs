@@ -1,4 +1,185 @@
+use std::net::{IpAddr, SocketAddr};
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use serde::Deserialize;
+use wgpull_shared::{
+    config::{ConfigFile, ConfigProblems},
+    signing::{SigningError, SigningKey},
+    validation::{Validated, ValidationError},
+};
+
+use super::store::StateStoreType;
+
+/// Role of a lighthouse in a replicated set of lighthouses.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationRole {
+    /// The leader serves nodes and the replicated state to the followers.
+    Leader,
+    /// Followers replicate the state of the leader and only serve nodes once the
+    /// leader is unreachable.
+    Follower,
+}
+
+/// Replication configuration of a lighthouse.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ReplicationConfig {
+    /// Role of this lighthouse.
+    pub role: ReplicationRole,
+    /// Key used by the lighthouses to authenticate replication requests.
+    pub key: String,
+    /// URL of the leader lighthouse (followers only), e.g. https://10.11.0.3:2001/
+    #[serde(default)]
+    pub leader_url: Option<String>,
+    /// Allows a plain http:// leader_url, the replicated state contains the pre-shared keys
+    /// of all nodes.
+    #[serde(default)]
+    pub allow_insecure: bool,
+    /// Interval in seconds in which followers replicate the state of the leader.
+    #[serde(default = "default_replication_interval_seconds")]
+    pub interval_seconds: u64,
+    /// Time in seconds the leader has to be unreachable before a follower serves nodes.
+    #[serde(default = "default_replication_failover_seconds")]
+    pub failover_seconds: u64,
+}
+
+fn default_replication_interval_seconds() -> u64 {
+    10
+}
+
+fn default_replication_failover_seconds() -> u64 {
+    60
+}
+
+/// Scheduled encrypted snapshots of the lighthouse state.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SnapshotConfig {
+    /// File containing the passphrase used to encrypt the snapshots.
+    pub passphrase_file: String,
+    /// Interval in seconds in which snapshots are taken.
+    #[serde(default = "default_snapshot_interval_seconds")]
+    pub interval_seconds: u64,
+    /// Number of snapshots to keep, older snapshots are removed.
+    #[serde(default = "default_snapshot_keep")]
+    pub keep: usize,
+}
+
+fn default_snapshot_interval_seconds() -> u64 {
+    3600
+}
+
+fn default_snapshot_keep() -> usize {
+    24
+}
+
+/// Rate limiting of requests to the api by source address.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Sustained number of requests per minute allowed from a single address, 0 disables the
+    /// rate limit.
+    pub requests_per_minute: u32,
+    /// Number of requests a single address can send at once before it is rate limited.
+    pub burst: u32,
+    /// Number of authentication failures after which an address is banned, 0 disables bans.
+    pub max_auth_failures: u32,
+    /// Time in seconds an address is banned for, authentication failures older than this
+    /// are forgotten.
+    pub ban_seconds: u64,
+    /// Addresses that are never rate limited or banned.
+    pub exempt: Vec<IpNet>,
+    /// Reverse proxies the client address is taken from the `X-Forwarded-For` header of.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 120,
+            burst: 30,
+            max_auth_failures: 10,
+            ban_seconds: 600,
+            exempt: Vec::new(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// Access restrictions of the prometheus metrics, which expose the mesh topology.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct MetricsAccessConfig {
+    /// Addresses allowed to request the metrics, all addresses if empty.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Username of the basic authentication, required if a password is set.
+    #[serde(default = "default_metrics_username")]
+    pub username: String,
+    /// Password of the basic authentication, disabled if not set.
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+fn default_metrics_username() -> String {
+    "metrics".to_string()
+}
+
+/// Name of the `lighthouse_key` and `node_key` of the configuration in logs and metrics.
+pub const PRIMARY_KEY_NAME: &str = "primary";
+
+/// Keys accepted in addition to the primary keys, used to rotate the keys without updating the
+/// lighthouse and all nodes at the same time.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AdditionalKeyConfig {
+    /// Name of the keys in logs and metrics.
+    pub name: String,
+    /// Lighthouse key the nodes authenticate with.
+    pub lighthouse_key: String,
+    /// Node key the lighthouse answers challenges of nodes using this lighthouse key with.
+    pub node_key: String,
+    /// Time after which the keys are rejected (RFC 3339), accepted indefinitely if not set.
+    #[serde(default)]
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+/// Window of hours of the day (from start, inclusive, to end, exclusive) in which keys are rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(from = "(u8, u8)")]
+pub struct RotationWindow {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl From<(u8, u8)> for RotationWindow {
+    fn from((start, end): (u8, u8)) -> Self {
+        Self { start, end }
+    }
+}
+
+impl RotationWindow {
+    /// Whether or not the hour of the day is within the window.
+    pub fn contains(&self, hour: u8) -> bool {
+        hour >= self.start && hour < self.end
+    }
+}
+
+impl Validated for RotationWindow {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.end > 24 {
+            return Err(ValidationError::InvalidFormat(
+                "key_rotation_tod",
+                "Expected hours from 0 to 24",
+            ));
+        }
+        if self.start >= self.end {
+            return Err(ValidationError::InvalidFormat(
+                "key_rotation_tod",
+                "The start hour must be before the end hour",
+            ));
+        }
+        Ok(())
+    }
+}
 
 /// Lighthouse configuration.
 #[derive(Debug, Clone, Deserialize)]
@@ -7,23 +188,82 @@ pub struct LighthouseConfig {
     pub lighthouse_key: String,
     /// Key used by nodes to authenticate with the lighthouse server.
     pub node_key: String,
+    /// Keys accepted in addition to the primary keys during a key rotation.
+    #[serde(default)]
+    pub additional_keys: Vec<AdditionalKeyConfig>,
     /// Port to listen on for incoming connections.
     pub port: u16,
     /// Host to bind to for incoming connections.
-    pub bindhost: String,
+    pub bindhost: IpAddr,
     /// Interval in seconds to rotate wireguard private, public and preshared keys.
     pub key_rotation_interval_seconds: u64,
     /// Time of day (in min/max hours) to rotate wireguard private, public and preshared keys.
-    pub key_rotation_tod: (u8, u8),
+    pub key_rotation_tod: RotationWindow,
     /// The time in seconds to wait before a node is considered offline.
     pub node_timeout_seconds: u64,
     /// State file to store the lighthouse's state.
     pub state_file: String,
+    /// File containing the key to encrypt the pre-shared keys in the state file, falls back
+    /// to the `WGPULL_STATE_KEY` environment variable and the `wgpull-state-key` systemd credential.
+    #[serde(default)]
+    pub state_key_file: Option<String>,
+    /// Storage format of the state file (toml / sqlite).
+    #[serde(default)]
+    pub state_store: StateStoreType,
+    /// Maximum time in seconds a node watch request is held open waiting for changes.
+    #[serde(default = "default_watch_timeout_seconds")]
+    pub watch_timeout_seconds: u64,
+    /// Key used to authenticate requests to the admin api, the admin api is disabled if not set.
+    #[serde(default)]
+    pub admin_key: Option<String>,
+    /// Replication of the lighthouse state to other lighthouses, disabled if not set.
+    #[serde(default)]
+    pub replication: Option<ReplicationConfig>,
+    /// Scheduled snapshots of the state next to the state file, disabled if not set.
+    #[serde(default)]
+    pub snapshot: Option<SnapshotConfig>,
+    /// Number of keepalive intervals without a handshake before a peer is considered stale.
+    #[serde(default = "default_health_stale_factor")]
+    pub health_stale_factor: u64,
+    /// Key material of the Ed25519 key to sign the pull responses and the endpoints of the
+    /// nodes with, which nodes exchange with their peers while the lighthouse is unreachable.
+    /// Disabled if not set.
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    /// Time in seconds signed pull responses are valid for, nodes reject expired responses.
+    #[serde(default = "default_response_ttl_seconds")]
+    pub response_ttl_seconds: u64,
+    /// Rate limiting and bans of clients of the api.
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Access restrictions of the prometheus metrics.
+    #[serde(default)]
+    pub metrics: MetricsAccessConfig,
+}
+
+fn default_watch_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_health_stale_factor() -> u64 {
+    3
+}
+
+fn default_response_ttl_seconds() -> u64 {
+    86400
 }
 
 impl LighthouseConfig {
-    pub fn get_listen_addr(&self) -> String {
-        format!("{}:{}", self.bindhost, self.port)
+    pub fn get_listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bindhost, self.port)
+    }
+
+    /// Returns the key to sign documents for the nodes with, none if not configured.
+    pub fn get_signing_key(&self) -> Result<Option<SigningKey>, SigningError> {
+        self.signing_key
+            .as_deref()
+            .map(SigningKey::from_material)
+            .transpose()
     }
 }
 
@@ -33,3 +273,228 @@ pub struct LighthouseConfigFile {
     /// Lighthouse configuration.
     pub lighthouse: LighthouseConfig,
 }
+
+impl ConfigFile for LighthouseConfigFile {
+    const SECRET_KEYS: &'static [&'static str] = &[
+        "lighthouse.lighthouse_key",
+        "lighthouse.node_key",
+        "lighthouse.admin_key",
+        "lighthouse.replication.key",
+        "lighthouse.signing_key",
+        "lighthouse.metrics.password",
+    ];
+
+    fn check(&self, problems: &mut ConfigProblems) {
+        let lighthouse = &self.lighthouse;
+        if lighthouse.lighthouse_key.is_empty() {
+            problems.push("lighthouse.lighthouse_key", "Value is empty");
+        }
+        if lighthouse.node_key.is_empty() {
+            problems.push("lighthouse.node_key", "Value is empty");
+        }
+        for (index, keys) in lighthouse.additional_keys.iter().enumerate() {
+            let key = format!("lighthouse.additional_keys[{}]", index);
+            if keys.name.is_empty() || keys.name == PRIMARY_KEY_NAME {
+                problems.push(
+                    format!("{}.name", key),
+                    "Expected a name other than primary",
+                );
+            } else if lighthouse.additional_keys[..index]
+                .iter()
+                .any(|other| other.name == keys.name)
+            {
+                problems.push(format!("{}.name", key), "Name is not unique");
+            }
+            if keys.lighthouse_key.is_empty() {
+                problems.push(format!("{}.lighthouse_key", key), "Value is empty");
+            }
+            if keys.node_key.is_empty() {
+                problems.push(format!("{}.node_key", key), "Value is empty");
+            }
+        }
+        if lighthouse.admin_key.as_deref() == Some("") {
+            problems.push("lighthouse.admin_key", "Value is empty");
+        }
+        if let Err(err) = lighthouse.get_signing_key() {
+            problems.push("lighthouse.signing_key", err.to_string());
+        }
+        if lighthouse.response_ttl_seconds == 0 {
+            problems.push(
+                "lighthouse.response_ttl_seconds",
+                "Expected at least one second",
+            );
+        }
+        problems.check(
+            "lighthouse.key_rotation_tod",
+            lighthouse.key_rotation_tod.validate(),
+        );
+        if lighthouse.health_stale_factor == 0 {
+            problems.push("lighthouse.health_stale_factor", "Expected at least 1");
+        }
+        if lighthouse.watch_timeout_seconds == 0 {
+            problems.push(
+                "lighthouse.watch_timeout_seconds",
+                "Expected at least one second",
+            );
+        }
+        // without burst every request of non-exempt clients would be rate limited
+        if lighthouse.rate_limit.requests_per_minute > 0 && lighthouse.rate_limit.burst == 0 {
+            problems.push("lighthouse.rate_limit.burst", "Expected at least 1");
+        }
+
+        if let Some(replication) = &lighthouse.replication {
+            if replication.key.is_empty() {
+                problems.push("lighthouse.replication.key", "Value is empty");
+            }
+            match &replication.leader_url {
+                Some(url) if url.starts_with("http://") && !replication.allow_insecure => {
+                    problems.push(
+                        "lighthouse.replication.leader_url",
+                        "Expected an https:// URL, the state contains the pre-shared keys \
+                         (set allow_insecure to replicate over http://)",
+                    );
+                }
+                Some(url) if !url.starts_with("http://") && !url.starts_with("https://") => {
+                    problems.push(
+                        "lighthouse.replication.leader_url",
+                        "Expected an http:// or https:// URL",
+                    );
+                }
+                None if replication.role == ReplicationRole::Follower => {
+                    problems.push(
+                        "lighthouse.replication",
+                        "Replication followers require a leader_url",
+                    );
+                }
+                _ => {}
+            }
+            if replication.interval_seconds == 0 {
+                problems.push(
+                    "lighthouse.replication.interval_seconds",
+                    "Expected at least one second",
+                );
+            } else if replication.failover_seconds <= replication.interval_seconds {
+                problems.push(
+                    "lighthouse.replication.failover_seconds",
+                    "Expected more than interval_seconds",
+                );
+            }
+        }
+        if let Some(snapshot) = &lighthouse.snapshot {
+            if snapshot.interval_seconds == 0 {
+                problems.push(
+                    "lighthouse.snapshot.interval_seconds",
+                    "Expected at least one second",
+                );
+            }
+            if snapshot.keep == 0 {
+                problems.push("lighthouse.snapshot.keep", "Expected at least 1");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpull_shared::config::{parse_config, ConfigError};
+
+    use super::{LighthouseConfigFile, RotationWindow};
+
+    #[test]
+    fn test_rotation_window() {
+        let window = RotationWindow { start: 2, end: 4 };
+        assert!(!window.contains(1));
+        assert!(window.contains(2));
+        assert!(window.contains(3));
+        assert!(!window.contains(4));
+    }
+
+    #[test]
+    fn test_lighthouse_config_problems() {
+        let contents = include_str!("../../../lighthouse.toml");
+        let no_file = |_: &str| Err("no files".to_string());
+        let config =
+            parse_config::<LighthouseConfigFile>(contents, std::iter::empty(), no_file).unwrap();
+        assert_eq!(
+            config.lighthouse.get_listen_addr().to_string(),
+            "0.0.0.0:2001"
+        );
+
+        let contents = format!(
+            "{}\n[lighthouse.rate_limit]\nburst = 0\n\n[lighthouse.replication]\nrole = \"follower\"\nkey = \"replication\"\nfailover_seconds = 10\n",
+            contents
+                .replace("key_rotation_tod = [2, 3]", "key_rotation_tod = [3, 2]")
+                .replace("watch_timeout_seconds = 60", "watch_timeout_seconds = 0")
+        );
+        let Err(ConfigError::InvalidConfig(problems)) =
+            parse_config::<LighthouseConfigFile>(&contents, std::iter::empty(), no_file)
+        else {
+            panic!("expected invalid config");
+        };
+        let keys: Vec<&str> = problems
+            .iter()
+            .map(|problem| problem.key.as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "lighthouse.key_rotation_tod",
+                "lighthouse.watch_timeout_seconds",
+                "lighthouse.rate_limit.burst",
+                "lighthouse.replication",
+                "lighthouse.replication.failover_seconds"
+            ]
+        );
+        assert!(problems.iter().all(|problem| problem.line.is_some()));
+
+        let contents = contents.replace("bindhost = \"0.0.0.0\"", "bindhost = \"localhost\"");
+        let Err(ConfigError::InvalidConfig(problems)) =
+            parse_config::<LighthouseConfigFile>(&contents, std::iter::empty(), no_file)
+        else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(problems[0].key, "lighthouse.bindhost");
+
+        let contents = format!(
+            "{}\n[lighthouse.snapshot]\npassphrase_file = \"/passphrase\"\ninterval_seconds = 0\n\n[[lighthouse.additional_keys]]\nname = \"primary\"\nlighthouse_key = \"\"\nnode_key = \"node\"\n",
+            include_str!("../../../lighthouse.toml")
+        );
+        let Err(ConfigError::InvalidConfig(problems)) =
+            parse_config::<LighthouseConfigFile>(&contents, std::iter::empty(), no_file)
+        else {
+            panic!("expected invalid config");
+        };
+        let keys: Vec<(&str, Option<usize>)> = problems
+            .iter()
+            .map(|problem| (problem.key.as_str(), problem.line))
+            .collect();
+        let line = contents.lines().count();
+        assert_eq!(
+            keys,
+            vec![
+                ("lighthouse.additional_keys[0].name", Some(line - 2)),
+                (
+                    "lighthouse.additional_keys[0].lighthouse_key",
+                    Some(line - 1)
+                ),
+                ("lighthouse.snapshot.interval_seconds", Some(line - 5)),
+            ]
+        );
+
+        // the replicated state contains the pre-shared keys, http:// must be allowed explicitly
+        let contents = format!(
+            "{}\n[lighthouse.replication]\nrole = \"follower\"\nkey = \"replication\"\nleader_url = \"http://10.11.0.3:2001/\"\n",
+            include_str!("../../../lighthouse.toml")
+        );
+        let Err(ConfigError::InvalidConfig(problems)) =
+            parse_config::<LighthouseConfigFile>(&contents, std::iter::empty(), no_file)
+        else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(problems[0].key, "lighthouse.replication.leader_url");
+        let contents = format!("{}allow_insecure = true\n", contents);
+        assert!(
+            parse_config::<LighthouseConfigFile>(&contents, std::iter::empty(), no_file).is_ok()
+        );
+    }
+}
@@ -1,16 +1,39 @@
-use anyhow::Result;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{watch, Mutex};
 use wgpull_shared::{
     challenge::ChallengeResponse,
     command::CommandExecutor,
+    crypto::verify_key,
     file::FileAccessor,
     request::{NodeMetricsPushRequest, NodePullRequest},
-    response::NodePullResponse,
+    response::{NodePullNotModified, NodePullResponse, PeerEndpointRecord},
+    signing::SignedDocument,
+    state::load_state_key,
     time::CurrentTime,
 };
 
-use super::{config::LighthouseConfig, metrics::LighthouseMetrics, state::LighthouseState};
+use super::{
+    config::{LighthouseConfig, ReplicationRole, PRIMARY_KEY_NAME},
+    health::{evaluate_mesh_health, MeshHealth},
+    metrics::{AuthFailure, LighthouseMetrics, RejectReason},
+    ratelimit::{RateLimitDecision, RateLimiter},
+    state::{new_state_epoch, LighthouseState},
+    status::LighthouseNodeStatus,
+    store::{open_state_store, StateStore},
+    traffic::LinkTrafficStatus,
+};
+
+/// Interval in which the state is saved to disk even if no peer configuration has changed,
+/// this keeps the last seen timestamps of the nodes reasonably up to date.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 /// The global context of the lighthouse server.
 ///
@@ -26,6 +49,21 @@ pub struct LighthouseContext {
     pub time: Arc<dyn CurrentTime + Send + Sync>,
     pub file_accessor: Arc<dyn FileAccessor + Send + Sync>,
     pub executor: Arc<dyn CommandExecutor + Send + Sync>,
+    /// Persistent storage of the lighthouse state.
+    pub store: Box<dyn StateStore>,
+    /// Time the state was last saved to disk.
+    pub last_saved: Option<SystemTime>,
+    /// Notifies watching nodes about modifications of the state.
+    pub changes: watch::Sender<SystemTime>,
+    /// Time of the last successful state replication from the leader (followers only).
+    pub leader_seen: Option<SystemTime>,
+    /// Time the lighthouse was started.
+    pub started: SystemTime,
+    /// The follower modified the replicated state while serving nodes, the state is no longer
+    /// replaced by the state of the leader until the follower is restarted.
+    pub modified_locally: bool,
+    /// Request rate limits and bans of client addresses.
+    pub limiter: RateLimiter,
 }
 
 impl LighthouseContext {
@@ -36,46 +74,201 @@ impl LighthouseContext {
         file_accessor: Arc<dyn FileAccessor + Send + Sync>,
         executor: Arc<dyn CommandExecutor + Send + Sync>,
     ) -> Result<Self> {
-        match LighthouseState::from_file(&config.state_file, file_accessor.as_ref()).await? {
-            Some(state) => {
-                let context = LighthouseContext {
-                    config,
-                    state,
-                    metrics: LighthouseMetrics::default(),
-                    time,
-                    file_accessor,
-                    executor,
-                };
-                Ok(context)
-            }
-            None => {
-                let state = LighthouseState {
-                    nodes: HashMap::new(),
-                    preshared_keys: HashMap::new(),
-                    last_modified: time.now(),
-                };
-                let context = LighthouseContext {
-                    config,
-                    state,
-                    metrics: LighthouseMetrics::default(),
-                    time,
-                    file_accessor,
-                    executor,
-                };
-                Ok(context)
-            }
+        let key = load_state_key(config.state_key_file.as_deref(), file_accessor.as_ref()).await?;
+        let store = open_state_store(
+            &config.state_store,
+            &config.state_file,
+            file_accessor.clone(),
+            key,
+        )?;
+        let state = match store.load().await? {
+            Some(state) => state,
+            None => LighthouseState {
+                nodes: HashMap::new(),
+                preshared_keys: HashMap::new(),
+                preshared_keys_created: HashMap::new(),
+                last_modified: time.now(),
+                revision: 0,
+                epoch: new_state_epoch(),
+            },
+        };
+        let (changes, _) = watch::channel(state.last_modified);
+        let started = time.now();
+
+        Ok(LighthouseContext {
+            config,
+            state,
+            metrics: LighthouseMetrics::default(),
+            time,
+            file_accessor,
+            executor,
+            store,
+            last_saved: None,
+            changes,
+            leader_seen: None,
+            started,
+            modified_locally: false,
+            limiter: RateLimiter::default(),
+        })
+    }
+
+    /// Returns true if the lighthouse serves node requests.
+    ///
+    /// A follower only serves nodes once the leader is unreachable for longer than the
+    /// configured failover time, until then nodes are expected to use the leader. A follower
+    /// that never reached the leader waits for the failover time after its start.
+    pub fn is_serving(&self) -> bool {
+        let Some(replication) = &self.config.replication else {
+            return true;
+        };
+        if replication.role == ReplicationRole::Leader {
+            return true;
         }
+
+        self.time
+            .now()
+            .duration_since(self.leader_seen.unwrap_or(self.started))
+            .map(|duration| duration.as_secs() >= replication.failover_seconds)
+            .unwrap_or(false)
     }
 
-    /// Verify the shared lighthouse key against the configuration.
-    pub fn verify_lighthouse_key(&self, key: &str) -> bool {
-        key == self.config.lighthouse_key
+    fn is_follower(&self) -> bool {
+        self.config
+            .replication
+            .as_ref()
+            .is_some_and(|replication| replication.role == ReplicationRole::Follower)
     }
 
-    /// Creates a challenge response to send to the node, this is used for the
-    /// node to verify the authenticity of the lighthouse.
-    pub fn get_node_challenge_response(&self, challenge: &str) -> String {
-        ChallengeResponse::with_challenge(self.config.node_key.clone(), challenge).response()
+    /// Verify the replication key against the configuration, returns none if replication is disabled.
+    pub fn verify_replication_key(&self, key: &str) -> Option<bool> {
+        self.config
+            .replication
+            .as_ref()
+            .map(|replication| verify_key(key, &replication.key))
+    }
+
+    /// Replaces the state with the state replicated from the leader.
+    ///
+    /// A state the follower modified while serving nodes is not replaced, as this would
+    /// silently discard the changes made during the failover.
+    pub async fn replace_state(&mut self, state: LighthouseState) -> Result<()> {
+        self.leader_seen = Some(self.time.now());
+        if self.modified_locally {
+            return Err(anyhow!(
+                "Refusing to replace the state modified while the leader was unreachable, \
+                 restart the follower to replicate the state of the leader again."
+            ));
+        }
+
+        let modified = state.last_modified != self.state.last_modified;
+        self.state = state;
+
+        if modified {
+            self.store.save(&self.state).await?;
+            self.last_saved = Some(self.time.now());
+            self.changes.send_replace(self.state.last_modified);
+        }
+
+        Ok(())
+    }
+
+    /// Saves the state to disk, used to flush the state on shutdown.
+    pub async fn flush_state(&mut self) -> Result<()> {
+        self.store.save(&self.state).await?;
+        self.last_saved = Some(self.time.now());
+        Ok(())
+    }
+
+    /// Matches the lighthouse key against the primary and additional keys of the configuration,
+    /// additional keys are rejected after their not-after time.
+    pub fn match_lighthouse_key(&self, key: &str) -> Result<MatchedKeys, AuthFailure> {
+        if verify_key(key, &self.config.lighthouse_key) {
+            return Ok(MatchedKeys {
+                name: PRIMARY_KEY_NAME.to_string(),
+                node_key: self.config.node_key.clone(),
+            });
+        }
+
+        let keys = self
+            .config
+            .additional_keys
+            .iter()
+            .find(|keys| verify_key(key, &keys.lighthouse_key))
+            .ok_or(AuthFailure::InvalidLighthouseKey)?;
+        if keys
+            .not_after
+            .is_some_and(|not_after| SystemTime::from(not_after) <= self.time.now())
+        {
+            return Err(AuthFailure::ExpiredLighthouseKey);
+        }
+        Ok(MatchedKeys {
+            name: keys.name.clone(),
+            node_key: keys.node_key.clone(),
+        })
+    }
+
+    /// Verify the admin key against the configuration, returns none if the admin api is disabled.
+    pub fn verify_admin_key(&self, key: &str) -> Option<bool> {
+        self.config
+            .admin_key
+            .as_ref()
+            .map(|admin_key| verify_key(key, admin_key))
+    }
+
+    /// Checks the rate limit and ban of the client address, rejected requests are logged and
+    /// counted in the metrics.
+    pub fn check_rate_limit(&mut self, client: IpAddr, path: &str) -> RateLimitDecision {
+        let decision = self
+            .limiter
+            .check(client, &self.config.rate_limit, self.time.now());
+        let reason = match decision {
+            RateLimitDecision::Allowed => return decision,
+            RateLimitDecision::RateLimited(_) => RejectReason::RateLimited,
+            RateLimitDecision::Banned(_) => RejectReason::Banned,
+        };
+        self.record_rejected_request(reason, client, path);
+        decision
+    }
+
+    /// Logs and counts a request rejected before the authentication.
+    pub fn record_rejected_request(&mut self, reason: RejectReason, client: IpAddr, path: &str) {
+        warn!(
+            "Rejected request: reason={:?} client={} path={}",
+            reason, client, path
+        );
+        self.metrics.record_rejected_request(reason);
+    }
+
+    /// Logs and counts a request rejected by the authentication, the client is banned after
+    /// repeated failures if its address is known.
+    pub fn record_auth_failure(&mut self, reason: AuthFailure, client: Option<IpAddr>, path: &str) {
+        let client_label = client.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+        warn!(
+            "Authentication failed: reason={:?} client={} path={}",
+            reason, client_label, path
+        );
+        self.metrics.record_auth_failure(reason);
+
+        let Some(client) = client else {
+            return;
+        };
+        if self
+            .limiter
+            .record_auth_failure(client, &self.config.rate_limit, self.time.now())
+        {
+            warn!(
+                "Banned client: client={} ban_seconds={}",
+                client, self.config.rate_limit.ban_seconds
+            );
+            self.metrics.record_ban();
+        }
+    }
+
+    /// Forgets the authentication failures of the client after it authenticated successfully.
+    pub fn record_auth_success(&mut self, client: Option<IpAddr>) {
+        if let Some(client) = client {
+            self.limiter.record_auth_success(client);
+        }
     }
 
     /// Both pushes the node configuration to the lighthouse and poll the configuration of connected nodes.
@@ -88,8 +281,24 @@ impl LighthouseContext {
     /// It also keeps track of the last time a node has pulled, and remove timed out nodes.
     /// This will set a flag for the node to regenerate keys if the key rotation interval has passed.
     ///
-    /// Everytime the function is called the lighthouse state will be saved to disk.
+    /// The lighthouse state is saved to disk whenever it was modified, otherwise at most
+    /// every `STATE_SAVE_INTERVAL` to persist the last seen timestamps.
     pub async fn node_pull(&mut self, request: &NodePullRequest) -> Result<NodePullResponse> {
+        // remember the result of the last configuration apply reported by the node
+        if let Some(last_apply) = &request.last_apply {
+            if !last_apply.success {
+                warn!(
+                    "Node {} failed to apply its configuration: {}",
+                    request.hostname,
+                    last_apply.error.as_deref().unwrap_or("unknown error")
+                );
+            }
+            self.metrics
+                .upsert_apply_status(&request.hostname, last_apply, self.time.now());
+        }
+
+        let last_modified = self.state.last_modified;
+
         // insert or update the node in the lighthouse state, updating the last_seen time
         self.state
             .upsert_node_lease_from_pull_request(request, self.time.as_ref());
@@ -99,38 +308,210 @@ impl LighthouseContext {
         let regenerate_keys = self.state.should_regenerate_keys(
             &request.hostname,
             self.config.key_rotation_interval_seconds,
-            self.config.key_rotation_tod,
+            &self.config.key_rotation_tod,
             self.time.as_ref(),
         )?;
 
         self.state
             .remove_expired_nodes(self.config.node_timeout_seconds, self.time.as_ref());
+        self.metrics.retain_nodes(&self.state);
 
-        self.state
-            .save(&self.config.state_file, self.file_accessor.as_ref())
-            .await?;
+        let peers = self
+            .state
+            .get_peers_response_for_node(
+                &request.hostname,
+                self.executor.clone(),
+                self.time.as_ref(),
+            )
+            .await;
+
+        let revision =
+            self.state
+                .update_peers_revision(&request.hostname, &peers, self.time.as_ref())?;
+
+        let modified = self.state.last_modified != last_modified;
+        if modified && self.is_follower() && !self.modified_locally {
+            // the state diverges from the leader, a new epoch keeps the revisions of both
+            // lighthouses apart
+            warn!("Follower modified the replicated state while the leader is unreachable.");
+            self.modified_locally = true;
+            self.state.epoch = new_state_epoch();
+        }
+        if modified || self.is_save_due() {
+            self.store.save(&self.state).await?;
+            self.last_saved = Some(self.time.now());
+        }
+
+        // wake up nodes watching for changes of their peers
+        if modified {
+            self.changes.send_replace(self.state.last_modified);
+        }
 
-        Ok(NodePullResponse {
+        let mut response = NodePullResponse {
             regenerate_keys,
-            peers: self
-                .state
-                .get_peers_response_for_node(&request.hostname, self.executor.clone())
-                .await,
-        })
+            peers,
+            revision,
+            signed_endpoint: None,
+            hostname: None,
+            expires_at: None,
+            epoch: None,
+        };
+        self.add_signed_fields(request, &mut response)?;
+        Ok(response)
+    }
+
+    /// Adds the signed endpoints of the node and its peers, the hostname and the expiry to the
+    /// response if a signing key is configured. These are added after the revision is
+    /// calculated as they change with every pull.
+    fn add_signed_fields(
+        &self,
+        request: &NodePullRequest,
+        response: &mut NodePullResponse,
+    ) -> Result<()> {
+        let Some(key) = self.config.get_signing_key()? else {
+            return Ok(());
+        };
+        let issued_at = self
+            .time
+            .now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        response.hostname = Some(request.hostname.clone());
+        response.expires_at = Some(issued_at + self.config.response_ttl_seconds);
+        response.epoch = Some(self.state.epoch);
+
+        response.signed_endpoint = Some(key.sign(&PeerEndpointRecord {
+            hostname: request.hostname.clone(),
+            public_key: request.public_key.clone(),
+            endpoint_host: request.endpoint.clone(),
+            endpoint_port: request.listen_port,
+            issued_at,
+        })?);
+        for peer in &mut response.peers {
+            peer.signed_endpoint = Some(key.sign(&PeerEndpointRecord {
+                hostname: peer.hostname.clone(),
+                public_key: peer.public_key.clone(),
+                endpoint_host: peer.endpoint_host.clone(),
+                endpoint_port: peer.endpoint_port,
+                issued_at,
+            })?);
+        }
+        Ok(())
+    }
+
+    /// Signs the pull response if a signing key is configured, the signed payload is sent as
+    /// the response body with the signature in a header.
+    pub fn sign_pull_response(
+        &self,
+        response: &NodePullResponse,
+    ) -> Result<Option<SignedDocument>> {
+        match self.config.get_signing_key()? {
+            Some(key) => Ok(Some(key.sign(response)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Signs the confirmation that the configuration identified by the ETag is still current
+    /// for the node if a signing key is configured, sent with a not modified pull response.
+    pub fn sign_not_modified(&self, hostname: &str, etag: &str) -> Result<Option<SignedDocument>> {
+        let Some(key) = self.config.get_signing_key()? else {
+            return Ok(None);
+        };
+        let now = self
+            .time
+            .now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        Ok(Some(key.sign(&NodePullNotModified {
+            hostname: hostname.to_string(),
+            etag: etag.to_string(),
+            expires_at: now + self.config.response_ttl_seconds,
+        })?))
+    }
+
+    /// Returns true if the state was not saved within the `STATE_SAVE_INTERVAL`.
+    fn is_save_due(&self) -> bool {
+        match self.last_saved {
+            Some(last_saved) => self
+                .time
+                .now()
+                .duration_since(last_saved)
+                .map(|duration| duration >= STATE_SAVE_INTERVAL)
+                .unwrap_or(true),
+            None => true,
+        }
+    }
+
+    /// Subscribes to modifications of the lighthouse state.
+    pub fn subscribe_changes(&self) -> watch::Receiver<SystemTime> {
+        self.changes.subscribe()
+    }
+
+    /// Returns true if the node should pull because its peer configuration changed since
+    /// the given revision.
+    pub fn has_node_peers_changed(&self, hostname: &str, revision: Option<u64>) -> bool {
+        self.state.has_peers_changed(hostname, revision)
     }
 
     /// The node pushes the latest metrics to the lighthouse, the metrics will be aggregated
     /// in the lighthouse context.
     pub fn update_metrics(&mut self, request: &NodeMetricsPushRequest) -> Result<()> {
         // insert or update the node in the lighthouse state, updating the last_seen time
-        self.metrics.upsert_metrics(request);
+        self.metrics.upsert_metrics(request, self.time.now());
 
         Ok(())
     }
 
+    /// Returns the status of all known nodes sorted by hostname, used by the admin api.
+    pub fn get_node_statuses(&self) -> Vec<LighthouseNodeStatus> {
+        let mut statuses: Vec<LighthouseNodeStatus> = self
+            .state
+            .nodes
+            .values()
+            .map(|lease| {
+                LighthouseNodeStatus::new(lease, self.metrics.get_apply_status(&lease.hostname))
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        statuses
+    }
+
+    /// Returns the traffic history of all links, used by the admin api.
+    pub fn get_traffic_statuses(&self) -> Vec<LinkTrafficStatus> {
+        self.metrics.get_traffic_statuses()
+    }
+
+    /// Evaluates the health of all node pairs from the metrics pushed by the nodes.
+    pub fn get_mesh_health(&self) -> MeshHealth {
+        evaluate_mesh_health(
+            &self.state,
+            &self.metrics,
+            self.time.now(),
+            self.config.health_stale_factor,
+        )
+    }
+
     /// Returns aggregated metrics as a prometheus export string.
     pub fn get_metrics_prometheus_export(&self) -> String {
-        self.metrics.export_prometheus()
+        self.metrics
+            .export_prometheus(&self.state, &self.get_mesh_health(), self.time.now())
+    }
+}
+
+/// Keys a node authenticated with, added to the request extensions by the key middleware.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedKeys {
+    /// Name of the keys in logs and metrics.
+    pub name: String,
+    /// Node key to answer the challenge of the node with.
+    pub node_key: String,
+}
+
+impl MatchedKeys {
+    /// Creates a challenge response to send to the node, this is used for the
+    /// node to verify the authenticity of the lighthouse.
+    pub fn challenge_response(&self, challenge: &str) -> String {
+        ChallengeResponse::with_challenge(self.node_key.clone(), challenge).response()
     }
 }
 
@@ -139,13 +520,211 @@ impl LighthouseContext {
 #[derive(Clone)]
 pub struct LighthouseContextProvider {
     pub context: Arc<Mutex<LighthouseContext>>,
+    /// Path of the configuration file to reload, reloading is disabled if not set.
+    pub config_path: Option<Arc<PathBuf>>,
+    /// Set once the lighthouse shuts down, ends the long-polls of watching nodes.
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl LighthouseContextProvider {
     /// Wraps the context in an Arc<Mutex<>>.
     pub fn new(context: LighthouseContext) -> Self {
+        let (shutdown, _) = watch::channel(false);
         LighthouseContextProvider {
             context: Arc::new(Mutex::new(context)),
+            config_path: None,
+            shutdown: Arc::new(shutdown),
         }
     }
+
+    /// Signals the shutdown of the lighthouse to running requests.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Waits until the lighthouse shuts down.
+    pub async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        // the sender lives as long as the provider, an error can't happen
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use wgpull_shared::{
+        challenge::ChallengeResponse,
+        request::NodePullRequest,
+        response::{NodePullResponse, PeerEndpointRecord},
+        signing::SigningKey,
+    };
+
+    use super::LighthouseContext;
+    use crate::{
+        config::LighthouseConfigFile,
+        metrics::AuthFailure,
+        mock::{MockCommandExecutor, MockCurrentTime, MockFileAccessor},
+    };
+
+    const WG_PUBKEY_1: &str = "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=";
+    const WG_PUBKEY_2: &str = "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=";
+
+    fn pull_request(hostname: &str, public_key: &str) -> NodePullRequest {
+        NodePullRequest {
+            hostname: hostname.to_string(),
+            endpoint: format!("{}.example.com", hostname),
+            public_key: public_key.to_string(),
+            listen_port: 30000,
+            persistent_keepalive: 0,
+            allowed_ips: vec![],
+            route_allowed_ips: false,
+            last_apply: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_match_lighthouse_key() {
+        let config: LighthouseConfigFile = toml::from_str(
+            r#"
+            [lighthouse]
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/lighthouse.state"
+
+            [[lighthouse.additional_keys]]
+            name = "previous"
+            lighthouse_key = "lighthouse-previous"
+            node_key = "node-previous"
+            not_after = "2024-07-03T12:00:00Z"
+            "#,
+        )
+        .unwrap();
+        let time = Arc::new(MockCurrentTime::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+        ));
+        let context = LighthouseContext::init(
+            config.lighthouse,
+            time.clone(),
+            Arc::new(MockFileAccessor::default()),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+
+        let keys = context.match_lighthouse_key("lighthouse").unwrap();
+        assert_eq!(keys.name, "primary");
+        assert_eq!(keys.node_key, "node");
+        let keys = context.match_lighthouse_key("lighthouse-previous").unwrap();
+        assert_eq!(keys.name, "previous");
+        // the challenge is answered with the node key paired with the lighthouse key
+        assert_eq!(
+            keys.challenge_response("challenge"),
+            ChallengeResponse::with_challenge("node-previous".to_string(), "challenge").response()
+        );
+        assert_eq!(
+            context.match_lighthouse_key("other"),
+            Err(AuthFailure::InvalidLighthouseKey)
+        );
+
+        // the keys are rejected from their not-after time on
+        time.advance(Duration::from_secs(7_999));
+        assert!(context.match_lighthouse_key("lighthouse-previous").is_ok());
+        time.advance(Duration::from_secs(1));
+        assert_eq!(
+            context.match_lighthouse_key("lighthouse-previous"),
+            Err(AuthFailure::ExpiredLighthouseKey)
+        );
+        assert!(context.match_lighthouse_key("lighthouse").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_node_pull_signs_response() {
+        let config: LighthouseConfigFile = toml::from_str(
+            r#"
+            [lighthouse]
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/lighthouse.state"
+            signing_key = "signing"
+            "#,
+        )
+        .unwrap();
+        let time = Arc::new(MockCurrentTime::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+        ));
+        let mut context = LighthouseContext::init(
+            config.lighthouse,
+            time.clone(),
+            Arc::new(MockFileAccessor::default()),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+        let public_key = SigningKey::from_material("signing").unwrap().public_key();
+
+        context
+            .node_pull(&pull_request("node2", WG_PUBKEY_2))
+            .await
+            .unwrap();
+        let response = context
+            .node_pull(&pull_request("node1", WG_PUBKEY_1))
+            .await
+            .unwrap();
+        assert_eq!(response.hostname.as_deref(), Some("node1"));
+        assert_eq!(response.expires_at, Some(1_720_086_400));
+        let signed = context.sign_pull_response(&response).unwrap().unwrap();
+        let verified: NodePullResponse = signed.verify(&public_key).unwrap();
+        assert_eq!(verified.revision, response.revision);
+        assert_eq!(verified.peers, response.peers);
+
+        let record: PeerEndpointRecord = response
+            .signed_endpoint
+            .clone()
+            .unwrap()
+            .verify(&public_key)
+            .unwrap();
+        assert_eq!(record.hostname, "node1");
+        assert_eq!(record.endpoint_host, "node1.example.com");
+        assert_eq!(record.issued_at, 1_720_000_000);
+
+        let record: PeerEndpointRecord = response.peers[0]
+            .signed_endpoint
+            .as_ref()
+            .unwrap()
+            .verify(&public_key)
+            .unwrap();
+        assert_eq!(record.hostname, "node2");
+        assert_eq!(record.public_key, WG_PUBKEY_2);
+        assert_eq!(record.endpoint_port, 30000);
+
+        // the records are signed again with every pull, without changing the revision
+        time.advance(Duration::from_secs(60));
+        let next = context
+            .node_pull(&pull_request("node1", WG_PUBKEY_1))
+            .await
+            .unwrap();
+        assert_eq!(next.revision, response.revision);
+        let record: PeerEndpointRecord = next.peers[0]
+            .signed_endpoint
+            .as_ref()
+            .unwrap()
+            .verify(&public_key)
+            .unwrap();
+        assert_eq!(record.issued_at, 1_720_000_060);
+    }
 }
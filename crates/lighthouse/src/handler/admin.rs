@@ -0,0 +1,44 @@
+use super::LighthouseResponseError;
+use crate::context::LighthouseContextProvider;
+use crate::reload::reload_config;
+use crate::status::LighthouseNodeStatus;
+use crate::traffic::LinkTrafficStatus;
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+
+/// Result of reloading the configuration through the admin api.
+#[derive(Debug, Serialize)]
+pub struct ConfigReloadResponse {
+    /// Changed settings that only take effect after a restart.
+    pub restart_required: Vec<&'static str>,
+}
+
+pub async fn get_admin_nodes_handler(
+    State(context): State<LighthouseContextProvider>,
+) -> Result<Json<Vec<LighthouseNodeStatus>>, LighthouseResponseError> {
+    let context = context.context.lock().await;
+
+    Ok(Json(context.get_node_statuses()))
+}
+
+pub async fn get_admin_traffic_handler(
+    State(context): State<LighthouseContextProvider>,
+) -> Result<Json<Vec<LinkTrafficStatus>>, LighthouseResponseError> {
+    let context = context.context.lock().await;
+
+    Ok(Json(context.get_traffic_statuses()))
+}
+
+pub async fn post_admin_reload_handler(
+    State(provider): State<LighthouseContextProvider>,
+) -> Result<Json<ConfigReloadResponse>, LighthouseResponseError> {
+    let Some(config_path) = provider.config_path.clone() else {
+        return Err(LighthouseResponseError::InternalError);
+    };
+    let restart_required = reload_config(&provider, &config_path)
+        .await
+        .map_err(|err| LighthouseResponseError::InvalidConfig(err.to_string()))?;
+
+    Ok(Json(ConfigReloadResponse { restart_required }))
+}
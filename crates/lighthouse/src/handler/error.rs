@@ -10,12 +10,32 @@ pub enum LighthouseResponseError {
     InvalidLighthouseKey,
     #[error("Invalid node key in request!")]
     InvalidNodeKey,
+    #[error("Invalid admin key in request!")]
+    InvalidAdminKey,
+    #[error("Admin API is disabled!")]
+    AdminApiDisabled,
+    #[error("Invalid replication key in request!")]
+    InvalidReplicationKey,
+    #[error("Replication is disabled!")]
+    ReplicationDisabled,
+    #[error("Too many requests, try again later!")]
+    RateLimited,
+    #[error("Client is banned after repeated authentication failures!")]
+    Banned,
+    #[error("Client address is not allowed to access the metrics!")]
+    MetricsNotAllowed,
+    #[error("Invalid metrics credentials in request!")]
+    InvalidMetricsCredentials,
+    #[error("Lighthouse is a follower, use the leader!")]
+    NotServing,
     #[error("Request body is invalid!")]
     BadRequestBody,
     #[error("Response body is invalid!")]
     BadResponseBody,
     #[error("Internal error in lighthouse context!")]
     InternalError,
+    #[error("Failed to reload the configuration: {0}")]
+    InvalidConfig(String),
 }
 
 // Tell axum how to convert `LighthouseResponseError` into a response.
@@ -25,9 +45,19 @@ impl IntoResponse for LighthouseResponseError {
             match self {
                 LighthouseResponseError::InvalidLighthouseKey => StatusCode::UNAUTHORIZED,
                 LighthouseResponseError::InvalidNodeKey => StatusCode::UNAUTHORIZED,
+                LighthouseResponseError::InvalidAdminKey => StatusCode::UNAUTHORIZED,
+                LighthouseResponseError::AdminApiDisabled => StatusCode::NOT_FOUND,
+                LighthouseResponseError::InvalidReplicationKey => StatusCode::UNAUTHORIZED,
+                LighthouseResponseError::ReplicationDisabled => StatusCode::NOT_FOUND,
+                LighthouseResponseError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                LighthouseResponseError::Banned => StatusCode::FORBIDDEN,
+                LighthouseResponseError::MetricsNotAllowed => StatusCode::FORBIDDEN,
+                LighthouseResponseError::InvalidMetricsCredentials => StatusCode::UNAUTHORIZED,
+                LighthouseResponseError::NotServing => StatusCode::SERVICE_UNAVAILABLE,
                 LighthouseResponseError::BadRequestBody => StatusCode::BAD_REQUEST,
                 LighthouseResponseError::BadResponseBody => StatusCode::INTERNAL_SERVER_ERROR,
                 LighthouseResponseError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+                LighthouseResponseError::InvalidConfig(_) => StatusCode::UNPROCESSABLE_ENTITY,
             },
             format!("{}", self),
         )
@@ -0,0 +1,13 @@
+use super::LighthouseResponseError;
+use crate::context::LighthouseContextProvider;
+use crate::health::MeshHealth;
+use axum::extract::State;
+use axum::Json;
+
+pub async fn get_health_handler(
+    State(context): State<LighthouseContextProvider>,
+) -> Result<Json<MeshHealth>, LighthouseResponseError> {
+    let context = context.context.lock().await;
+
+    Ok(Json(context.get_mesh_health()))
+}
@@ -1,11 +1,22 @@
+use std::net::SocketAddr;
+
 use axum::{
-    extract::{Request, State},
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue},
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use wgpull_shared::headers::{HEADER_LIGHTHOUSE_KEY, HEADER_NODE_CHALLENGE, HEADER_NODE_RESPONSE};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use wgpull_shared::{
+    crypto::verify_key,
+    headers::{HEADER_LIGHTHOUSE_KEY, HEADER_NODE_CHALLENGE, HEADER_NODE_RESPONSE},
+};
 
-use crate::context::LighthouseContextProvider;
+use crate::{
+    context::LighthouseContextProvider,
+    metrics::{AuthFailure, RejectReason},
+    ratelimit::{client_address, ClientAddress, RateLimitDecision},
+};
 
 use super::LighthouseResponseError;
 
@@ -13,12 +24,13 @@ use super::LighthouseResponseError;
 ///
 /// The client sends <HEADER_LIGHTHOUSE_KEY> and <HEADER_NODE_CHALLENGE> in the request headers.
 /// The server verifies the lighthouse key, terminating the request early if it is invalid.
-/// Using the node challenge it generates a challenge response and injects it in the final response header.
+/// Using the node challenge and the node key paired with the lighthouse key it generates a
+/// challenge response and injects it in the final response header.
 ///
 /// This allows the lighthouse to authenticate the clients and vice-versa the nodes the lighthouse.
 pub async fn lighthouse_keys_layer(
     State(context): State<LighthouseContextProvider>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> Response {
     let received_lighthouse_key = request
@@ -26,25 +38,50 @@ pub async fn lighthouse_keys_layer(
         .get(HEADER_LIGHTHOUSE_KEY)
         .and_then(|value| value.to_str().ok())
         .unwrap_or("");
+    let client = request
+        .extensions()
+        .get::<ClientAddress>()
+        .map(|client| client.0);
 
+    let keys;
     let challenge_response;
     {
-        let context = context.context.lock().await;
+        let mut context = context.context.lock().await;
+
+        keys = match context.match_lighthouse_key(received_lighthouse_key) {
+            Ok(keys) => keys,
+            Err(reason) => {
+                context.record_auth_failure(reason, client, request.uri().path());
+                return LighthouseResponseError::InvalidLighthouseKey.into_response();
+            }
+        };
 
-        if !context.verify_lighthouse_key(received_lighthouse_key) {
-            return LighthouseResponseError::InvalidLighthouseKey.into_response();
+        // followers reject nodes while the leader is reachable, so nodes fail over to it
+        if !context.is_serving() {
+            return LighthouseResponseError::NotServing.into_response();
         }
 
         challenge_response = match request
             .headers()
             .get(HEADER_NODE_CHALLENGE)
             .and_then(|value| value.to_str().ok())
-            .map(|challenge| context.get_node_challenge_response(challenge))
+            .map(|challenge| keys.challenge_response(challenge))
         {
             Some(response) => response,
-            None => return LighthouseResponseError::InvalidNodeKey.into_response(),
+            None => {
+                context.record_auth_failure(
+                    AuthFailure::MissingNodeChallenge,
+                    client,
+                    request.uri().path(),
+                );
+                return LighthouseResponseError::InvalidNodeKey.into_response();
+            }
         };
+
+        // failures of a rejected primary key don't count once the secondary key is accepted
+        context.record_auth_success(client);
     }
+    request.extensions_mut().insert(keys);
 
     let mut response = next.run(request).await;
 
@@ -55,3 +92,298 @@ pub async fn lighthouse_keys_layer(
 
     response
 }
+
+/// Middleware to verify the admin key of requests to the admin api.
+///
+/// The client sends the configured admin key in <HEADER_LIGHTHOUSE_KEY>, the admin api
+/// responds with not found to all requests if no admin key is configured.
+pub async fn admin_key_layer(
+    State(context): State<LighthouseContextProvider>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let received_admin_key = request
+        .headers()
+        .get(HEADER_LIGHTHOUSE_KEY)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let client = request
+        .extensions()
+        .get::<ClientAddress>()
+        .map(|client| client.0);
+
+    {
+        let mut context = context.context.lock().await;
+
+        match context.verify_admin_key(received_admin_key) {
+            None => return LighthouseResponseError::AdminApiDisabled.into_response(),
+            Some(false) => {
+                context.record_auth_failure(
+                    AuthFailure::InvalidAdminKey,
+                    client,
+                    request.uri().path(),
+                );
+                return LighthouseResponseError::InvalidAdminKey.into_response();
+            }
+            Some(true) => {}
+        }
+    }
+
+    next.run(request).await
+}
+
+/// Middleware to verify the replication key of requests from other lighthouses.
+///
+/// The lighthouse sends the replication key in <HEADER_LIGHTHOUSE_KEY>, the replication
+/// endpoint responds with not found to all requests if replication is not configured.
+pub async fn replication_key_layer(
+    State(context): State<LighthouseContextProvider>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let received_replication_key = request
+        .headers()
+        .get(HEADER_LIGHTHOUSE_KEY)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let client = request
+        .extensions()
+        .get::<ClientAddress>()
+        .map(|client| client.0);
+
+    {
+        let mut context = context.context.lock().await;
+
+        match context.verify_replication_key(received_replication_key) {
+            None => return LighthouseResponseError::ReplicationDisabled.into_response(),
+            Some(false) => {
+                context.record_auth_failure(
+                    AuthFailure::InvalidReplicationKey,
+                    client,
+                    request.uri().path(),
+                );
+                return LighthouseResponseError::InvalidReplicationKey.into_response();
+            }
+            Some(true) => {}
+        }
+    }
+
+    next.run(request).await
+}
+
+/// Returns the address of the client from the connection, none if the server doesn't provide
+/// connection information (e.g. in tests).
+fn connection_client_address(
+    request: &Request,
+    context: &crate::context::LighthouseContext,
+) -> Option<std::net::IpAddr> {
+    let ConnectInfo(peer) = request.extensions().get::<ConnectInfo<SocketAddr>>()?;
+    Some(client_address(
+        peer.ip(),
+        request.headers(),
+        &context.config.rate_limit.trusted_proxies,
+    ))
+}
+
+/// Returns the response to requests rejected by the rate limiter, none if the request is allowed.
+fn rejected_response(decision: RateLimitDecision) -> Option<Response> {
+    match decision {
+        RateLimitDecision::Allowed => None,
+        RateLimitDecision::RateLimited(retry_after) => {
+            let mut response = LighthouseResponseError::RateLimited.into_response();
+            // round up, clients retrying early would be rejected again
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs() + 1),
+            );
+            Some(response)
+        }
+        RateLimitDecision::Banned(_) => Some(LighthouseResponseError::Banned.into_response()),
+    }
+}
+
+/// Middleware to rate limit requests by client address and to reject banned clients.
+///
+/// The client address is added to the request extensions, so the key middlewares can ban
+/// clients after repeated authentication failures.
+pub async fn rate_limit_layer(
+    State(context): State<LighthouseContextProvider>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    {
+        let mut context = context.context.lock().await;
+        let Some(client) = connection_client_address(&request, &context) else {
+            drop(context);
+            return next.run(request).await;
+        };
+
+        if let Some(response) =
+            rejected_response(context.check_rate_limit(client, request.uri().path()))
+        {
+            return response;
+        }
+        request.extensions_mut().insert(ClientAddress(client));
+    }
+
+    next.run(request).await
+}
+
+/// Middleware to restrict access to the prometheus metrics.
+///
+/// Clients must connect from an allowed address and send the basic authentication credentials,
+/// if configured. Failed authentications count towards the ban of the client.
+pub async fn metrics_access_layer(
+    State(context): State<LighthouseContextProvider>,
+    request: Request,
+    next: Next,
+) -> Response {
+    {
+        let mut context = context.context.lock().await;
+        let client = connection_client_address(&request, &context);
+        let path = request.uri().path();
+
+        if let Some(client) = client {
+            if let Some(response) = rejected_response(context.check_rate_limit(client, path)) {
+                return response;
+            }
+        }
+
+        let allow = &context.config.metrics.allow;
+        if !allow.is_empty()
+            && !client.is_some_and(|client| allow.iter().any(|net| net.contains(&client)))
+        {
+            let client = client.unwrap_or(std::net::Ipv4Addr::UNSPECIFIED.into());
+            context.record_rejected_request(RejectReason::MetricsNotAllowed, client, path);
+            return LighthouseResponseError::MetricsNotAllowed.into_response();
+        }
+
+        if let Some(password) = &context.config.metrics.password {
+            let expected = format!("{}:{}", context.config.metrics.username, password);
+            let received = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Basic "))
+                .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .unwrap_or_default();
+            if !verify_key(&received, &expected) {
+                context.record_auth_failure(AuthFailure::InvalidMetricsCredentials, client, path);
+                let mut response =
+                    LighthouseResponseError::InvalidMetricsCredentials.into_response();
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic realm=\"wgpull metrics\""),
+                );
+                return response;
+            }
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use axum::{
+        body::Body,
+        extract::{ConnectInfo, Request},
+        http::StatusCode,
+    };
+    use tower::ServiceExt;
+    use wgpull_shared::{
+        headers::{HEADER_LIGHTHOUSE_KEY, HEADER_NODE_CHALLENGE},
+        request::NodePullRequest,
+    };
+
+    use crate::{
+        config::LighthouseConfig,
+        context::{LighthouseContext, LighthouseContextProvider},
+        make_router,
+        mock::{MockCommandExecutor, MockCurrentTime, MockFileAccessor},
+    };
+
+    fn pull_request(lighthouse_key: &str) -> Request {
+        let body = serde_json::to_string(&NodePullRequest {
+            hostname: "node1".to_string(),
+            endpoint: "node1.example.com".to_string(),
+            public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+            listen_port: 30000,
+            persistent_keepalive: 0,
+            allowed_ips: vec![],
+            route_allowed_ips: false,
+            last_apply: None,
+        })
+        .unwrap();
+        let client: SocketAddr = "203.0.113.1:40000".parse().unwrap();
+        Request::post("/api/v1/pull")
+            .header("content-type", "application/json")
+            .header(HEADER_LIGHTHOUSE_KEY, lighthouse_key)
+            .header(HEADER_NODE_CHALLENGE, "challenge")
+            .extension(ConnectInfo(client))
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_key_fallback_not_banned() {
+        let config: LighthouseConfig = toml::from_str(
+            r#"
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/lighthouse.state"
+
+            [rate_limit]
+            requests_per_minute = 0
+            max_auth_failures = 3
+            "#,
+        )
+        .unwrap();
+        let context = LighthouseContext::init(
+            config,
+            Arc::new(MockCurrentTime::new(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+            )),
+            Arc::new(MockFileAccessor::default()),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+        let router = make_router(LighthouseContextProvider::new(context));
+
+        // the primary key of the node is rejected, its secondary key is accepted
+        for _ in 0..10 {
+            let response = router.clone().oneshot(pull_request("next")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let response = router
+                .clone()
+                .oneshot(pull_request("lighthouse"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // clients without valid key are still banned
+        for _ in 0..3 {
+            router.clone().oneshot(pull_request("next")).await.unwrap();
+        }
+        let response = router
+            .clone()
+            .oneshot(pull_request("lighthouse"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -1,9 +1,20 @@
+mod admin;
 mod error;
+mod health;
 mod metrics;
 mod middleware;
 mod pull;
+mod replication;
+mod watch;
 
+pub use admin::{get_admin_nodes_handler, get_admin_traffic_handler, post_admin_reload_handler};
 pub use error::LighthouseResponseError;
+pub use health::get_health_handler;
 pub use metrics::{get_metrics_handler, post_metrics_handler};
-pub use middleware::lighthouse_keys_layer;
+pub use middleware::{
+    admin_key_layer, lighthouse_keys_layer, metrics_access_layer, rate_limit_layer,
+    replication_key_layer,
+};
 pub use pull::post_pull_handler;
+pub use replication::get_replication_state_handler;
+pub use watch::post_watch_handler;
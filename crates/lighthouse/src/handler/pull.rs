@@ -1,25 +1,69 @@
 use super::LighthouseResponseError;
-use crate::context::LighthouseContextProvider;
+use crate::context::{LighthouseContext, LighthouseContextProvider, MatchedKeys};
+use crate::metrics::PullResult;
 use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
 use axum::Json;
 use axum_macros::debug_handler;
 use log::error;
+use std::time::Instant;
+use wgpull_shared::headers::{
+    format_revision_etag, parse_revision_etag, HEADER_SIGNATURE, HEADER_SIGNED_PAYLOAD,
+};
 use wgpull_shared::request::NodePullRequest;
-use wgpull_shared::response::NodePullResponse;
 use wgpull_shared::validation::Validated;
 
+/// Handles node pulls, the response carries the epoch of the state and the revision of the peer
+/// configuration as ETag.
+///
+/// If the node sends the ETag it already has in the If-None-Match header and the
+/// peer configuration did not change since, the lighthouse responds with not modified
+/// and without a body. If a signing key is configured, the not modified response carries a
+/// signed `NodePullNotModified` in headers instead, so the node can verify that its
+/// configuration is still current.
 #[debug_handler]
 pub async fn post_pull_handler(
     State(context): State<LighthouseContextProvider>,
+    keys: Option<Extension<MatchedKeys>>,
+    headers: HeaderMap,
     Json(request): Json<NodePullRequest>,
-) -> Result<Json<NodePullResponse>, LighthouseResponseError> {
+) -> Result<Response, LighthouseResponseError> {
+    let started = Instant::now();
     if request.validate().is_err() {
         return Err(LighthouseResponseError::BadRequestBody);
     }
 
+    let known_revision = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_revision_etag);
+
     let mut context = context.context.lock().await;
 
-    let response = context.node_pull(&request).await;
+    let result = pull(&mut context, &request, known_revision).await;
+    let pull_result = match &result {
+        Ok(response) if response.status() == StatusCode::NOT_MODIFIED => PullResult::NotModified,
+        Ok(_) => PullResult::Ok,
+        Err(_) => PullResult::Error,
+    };
+    context.metrics.record_pull(pull_result, started.elapsed());
+    if let (Some(Extension(keys)), Ok(_)) = (keys, &result) {
+        context
+            .metrics
+            .record_node_key(&request.hostname, &keys.name);
+    }
+
+    result
+}
+
+async fn pull(
+    context: &mut LighthouseContext,
+    request: &NodePullRequest,
+    known_revision: Option<(u64, u64)>,
+) -> Result<Response, LighthouseResponseError> {
+    let response = context.node_pull(request).await;
     if let Err(err) = response {
         error!("Error creating pull response: {}", err);
         return Err(LighthouseResponseError::InternalError);
@@ -30,5 +74,187 @@ pub async fn post_pull_handler(
         return Err(LighthouseResponseError::BadResponseBody);
     }
 
-    Ok(Json(response))
+    let revision = (context.state.epoch, response.revision);
+    let etag_value = format_revision_etag(revision.0, revision.1);
+    if !response.regenerate_keys && known_revision == Some(revision) {
+        return match context.sign_not_modified(&request.hostname, &etag_value) {
+            Ok(Some(signed)) => Ok((
+                StatusCode::NOT_MODIFIED,
+                [(header::ETAG, etag_value)],
+                [(HEADER_SIGNED_PAYLOAD, signed.payload)],
+                [(HEADER_SIGNATURE, signed.signature)],
+            )
+                .into_response()),
+            Ok(None) => {
+                Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag_value)]).into_response())
+            }
+            Err(err) => {
+                error!("Error signing not modified pull response: {}", err);
+                Err(LighthouseResponseError::InternalError)
+            }
+        };
+    }
+    let etag = [(header::ETAG, etag_value)];
+
+    // signed responses are sent as the exact signed payload
+    match context.sign_pull_response(&response) {
+        Ok(Some(signed)) => Ok((
+            etag,
+            [(header::CONTENT_TYPE, "application/json")],
+            [(HEADER_SIGNATURE, signed.signature)],
+            signed.payload,
+        )
+            .into_response()),
+        Ok(None) => Ok((etag, Json(response)).into_response()),
+        Err(err) => {
+            error!("Error signing node pull response: {}", err);
+            Err(LighthouseResponseError::InternalError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use axum::{
+        extract::State,
+        http::{header, HeaderMap, HeaderValue, StatusCode},
+        Json,
+    };
+    use wgpull_shared::{
+        headers::{format_revision_etag, HEADER_SIGNATURE, HEADER_SIGNED_PAYLOAD},
+        request::NodePullRequest,
+        response::NodePullNotModified,
+        signing::{SignedDocument, SigningKey},
+    };
+
+    use super::post_pull_handler;
+    use crate::{
+        config::LighthouseConfig,
+        context::{LighthouseContext, LighthouseContextProvider},
+        mock::{MockCommandExecutor, MockCurrentTime, MockFileAccessor},
+    };
+
+    fn pull_request() -> NodePullRequest {
+        NodePullRequest {
+            hostname: "node1".to_string(),
+            endpoint: "node1.example.com".to_string(),
+            public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+            listen_port: 30000,
+            persistent_keepalive: 0,
+            allowed_ips: vec![],
+            route_allowed_ips: false,
+            last_apply: None,
+        }
+    }
+
+    fn if_none_match(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    async fn provider(extra_config: &str) -> LighthouseContextProvider {
+        let config: LighthouseConfig = toml::from_str(&format!(
+            r#"
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/lighthouse.state"
+            {}
+            "#,
+            extra_config
+        ))
+        .unwrap();
+        let context = LighthouseContext::init(
+            config,
+            Arc::new(MockCurrentTime::new(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+            )),
+            Arc::new(MockFileAccessor::default()),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+        LighthouseContextProvider::new(context)
+    }
+
+    #[tokio::test]
+    async fn test_pull_if_none_match() {
+        let provider = provider("").await;
+        let pull = |headers: HeaderMap| {
+            post_pull_handler(State(provider.clone()), None, headers, Json(pull_request()))
+        };
+
+        let response = pull(HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // hit: the node already has the current revision
+        let response = pull(if_none_match(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+        // miss: same revision of another state epoch
+        let epoch = provider.context.lock().await.state.epoch;
+        let (_, revision) = etag.trim_matches('"').split_once('-').unwrap();
+        let other = format_revision_etag(epoch + 1, revision.parse().unwrap());
+        let response = pull(if_none_match(&other)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // malformed values are ignored
+        for value in ["\"42\"", "garbage", "*"] {
+            let response = pull(if_none_match(value)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_not_modified_signed() {
+        let provider = provider("signing_key = \"signing\"").await;
+        let pull = |headers: HeaderMap| {
+            post_pull_handler(State(provider.clone()), None, headers, Json(pull_request()))
+        };
+
+        let response = pull(HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let response = pull(if_none_match(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let signed = SignedDocument {
+            payload: response.headers()[HEADER_SIGNED_PAYLOAD]
+                .to_str()
+                .unwrap()
+                .to_string(),
+            signature: response.headers()[HEADER_SIGNATURE]
+                .to_str()
+                .unwrap()
+                .to_string(),
+        };
+        let public_key = SigningKey::from_material("signing").unwrap().public_key();
+        let not_modified: NodePullNotModified = signed.verify(&public_key).unwrap();
+        assert_eq!(
+            not_modified,
+            NodePullNotModified {
+                hostname: "node1".to_string(),
+                etag,
+                expires_at: 1_720_000_000 + 86400,
+            }
+        );
+    }
 }
@@ -0,0 +1,13 @@
+use super::LighthouseResponseError;
+use crate::context::LighthouseContextProvider;
+use crate::state::LighthouseState;
+use axum::extract::State;
+use axum::Json;
+
+pub async fn get_replication_state_handler(
+    State(context): State<LighthouseContextProvider>,
+) -> Result<Json<LighthouseState>, LighthouseResponseError> {
+    let context = context.context.lock().await;
+
+    Ok(Json(context.state.clone()))
+}
@@ -0,0 +1,134 @@
+use super::LighthouseResponseError;
+use crate::context::LighthouseContextProvider;
+use axum::extract::State;
+use axum::Json;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+use wgpull_shared::request::NodeWatchRequest;
+use wgpull_shared::response::NodeWatchResponse;
+use wgpull_shared::validation::Validated;
+
+/// Long-poll handler, responds as soon as the peer configuration of the node changes
+/// or after the timeout passed without changes.
+///
+/// The context is only locked to check for changes, not while waiting for them. Waiting
+/// nodes get an unchanged response once the lighthouse shuts down, so they don't delay it.
+pub async fn post_watch_handler(
+    State(provider): State<LighthouseContextProvider>,
+    Json(request): Json<NodeWatchRequest>,
+) -> Result<Json<NodeWatchResponse>, LighthouseResponseError> {
+    if request.validate().is_err() {
+        return Err(LighthouseResponseError::BadRequestBody);
+    }
+
+    let (mut changes, timeout) = {
+        let context = provider.context.lock().await;
+        (
+            context.subscribe_changes(),
+            request.timeout.min(context.config.watch_timeout_seconds),
+        )
+    };
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+
+    loop {
+        {
+            let context = provider.context.lock().await;
+            if context.has_node_peers_changed(&request.hostname, request.revision) {
+                return Ok(Json(NodeWatchResponse { changed: true }));
+            }
+        }
+
+        tokio::select! {
+            result = timeout_at(deadline, changes.changed()) => match result {
+                Ok(Ok(())) => continue,
+                _ => return Ok(Json(NodeWatchResponse { changed: false })),
+            },
+            _ = provider.shutting_down() => {
+                return Ok(Json(NodeWatchResponse { changed: false }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use axum::{extract::State, Json};
+    use wgpull_shared::request::{NodePullRequest, NodeWatchRequest};
+
+    use super::post_watch_handler;
+    use crate::{
+        config::LighthouseConfig,
+        context::{LighthouseContext, LighthouseContextProvider},
+        mock::{MockCommandExecutor, MockCurrentTime, MockFileAccessor},
+    };
+
+    #[tokio::test]
+    async fn test_watch_ends_on_shutdown() {
+        let config: LighthouseConfig = toml::from_str(
+            r#"
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/lighthouse.state"
+            watch_timeout_seconds = 3600
+            "#,
+        )
+        .unwrap();
+        let context = LighthouseContext::init(
+            config,
+            Arc::new(MockCurrentTime::new(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+            )),
+            Arc::new(MockFileAccessor::default()),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+        let provider = LighthouseContextProvider::new(context);
+        let revision = provider
+            .context
+            .lock()
+            .await
+            .node_pull(&NodePullRequest {
+                hostname: "node1".to_string(),
+                endpoint: "node1.example.com".to_string(),
+                public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+                listen_port: 30000,
+                persistent_keepalive: 0,
+                allowed_ips: vec![],
+                route_allowed_ips: false,
+                last_apply: None,
+            })
+            .await
+            .unwrap()
+            .revision;
+
+        let watch = tokio::spawn(post_watch_handler(
+            State(provider.clone()),
+            Json(NodeWatchRequest {
+                hostname: "node1".to_string(),
+                revision: Some(revision),
+                timeout: 3600,
+            }),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!watch.is_finished());
+
+        provider.shutdown();
+        let response = tokio::time::timeout(Duration::from_secs(5), watch)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(!response.changed);
+    }
+}
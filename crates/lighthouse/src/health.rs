@@ -0,0 +1,333 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use super::{
+    metrics::{LighthouseMetrics, LighthouseMetricsPeer},
+    state::LighthouseState,
+};
+
+/// Minimum time in seconds without a handshake before a peer is considered stale.
+///
+/// Wireguard only renews the handshake every 2 minutes while there is traffic, regardless
+/// of the persistent keepalive interval.
+const MIN_STALE_SECONDS: u64 = 180;
+
+/// Health of the wireguard connection between a pair of nodes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerPairHealth {
+    /// Both nodes had a recent handshake with each other.
+    Healthy,
+    /// Only one of the nodes had a recent handshake with the other, this usually indicates
+    /// a firewall or NAT problem.
+    OneSided,
+    /// Neither node had a recent handshake with the other.
+    Stale,
+    /// At least one of the nodes does not have the other configured as a peer.
+    Missing,
+}
+
+impl PeerPairHealth {
+    pub const ALL: [PeerPairHealth; 4] = [
+        PeerPairHealth::Healthy,
+        PeerPairHealth::OneSided,
+        PeerPairHealth::Stale,
+        PeerPairHealth::Missing,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PeerPairHealth::Healthy => "healthy",
+            PeerPairHealth::OneSided => "one_sided",
+            PeerPairHealth::Stale => "stale",
+            PeerPairHealth::Missing => "missing",
+        }
+    }
+}
+
+/// Health of a pair of nodes as exposed by the health api.
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerPairHealthStatus {
+    /// Hostname of the first node of the pair (sorted).
+    pub hostname: String,
+
+    /// Hostname of the second node of the pair (sorted).
+    pub peer_hostname: String,
+
+    /// The evaluated health of the pair.
+    pub health: PeerPairHealth,
+
+    /// Seconds since the first node's latest handshake with the second node, none if the
+    /// peer is missing or there was no handshake.
+    pub handshake_age_seconds: Option<u64>,
+
+    /// Seconds since the second node's latest handshake with the first node, none if the
+    /// peer is missing or there was no handshake.
+    pub peer_handshake_age_seconds: Option<u64>,
+}
+
+/// Health of all node pairs of the mesh.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MeshHealth {
+    pub healthy: usize,
+    pub one_sided: usize,
+    pub stale: usize,
+    pub missing: usize,
+
+    /// Health of every evaluated pair, sorted by hostnames.
+    pub pairs: Vec<PeerPairHealthStatus>,
+}
+
+/// Returns the seconds since the latest handshake with the peer, none if there was none.
+fn handshake_age(peer: &LighthouseMetricsPeer, now: SystemTime) -> Option<u64> {
+    if peer.latest_handshake == 0 {
+        return None;
+    }
+    let handshake = UNIX_EPOCH + Duration::from_secs(peer.latest_handshake);
+    Some(
+        now.duration_since(handshake)
+            .map(|age| age.as_secs())
+            .unwrap_or(0),
+    )
+}
+
+/// Returns true if the handshake with the peer is recent, the peer is stale after
+/// `stale_factor` keepalive intervals without a handshake.
+fn is_fresh(peer: &LighthouseMetricsPeer, age: Option<u64>, stale_factor: u64) -> bool {
+    let keepalive = u64::try_from(peer.persistent_keepalive).unwrap_or(0);
+    let stale_after = (keepalive * stale_factor).max(MIN_STALE_SECONDS);
+    age.is_some_and(|age| age <= stale_after)
+}
+
+/// Evaluates the health of every pair of nodes known to the lighthouse, based on the wireguard
+/// metrics pushed by both nodes.
+///
+/// Pairs are only evaluated once both nodes pushed metrics, a node that never pushed
+/// metrics can't tell which peers it has configured.
+pub fn evaluate_mesh_health(
+    state: &LighthouseState,
+    metrics: &LighthouseMetrics,
+    now: SystemTime,
+    stale_factor: u64,
+) -> MeshHealth {
+    let mut hostnames: Vec<&String> = state.nodes.keys().collect();
+    hostnames.sort();
+
+    let mut health = MeshHealth::default();
+    for (index, hostname) in hostnames.iter().enumerate() {
+        let Some(metric) = metrics.get_metrics(hostname) else {
+            continue;
+        };
+        for peer_hostname in &hostnames[index + 1..] {
+            let Some(peer_metric) = metrics.get_metrics(peer_hostname) else {
+                continue;
+            };
+
+            // how each node of the pair sees the other node
+            let peer = metric
+                .peers
+                .iter()
+                .find(|peer| &peer.hostname == *peer_hostname);
+            let reverse = peer_metric
+                .peers
+                .iter()
+                .find(|peer| &peer.hostname == *hostname);
+
+            let handshake_age_seconds = peer.and_then(|peer| handshake_age(peer, now));
+            let peer_handshake_age_seconds = reverse.and_then(|peer| handshake_age(peer, now));
+
+            let pair_health = match (peer, reverse) {
+                (Some(peer), Some(reverse)) => {
+                    match (
+                        is_fresh(peer, handshake_age_seconds, stale_factor),
+                        is_fresh(reverse, peer_handshake_age_seconds, stale_factor),
+                    ) {
+                        (true, true) => PeerPairHealth::Healthy,
+                        (false, false) => PeerPairHealth::Stale,
+                        _ => PeerPairHealth::OneSided,
+                    }
+                }
+                _ => PeerPairHealth::Missing,
+            };
+
+            match pair_health {
+                PeerPairHealth::Healthy => health.healthy += 1,
+                PeerPairHealth::OneSided => health.one_sided += 1,
+                PeerPairHealth::Stale => health.stale += 1,
+                PeerPairHealth::Missing => health.missing += 1,
+            }
+            health.pairs.push(PeerPairHealthStatus {
+                hostname: hostname.to_string(),
+                peer_hostname: peer_hostname.to_string(),
+                health: pair_health,
+                handshake_age_seconds,
+                peer_handshake_age_seconds,
+            });
+        }
+    }
+    health
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    use wgpull_shared::request::{NodeMetricsPushRequest, NodeMetricsPushRequestPeer};
+
+    use super::{evaluate_mesh_health, PeerPairHealth};
+    use crate::{
+        metrics::LighthouseMetrics,
+        state::{LighthouseNodeLease, LighthouseState},
+    };
+
+    const NOW: u64 = 1_720_000_000;
+
+    fn lease(hostname: &str) -> LighthouseNodeLease {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(NOW);
+        LighthouseNodeLease {
+            last_seen: time,
+            last_rotation: time,
+            public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+            hostname: hostname.to_string(),
+            endpoint_host: hostname.to_string(),
+            endpoint_port: 30000,
+            persistent_keepalive: 25,
+            allowed_ips: vec!["10.0.0.1/32".to_string()],
+            route_allowed_ips: true,
+            peers_revision: 1,
+            peers_digest: "digest".to_string(),
+        }
+    }
+
+    /// Pushes metrics of the node with the given peers and their handshake ages
+    /// (none for no handshake).
+    fn push(metrics: &mut LighthouseMetrics, hostname: &str, peers: &[(&str, Option<u64>)]) {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(NOW);
+        metrics.upsert_metrics(
+            &NodeMetricsPushRequest {
+                hostname: hostname.to_string(),
+                interface: "wg0".to_string(),
+                listening_port: 51820,
+                peers: peers
+                    .iter()
+                    .map(|(peer, age)| NodeMetricsPushRequestPeer {
+                        hostname: peer.to_string(),
+                        endpoint: format!("{}:51820", peer),
+                        latest_handshake: age.map(|age| NOW - age).unwrap_or(0),
+                        transfer_rx: 0,
+                        transfer_tx: 0,
+                        persistent_keepalive: 100,
+                        rtt_seconds: None,
+                        loss_ratio: None,
+                    })
+                    .collect(),
+            },
+            now,
+        );
+    }
+
+    #[test]
+    fn test_evaluate_mesh_health() {
+        let hostnames = ["a", "b", "c", "d", "e"];
+        let state = LighthouseState {
+            nodes: hostnames
+                .iter()
+                .map(|hostname| (hostname.to_string(), lease(hostname)))
+                .collect(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: SystemTime::now(),
+            revision: 1,
+            epoch: 1,
+        };
+
+        // a-b healthy, a-c one-sided, b-c stale (3 * 100s keepalive), c-d missing on d,
+        // e never pushed metrics
+        let mut metrics = LighthouseMetrics::default();
+        push(
+            &mut metrics,
+            "a",
+            &[("b", Some(10)), ("c", Some(290)), ("d", Some(5))],
+        );
+        push(
+            &mut metrics,
+            "b",
+            &[("a", Some(10)), ("c", Some(400)), ("d", Some(5))],
+        );
+        push(
+            &mut metrics,
+            "c",
+            &[("a", None), ("b", Some(301)), ("d", Some(5))],
+        );
+        push(&mut metrics, "d", &[("a", Some(5)), ("b", Some(5))]);
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(NOW);
+        let health = evaluate_mesh_health(&state, &metrics, now, 3);
+
+        let pairs: Vec<(&str, &str, PeerPairHealth)> = health
+            .pairs
+            .iter()
+            .map(|pair| {
+                (
+                    pair.hostname.as_str(),
+                    pair.peer_hostname.as_str(),
+                    pair.health,
+                )
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a", "b", PeerPairHealth::Healthy),
+                ("a", "c", PeerPairHealth::OneSided),
+                ("a", "d", PeerPairHealth::Healthy),
+                ("b", "c", PeerPairHealth::Stale),
+                ("b", "d", PeerPairHealth::Healthy),
+                ("c", "d", PeerPairHealth::Missing),
+            ]
+        );
+        assert_eq!(
+            (
+                health.healthy,
+                health.one_sided,
+                health.stale,
+                health.missing
+            ),
+            (3, 1, 1, 1)
+        );
+        assert_eq!(health.pairs[1].handshake_age_seconds, Some(290));
+        assert_eq!(health.pairs[1].peer_handshake_age_seconds, None);
+    }
+
+    #[test]
+    fn test_stale_after_minimum_handshake_interval() {
+        let state = LighthouseState {
+            nodes: ["a", "b"]
+                .iter()
+                .map(|hostname| (hostname.to_string(), lease(hostname)))
+                .collect(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: SystemTime::now(),
+            revision: 1,
+            epoch: 1,
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(NOW);
+
+        // handshakes are renewed every 2 minutes, a small keepalive doesn't make them stale
+        let mut metrics = LighthouseMetrics::default();
+        push(&mut metrics, "a", &[("b", Some(150))]);
+        push(&mut metrics, "b", &[("a", Some(150))]);
+        let health = evaluate_mesh_health(&state, &metrics, now, 1);
+        assert_eq!(health.pairs[0].health, PeerPairHealth::Healthy);
+
+        push(&mut metrics, "a", &[("b", Some(181))]);
+        push(&mut metrics, "b", &[("a", Some(181))]);
+        let health = evaluate_mesh_health(&state, &metrics, now, 1);
+        assert_eq!(health.pairs[0].health, PeerPairHealth::Stale);
+    }
+}
@@ -1,89 +1,298 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
 use crate::context::LighthouseContext;
 use crate::{
-    config::LighthouseConfig, context::LighthouseContextProvider, handler::lighthouse_keys_layer,
+    config::ReplicationRole,
+    context::LighthouseContextProvider,
+    handler::{
+        admin_key_layer, lighthouse_keys_layer, metrics_access_layer, rate_limit_layer,
+        replication_key_layer,
+    },
 };
 use axum::{
     middleware,
     routing::{get, post},
     Router,
 };
-use log::info;
+use clap::{Parser, Subcommand};
+use log::{error, info};
 use wgpull_shared::config::{discover_config_path, load_config};
+use wgpull_shared::file::check_private_file;
+use wgpull_shared::time::SystemCurrentTime;
 use wgpull_shared::{
-    command::CommandExecutor,
-    file::FileAccessor,
-    time::{CurrentTime, SystemCurrentTime},
+    client::SystemHttpClient, command::SystemCommandExecutor, file::SystemFileAccessor,
 };
-use wgpull_shared::{command::SystemCommandExecutor, file::SystemFileAccessor};
 
 use crate::config::LighthouseConfigFile;
-use wgpull_shared::logger;
+use wgpull_shared::{logger, service};
 
+pub mod archive;
+pub mod commands;
 pub mod config;
 pub mod context;
 pub mod handler;
+pub mod health;
 pub mod metrics;
+#[cfg(test)]
+mod mock;
 pub mod peer_pair;
+pub mod ratelimit;
+pub mod reload;
+pub mod replication;
+pub mod snapshot;
 pub mod state;
+pub mod status;
+pub mod store;
+pub mod traffic;
 
-async fn make_router(
-    config: LighthouseConfig,
-    time: Arc<dyn CurrentTime + Send + Sync>,
-    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
-    executor: Arc<dyn CommandExecutor + Send + Sync>,
-) -> anyhow::Result<Router> {
-    // create the lighthouse context to share across handlers
-    let lighthouse = LighthouseContext::init(config, time, file_accessor, executor).await?;
-
-    // let state = Arc::new(lighthouse);
-    let state = LighthouseContextProvider::new(lighthouse);
-
+fn make_router(state: LighthouseContextProvider) -> Router {
     let verify_keys_middleware =
         middleware::from_fn_with_state(state.clone(), lighthouse_keys_layer);
+    let verify_admin_key_middleware =
+        middleware::from_fn_with_state(state.clone(), admin_key_layer);
+    let verify_replication_key_middleware =
+        middleware::from_fn_with_state(state.clone(), replication_key_layer);
+    let rate_limit_middleware = middleware::from_fn_with_state(state.clone(), rate_limit_layer);
+    let metrics_access_middleware =
+        middleware::from_fn_with_state(state.clone(), metrics_access_layer);
 
-    let app = Router::new()
+    Router::new()
         .route(
             "/api/v1/pull",
             post(handler::post_pull_handler).layer(verify_keys_middleware.clone()),
         )
+        .route(
+            "/api/v1/watch",
+            post(handler::post_watch_handler).layer(verify_keys_middleware.clone()),
+        )
         .route(
             "/api/v1/metrics",
             post(handler::post_metrics_handler).layer(verify_keys_middleware),
         )
-        .route("/metrics", get(handler::get_metrics_handler))
-        .with_state(state);
+        .route(
+            "/api/v1/admin/nodes",
+            get(handler::get_admin_nodes_handler).layer(verify_admin_key_middleware.clone()),
+        )
+        .route(
+            "/api/v1/admin/traffic",
+            get(handler::get_admin_traffic_handler).layer(verify_admin_key_middleware.clone()),
+        )
+        .route(
+            "/api/v1/admin/reload",
+            post(handler::post_admin_reload_handler).layer(verify_admin_key_middleware),
+        )
+        .route(
+            "/api/v1/replication/state",
+            get(handler::get_replication_state_handler).layer(verify_replication_key_middleware),
+        )
+        // rate limits all api routes, the key middlewares run after it
+        .route_layer(rate_limit_middleware)
+        // the mesh health reveals the same details as the metrics
+        .route(
+            "/api/v1/health",
+            get(handler::get_health_handler).layer(metrics_access_middleware.clone()),
+        )
+        .route(
+            "/metrics",
+            get(handler::get_metrics_handler).layer(metrics_access_middleware),
+        )
+        .with_state(state)
+}
 
-    Ok(app)
+#[derive(Parser)]
+#[command(version, about = "Wireguard lighthouse server")]
+struct Cli {
+    /// Path of the configuration file, discovered in /etc/wgpull and the working directory by default.
+    #[arg(long, global = true, env = "WGPULL_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the lighthouse server (default).
+    Serve,
+    /// Check the configuration and the state it refers to without starting the server.
+    CheckConfig,
+    /// Print the public key of the signing key, to configure on the nodes.
+    PublicKey,
+    /// Print the stored lighthouse state.
+    DumpState {
+        /// Include the pre-shared keys.
+        #[arg(long)]
+        show_secrets: bool,
+    },
+    /// Export the lighthouse state to an encrypted archive.
+    Export {
+        /// Path of the archive to write.
+        archive: String,
+        /// File containing the passphrase to encrypt the archive with.
+        #[arg(long)]
+        passphrase_file: String,
+    },
+    /// Import the lighthouse state from an encrypted archive or snapshot.
+    Import {
+        /// Path of the archive to read.
+        archive: String,
+        /// File containing the passphrase to decrypt the archive with.
+        #[arg(long)]
+        passphrase_file: String,
+        /// Replace an existing lighthouse state.
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
     // setup logger (defaults the log level to info)
     logger::setup_logger();
 
-    let config_path =
-        discover_config_path("lighthouse.toml").expect("Failed to discover config path");
+    let config_path = discover_config_path("lighthouse.toml", cli.config.clone())
+        .expect("Failed to discover config path");
     info!("Using configuration from: {:?}", config_path);
 
-    let config = load_config::<LighthouseConfigFile>(&config_path).expect("Failed to load config");
-    let addr = config.lighthouse.get_listen_addr();
+    let config = match load_config::<LighthouseConfigFile>(&config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Failed to load config {:?}: {}", config_path, err);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            serve(config, config_path).await;
+            Ok(())
+        }
+        Command::CheckConfig => {
+            commands::check_config(&config.lighthouse, Arc::new(SystemFileAccessor))
+                .await
+                .map(|summary| print!("{}", summary))
+        }
+        Command::PublicKey => commands::public_key(&config.lighthouse).map(|key| print!("{}", key)),
+        Command::DumpState { show_secrets } => commands::dump_state(
+            &config.lighthouse,
+            Arc::new(SystemFileAccessor),
+            show_secrets,
+        )
+        .await
+        .map(|state| print!("{}", state)),
+        Command::Export {
+            archive,
+            passphrase_file,
+        } => {
+            archive::export_archive_file(
+                &config.lighthouse,
+                &archive,
+                &passphrase_file,
+                Arc::new(SystemFileAccessor),
+            )
+            .await
+        }
+        Command::Import {
+            archive,
+            passphrase_file,
+            force,
+        } => {
+            archive::import_archive_file(
+                &config.lighthouse,
+                &archive,
+                &passphrase_file,
+                force,
+                Arc::new(SystemFileAccessor),
+            )
+            .await
+        }
+    };
 
+    if let Err(err) = result {
+        error!("{:#}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(config: LighthouseConfigFile, config_path: PathBuf) {
+    // the state file contains the pre-shared keys of all nodes
+    check_private_file(&config.lighthouse.state_file).expect("Refusing to start");
+
+    let addr = config.lighthouse.get_listen_addr();
     info!("Lighthouse listening on: {}", addr);
-    let addr = addr
-        .parse::<SocketAddr>()
-        .expect("Invalid bindhost/port for lighthouse!");
 
-    let app = make_router(
+    let replication = config.lighthouse.replication.clone();
+    let snapshot = config.lighthouse.snapshot.clone();
+
+    // create the lighthouse context to share across handlers
+    let lighthouse = LighthouseContext::init(
         config.lighthouse,
         Arc::new(SystemCurrentTime),
         Arc::new(SystemFileAccessor),
         Arc::new(SystemCommandExecutor),
     )
     .await
-    .expect("Unable to create router!");
+    .expect("Unable to initialize lighthouse context!");
+    let mut state = LighthouseContextProvider::new(lighthouse);
+    state.config_path = Some(Arc::new(config_path));
+
+    // reload the configuration on SIGHUP
+    tokio::spawn(reload::reload_on_hangup(state.clone()));
+
+    // followers replicate the state of the leader in the background
+    if let Some(replication) = replication {
+        if replication.role == ReplicationRole::Follower {
+            let http_client = SystemHttpClient::new(10).expect("Failed to create http client");
+            tokio::spawn(replication::follow_leader(
+                state.clone(),
+                replication,
+                Box::new(http_client),
+            ));
+        }
+    }
+
+    // scheduled encrypted snapshots of the state
+    if let Some(snapshot) = snapshot {
+        tokio::spawn(snapshot::run_snapshots(state.clone(), snapshot));
+    }
+
+    let app = make_router(state.clone());
 
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    service::notify_ready();
+
+    // ping the watchdog as long as the context isn't stuck
+    if let Some(interval) = service::watchdog_interval() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let _context = state.context.lock().await;
+                service::notify_watchdog();
+            }
+        });
+    }
+
+    // drain in-flight requests on SIGTERM/SIGINT, then flush the state to disk
+    // the client address is required to rate limit requests
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown({
+        let state = state.clone();
+        async move {
+            service::shutdown_signal().await;
+            // end the long-polls of watching nodes, they would delay the shutdown
+            state.shutdown();
+        }
+    })
+    .await
+    .unwrap();
+    if let Err(err) = state.context.lock().await.flush_state().await {
+        error!("Failed to save state on shutdown: {}", err);
+    }
+    info!("Lighthouse stopped.");
 }
@@ -1,12 +1,158 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use wgpull_shared::request::NodeMetricsPushRequest;
+use wgpull_shared::{
+    prometheus::PrometheusExport,
+    request::{NodeApplyStatus, NodeMetricsPushRequest},
+};
+
+use super::{
+    health::{MeshHealth, PeerPairHealth},
+    peer_pair::PeerPair,
+    state::{LighthouseNodeLease, LighthouseState},
+    traffic::{LinkTraffic, LinkTrafficStatus},
+};
+
+/// Upper bounds of the pull request duration histogram buckets in seconds.
+const PULL_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Result of a pull request, exported as label of the pull request counter.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PullResult {
+    /// The peer configuration was returned to the node.
+    Ok,
+    /// The peer configuration did not change since the node's last pull.
+    NotModified,
+    /// The pull request failed.
+    Error,
+}
+
+impl PullResult {
+    const ALL: [PullResult; 3] = [PullResult::Ok, PullResult::NotModified, PullResult::Error];
+
+    fn label(&self) -> &'static str {
+        match self {
+            PullResult::Ok => "ok",
+            PullResult::NotModified => "not_modified",
+            PullResult::Error => "error",
+        }
+    }
+}
+
+/// Reason a request was rejected, exported as label of the authentication failure counter.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AuthFailure {
+    InvalidLighthouseKey,
+    ExpiredLighthouseKey,
+    MissingNodeChallenge,
+    InvalidAdminKey,
+    InvalidReplicationKey,
+    InvalidMetricsCredentials,
+}
+
+impl AuthFailure {
+    const ALL: [AuthFailure; 6] = [
+        AuthFailure::InvalidLighthouseKey,
+        AuthFailure::ExpiredLighthouseKey,
+        AuthFailure::MissingNodeChallenge,
+        AuthFailure::InvalidAdminKey,
+        AuthFailure::InvalidReplicationKey,
+        AuthFailure::InvalidMetricsCredentials,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            AuthFailure::InvalidLighthouseKey => "invalid_lighthouse_key",
+            AuthFailure::ExpiredLighthouseKey => "expired_lighthouse_key",
+            AuthFailure::MissingNodeChallenge => "missing_node_challenge",
+            AuthFailure::InvalidAdminKey => "invalid_admin_key",
+            AuthFailure::InvalidReplicationKey => "invalid_replication_key",
+            AuthFailure::InvalidMetricsCredentials => "invalid_metrics_credentials",
+        }
+    }
+}
+
+/// Reason a request was rejected before the authentication, exported as label of the
+/// rejected request counter.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RejectReason {
+    /// The client exceeded the request rate limit.
+    RateLimited,
+    /// The client is banned after repeated authentication failures.
+    Banned,
+    /// The client address is not allowed to scrape the metrics.
+    MetricsNotAllowed,
+}
+
+impl RejectReason {
+    const ALL: [RejectReason; 3] = [
+        RejectReason::RateLimited,
+        RejectReason::Banned,
+        RejectReason::MetricsNotAllowed,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            RejectReason::RateLimited => "rate_limited",
+            RejectReason::Banned => "banned",
+            RejectReason::MetricsNotAllowed => "metrics_not_allowed",
+        }
+    }
+}
+
+/// Histogram of observed values with fixed buckets.
+pub struct Histogram {
+    buckets: &'static [f64],
+    /// Number of observations per bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(index) = self.buckets.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Exports the cumulative buckets, sum and count of the histogram.
+    fn export(&self, export: &mut PrometheusExport, name: &str) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            export.sample(&bucket_name, &[("le", &bound.to_string())], cumulative);
+        }
+        export.sample(&bucket_name, &[("le", "+Inf")], self.count);
+        export.sample(&format!("{}_sum", name), &[], self.sum);
+        export.sample(&format!("{}_count", name), &[], self.count);
+    }
+}
 
 /// A collected peer metrics of a node.
 pub struct LighthouseMetricsPeer {
     /// Hostname of the peer.
     pub hostname: String,
 
+    /// Endpoint of the peer as seen by the node.
+    pub endpoint: String,
+
     /// Latest handshake of the peer.
     pub latest_handshake: u64,
 
@@ -15,6 +161,15 @@ pub struct LighthouseMetricsPeer {
 
     /// Sent bytes of the peer.
     pub transfer_tx: i64,
+
+    /// Persistent keepalive interval of the peer in seconds, 0 if disabled.
+    pub persistent_keepalive: i64,
+
+    /// Average round trip time through the tunnel in seconds, if probed by the node.
+    pub rtt_seconds: Option<f64>,
+
+    /// Ratio of lost probes through the tunnel, if probed by the node.
+    pub loss_ratio: Option<f64>,
 }
 
 /// The collected metrics of a node.
@@ -32,23 +187,77 @@ pub struct LighthouseCollectedMetric {
     pub peers: Vec<LighthouseMetricsPeer>,
 }
 
+/// The last apply result reported by a node with its pull request.
+#[derive(Clone, Debug)]
+pub struct LighthouseApplyStatus {
+    /// The apply result as reported by the node.
+    pub status: NodeApplyStatus,
+
+    /// Time the lighthouse received the apply result.
+    pub reported_at: SystemTime,
+}
+
 /// The collected metrics of all nodes.
-#[derive(Default)]
 pub struct LighthouseMetrics {
     metrics: HashMap<String, LighthouseCollectedMetric>,
+    apply_status: HashMap<String, LighthouseApplyStatus>,
+    pull_requests: HashMap<PullResult, u64>,
+    pull_duration: Histogram,
+    auth_failures: HashMap<AuthFailure, u64>,
+    rejected_requests: HashMap<RejectReason, u64>,
+    bans: u64,
+    /// Name of the keys each node last pulled with, by hostname.
+    node_keys: HashMap<String, String>,
+    /// Traffic of each node with its peers, by hostname and peer hostname.
+    traffic: HashMap<(String, String), LinkTraffic>,
+}
+
+impl Default for LighthouseMetrics {
+    fn default() -> Self {
+        Self {
+            metrics: HashMap::new(),
+            apply_status: HashMap::new(),
+            pull_requests: HashMap::new(),
+            pull_duration: Histogram::new(PULL_DURATION_BUCKETS),
+            auth_failures: HashMap::new(),
+            rejected_requests: HashMap::new(),
+            bans: 0,
+            node_keys: HashMap::new(),
+            traffic: HashMap::new(),
+        }
+    }
 }
 
 impl LighthouseMetrics {
     /// Upserts the metrics of a single node, aggregating the metrics of all nodes by hostname.
-    pub fn upsert_metrics(&mut self, request: &NodeMetricsPushRequest) {
+    ///
+    /// The transfer rates of the node's peers are computed from the previous push.
+    pub fn upsert_metrics(&mut self, request: &NodeMetricsPushRequest, now: SystemTime) {
+        for peer in &request.peers {
+            let link = (request.hostname.clone(), peer.hostname.clone());
+            match self.traffic.get_mut(&link) {
+                Some(traffic) => traffic.record(peer.transfer_rx, peer.transfer_tx, now),
+                None => {
+                    self.traffic.insert(
+                        link,
+                        LinkTraffic::new(peer.transfer_rx, peer.transfer_tx, now),
+                    );
+                }
+            }
+        }
+
         let peers = request
             .peers
             .iter()
             .map(|peer| LighthouseMetricsPeer {
                 hostname: peer.hostname.clone(),
+                endpoint: peer.endpoint.clone(),
                 latest_handshake: peer.latest_handshake,
                 transfer_rx: peer.transfer_rx,
                 transfer_tx: peer.transfer_tx,
+                persistent_keepalive: peer.persistent_keepalive,
+                rtt_seconds: peer.rtt_seconds,
+                loss_ratio: peer.loss_ratio,
             })
             .collect();
 
@@ -62,31 +271,866 @@ impl LighthouseMetrics {
         self.metrics.insert(request.hostname.clone(), metric);
     }
 
-    /// Export metrics for prometheus.
-    pub fn export_prometheus(&self) -> String {
-        let mut export = String::new();
+    /// Returns the last metrics pushed by the node, if any.
+    pub fn get_metrics(&self, hostname: &str) -> Option<&LighthouseCollectedMetric> {
+        self.metrics.get(hostname)
+    }
+
+    /// Upserts the last apply result reported by a node.
+    pub fn upsert_apply_status(
+        &mut self,
+        hostname: &str,
+        status: &NodeApplyStatus,
+        reported_at: SystemTime,
+    ) {
+        self.apply_status.insert(
+            hostname.to_string(),
+            LighthouseApplyStatus {
+                status: status.clone(),
+                reported_at,
+            },
+        );
+    }
+
+    /// Returns the last apply result reported by the node, if any.
+    pub fn get_apply_status(&self, hostname: &str) -> Option<&LighthouseApplyStatus> {
+        self.apply_status.get(hostname)
+    }
+
+    /// Returns the traffic history of all links sorted by hostnames, used by the admin api.
+    pub fn get_traffic_statuses(&self) -> Vec<LinkTrafficStatus> {
+        let mut statuses: Vec<LinkTrafficStatus> = self
+            .traffic
+            .iter()
+            .map(|((hostname, peer_hostname), traffic)| LinkTrafficStatus {
+                hostname: hostname.clone(),
+                peer_hostname: peer_hostname.clone(),
+                history: traffic.history().cloned().collect(),
+            })
+            .collect();
+        statuses
+            .sort_by(|a, b| (&a.hostname, &a.peer_hostname).cmp(&(&b.hostname, &b.peer_hostname)));
+        statuses
+    }
+
+    /// Counts a pull request by its result and records its duration.
+    pub fn record_pull(&mut self, result: PullResult, duration: Duration) {
+        *self.pull_requests.entry(result).or_default() += 1;
+        self.pull_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Counts a request rejected by the authentication.
+    pub fn record_auth_failure(&mut self, reason: AuthFailure) {
+        *self.auth_failures.entry(reason).or_default() += 1;
+    }
+
+    /// Counts a request rejected by the rate limiter or the metrics access control.
+    pub fn record_rejected_request(&mut self, reason: RejectReason) {
+        *self.rejected_requests.entry(reason).or_default() += 1;
+    }
+
+    /// Records the name of the keys the node pulled with.
+    pub fn record_node_key(&mut self, hostname: &str, key_name: &str) {
+        self.node_keys
+            .insert(hostname.to_string(), key_name.to_string());
+    }
+
+    /// Removes the metrics of nodes that are no longer in the state, e.g. after their lease
+    /// expired, including the traffic of their remaining peers with them.
+    pub fn retain_nodes(&mut self, state: &LighthouseState) {
+        let is_node = |hostname: &String| state.nodes.contains_key(hostname);
+        self.metrics.retain(|hostname, _| is_node(hostname));
+        for metric in self.metrics.values_mut() {
+            metric.peers.retain(|peer| is_node(&peer.hostname));
+        }
+        self.apply_status.retain(|hostname, _| is_node(hostname));
+        self.node_keys.retain(|hostname, _| is_node(hostname));
+        self.traffic
+            .retain(|(hostname, peer_hostname), _| is_node(hostname) && is_node(peer_hostname));
+    }
+
+    /// Counts a client banned after repeated authentication failures.
+    pub fn record_ban(&mut self) {
+        self.bans += 1;
+    }
+
+    /// Export metrics for prometheus, ages are relative to the given time.
+    pub fn export_prometheus(
+        &self,
+        state: &LighthouseState,
+        health: &MeshHealth,
+        now: SystemTime,
+    ) -> String {
+        let mut export = PrometheusExport::default();
+
+        let mut nodes: Vec<&LighthouseNodeLease> = state.nodes.values().collect();
+        nodes.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        let mut metrics: Vec<&LighthouseCollectedMetric> = self.metrics.values().collect();
+        metrics.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        let mut apply_status: Vec<(&String, &LighthouseApplyStatus)> =
+            self.apply_status.iter().collect();
+        apply_status.sort_by(|a, b| a.0.cmp(b.0));
+
+        export.family(
+            "lighthouse_nodes",
+            "gauge",
+            "Number of nodes known to the lighthouse.",
+        );
+        export.sample("lighthouse_nodes", &[], state.nodes.len());
+
+        export.family(
+            "lighthouse_node_up",
+            "gauge",
+            "Whether the node pushed metrics to the lighthouse.",
+        );
+        for metric in &metrics {
+            export.sample("lighthouse_node_up", &[("hostname", &metric.hostname)], 1);
+        }
+
+        export.family(
+            "lighthouse_node_last_seen_age_seconds",
+            "gauge",
+            "Seconds since the node last pulled from the lighthouse.",
+        );
+        for node in &nodes {
+            export.sample(
+                "lighthouse_node_last_seen_age_seconds",
+                &[("hostname", &node.hostname)],
+                age_seconds(now, node.last_seen),
+            );
+        }
+
+        export.family(
+            "lighthouse_node_key_age_seconds",
+            "gauge",
+            "Seconds since the keys of the node were last rotated.",
+        );
+        for node in &nodes {
+            export.sample(
+                "lighthouse_node_key_age_seconds",
+                &[("hostname", &node.hostname)],
+                age_seconds(now, node.last_rotation),
+            );
+        }
+
+        export.family(
+            "lighthouse_node_credential_info",
+            "gauge",
+            "Name of the keys the node last pulled with, the value is always 1.",
+        );
+        for node in &nodes {
+            if let Some(key_name) = self.node_keys.get(&node.hostname) {
+                export.sample(
+                    "lighthouse_node_credential_info",
+                    &[("hostname", &node.hostname), ("key", key_name)],
+                    1,
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_peer_psk_age_seconds",
+            "gauge",
+            "Seconds since the pre-shared key of the peer pair was generated.",
+        );
+        let mut preshared_keys_created: Vec<(&PeerPair, &SystemTime)> =
+            state.preshared_keys_created.iter().collect();
+        preshared_keys_created.sort_by(|a, b| a.0.peers().cmp(&b.0.peers()));
+        for (pair, created) in preshared_keys_created {
+            let (hostname, peer_hostname) = pair.peers();
+            export.sample(
+                "lighthouse_peer_psk_age_seconds",
+                &[("hostname", hostname), ("peer_hostname", peer_hostname)],
+                age_seconds(now, *created),
+            );
+        }
+
+        export.family(
+            "lighthouse_peer_latest_handshake",
+            "gauge",
+            "Unix timestamp of the latest handshake with the peer, 0 if there was none.",
+        );
+        for metric in &metrics {
+            for peer in &metric.peers {
+                export.sample(
+                    "lighthouse_peer_latest_handshake",
+                    &[
+                        ("hostname", &metric.hostname),
+                        ("peer_hostname", &peer.hostname),
+                    ],
+                    peer.latest_handshake,
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_peer_handshake_age_seconds",
+            "gauge",
+            "Seconds since the latest handshake with the peer, absent if there was none.",
+        );
+        for metric in &metrics {
+            for peer in metric.peers.iter().filter(|peer| peer.latest_handshake > 0) {
+                let handshake = UNIX_EPOCH + Duration::from_secs(peer.latest_handshake);
+                export.sample(
+                    "lighthouse_peer_handshake_age_seconds",
+                    &[
+                        ("hostname", &metric.hostname),
+                        ("peer_hostname", &peer.hostname),
+                    ],
+                    age_seconds(now, handshake),
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_peer_transfer_rx",
+            "counter",
+            "Bytes received from the peer.",
+        );
+        for metric in &metrics {
+            for peer in &metric.peers {
+                export.sample(
+                    "lighthouse_peer_transfer_rx",
+                    &[
+                        ("hostname", &metric.hostname),
+                        ("peer_hostname", &peer.hostname),
+                    ],
+                    peer.transfer_rx,
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_peer_transfer_tx",
+            "counter",
+            "Bytes sent to the peer.",
+        );
+        for metric in &metrics {
+            for peer in &metric.peers {
+                export.sample(
+                    "lighthouse_peer_transfer_tx",
+                    &[
+                        ("hostname", &metric.hostname),
+                        ("peer_hostname", &peer.hostname),
+                    ],
+                    peer.transfer_tx,
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_peer_rtt_seconds",
+            "gauge",
+            "Average round trip time through the tunnel to the peer, absent if not probed or lost.",
+        );
+        for metric in &metrics {
+            for peer in &metric.peers {
+                if let Some(rtt_seconds) = peer.rtt_seconds {
+                    export.sample(
+                        "lighthouse_peer_rtt_seconds",
+                        &[
+                            ("hostname", &metric.hostname),
+                            ("peer_hostname", &peer.hostname),
+                        ],
+                        rtt_seconds,
+                    );
+                }
+            }
+        }
+
+        export.family(
+            "lighthouse_peer_loss_ratio",
+            "gauge",
+            "Ratio of lost probes through the tunnel to the peer, absent if not probed.",
+        );
+        for metric in &metrics {
+            for peer in &metric.peers {
+                if let Some(loss_ratio) = peer.loss_ratio {
+                    export.sample(
+                        "lighthouse_peer_loss_ratio",
+                        &[
+                            ("hostname", &metric.hostname),
+                            ("peer_hostname", &peer.hostname),
+                        ],
+                        loss_ratio,
+                    );
+                }
+            }
+        }
+
+        let mut traffic: Vec<(&(String, String), &LinkTraffic)> = self.traffic.iter().collect();
+        traffic.sort_by(|a, b| a.0.cmp(b.0));
+
+        export.family(
+            "lighthouse_peer_rx_bytes_per_second",
+            "gauge",
+            "Bytes per second received from the peer between the last two metric pushes.",
+        );
+        for ((hostname, peer_hostname), link) in &traffic {
+            if let Some(sample) = link.latest() {
+                export.sample(
+                    "lighthouse_peer_rx_bytes_per_second",
+                    &[("hostname", hostname), ("peer_hostname", peer_hostname)],
+                    sample.rx_bytes_per_second,
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_peer_tx_bytes_per_second",
+            "gauge",
+            "Bytes per second sent to the peer between the last two metric pushes.",
+        );
+        for ((hostname, peer_hostname), link) in &traffic {
+            if let Some(sample) = link.latest() {
+                export.sample(
+                    "lighthouse_peer_tx_bytes_per_second",
+                    &[("hostname", hostname), ("peer_hostname", peer_hostname)],
+                    sample.tx_bytes_per_second,
+                );
+            }
+        }
 
-        for (hostname, metric) in &self.metrics {
-            export.push_str(&format!(
-                "lighthouse_node_up{{hostname=\"{}\"}} 1\n",
-                hostname
-            ));
+        export.family(
+            "lighthouse_peer_endpoint_info",
+            "gauge",
+            "Endpoint of the peer as seen by the node, the value is always 1.",
+        );
+        for metric in &metrics {
             for peer in &metric.peers {
-                export.push_str(&format!(
-                    "lighthouse_peer_latest_handshake{{hostname=\"{}\",peer_hostname=\"{}\"}} {}\n",
-                    hostname, peer.hostname, peer.latest_handshake
-                ));
-                export.push_str(&format!(
-                    "lighthouse_peer_transfer_rx{{hostname=\"{}\",peer_hostname=\"{}\"}} {}\n",
-                    hostname, peer.hostname, peer.transfer_rx
-                ));
-                export.push_str(&format!(
-                    "lighthouse_peer_transfer_tx{{hostname=\"{}\",peer_hostname=\"{}\"}} {}\n",
-                    hostname, peer.hostname, peer.transfer_tx
-                ));
+                export.sample(
+                    "lighthouse_peer_endpoint_info",
+                    &[
+                        ("hostname", &metric.hostname),
+                        ("peer_hostname", &peer.hostname),
+                        ("endpoint", &peer.endpoint),
+                    ],
+                    1,
+                );
             }
         }
 
-        export
+        export.family(
+            "lighthouse_peer_pair_health",
+            "gauge",
+            "Health of the connection between the pair of nodes, 1 for the current status.",
+        );
+        for pair in &health.pairs {
+            for status in PeerPairHealth::ALL {
+                export.sample(
+                    "lighthouse_peer_pair_health",
+                    &[
+                        ("hostname", &pair.hostname),
+                        ("peer_hostname", &pair.peer_hostname),
+                        ("status", status.label()),
+                    ],
+                    if pair.health == status { 1 } else { 0 },
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_node_apply_success",
+            "gauge",
+            "Whether the node applied its last configuration successfully.",
+        );
+        for (hostname, apply) in &apply_status {
+            export.sample(
+                "lighthouse_node_apply_success",
+                &[("hostname", hostname), ("backend", &apply.status.backend)],
+                if apply.status.success { 1 } else { 0 },
+            );
+        }
+
+        export.family(
+            "lighthouse_node_applied_revision",
+            "gauge",
+            "Revision of the peer configuration last applied by the node.",
+        );
+        for (hostname, apply) in &apply_status {
+            if let Some(revision) = apply.status.revision {
+                export.sample(
+                    "lighthouse_node_applied_revision",
+                    &[("hostname", hostname)],
+                    revision,
+                );
+            }
+        }
+
+        export.family(
+            "lighthouse_pull_requests_total",
+            "counter",
+            "Pull requests of nodes by result.",
+        );
+        for result in PullResult::ALL {
+            export.sample(
+                "lighthouse_pull_requests_total",
+                &[("result", result.label())],
+                self.pull_requests.get(&result).copied().unwrap_or(0),
+            );
+        }
+
+        export.family(
+            "lighthouse_pull_duration_seconds",
+            "histogram",
+            "Time to handle pull requests of nodes.",
+        );
+        self.pull_duration
+            .export(&mut export, "lighthouse_pull_duration_seconds");
+
+        export.family(
+            "lighthouse_auth_failures_total",
+            "counter",
+            "Requests rejected by the authentication by reason.",
+        );
+        for reason in AuthFailure::ALL {
+            export.sample(
+                "lighthouse_auth_failures_total",
+                &[("reason", reason.label())],
+                self.auth_failures.get(&reason).copied().unwrap_or(0),
+            );
+        }
+
+        export.family(
+            "lighthouse_rejected_requests_total",
+            "counter",
+            "Requests rejected by the rate limiter or metrics access control by reason.",
+        );
+        for reason in RejectReason::ALL {
+            export.sample(
+                "lighthouse_rejected_requests_total",
+                &[("reason", reason.label())],
+                self.rejected_requests.get(&reason).copied().unwrap_or(0),
+            );
+        }
+
+        export.family(
+            "lighthouse_bans_total",
+            "counter",
+            "Clients banned after repeated authentication failures.",
+        );
+        export.sample("lighthouse_bans_total", &[], self.bans);
+
+        export.finish()
+    }
+}
+
+/// Returns the seconds elapsed since the time, 0 if the time is in the future.
+fn age_seconds(now: SystemTime, time: SystemTime) -> f64 {
+    now.duration_since(time)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthFailure, LighthouseMetrics, PullResult, RejectReason};
+    use crate::{
+        health::{evaluate_mesh_health, MeshHealth},
+        peer_pair::PeerPair,
+        state::{LighthouseNodeLease, LighthouseState},
+    };
+    use prometheus_parse::{HistogramCount, Scrape, Value};
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+    use wgpull_shared::request::{
+        NodeApplyStatus, NodeMetricsPushRequest, NodeMetricsPushRequestPeer,
+    };
+
+    fn lease(
+        hostname: &str,
+        last_seen: SystemTime,
+        last_rotation: SystemTime,
+    ) -> LighthouseNodeLease {
+        LighthouseNodeLease {
+            last_seen,
+            last_rotation,
+            public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+            hostname: hostname.to_string(),
+            endpoint_host: hostname.to_string(),
+            endpoint_port: 30000,
+            persistent_keepalive: 25,
+            allowed_ips: vec!["10.0.0.1/32".to_string()],
+            route_allowed_ips: true,
+            peers_revision: 1,
+            peers_digest: "digest".to_string(),
+        }
+    }
+
+    fn scrape(export: &str) -> Scrape {
+        Scrape::parse(export.lines().map(|line| Ok(line.to_string()))).unwrap()
+    }
+
+    fn sample_value(scrape: &Scrape, metric: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        scrape
+            .samples
+            .iter()
+            .find(|sample| {
+                sample.metric == metric
+                    && labels
+                        .iter()
+                        .all(|(name, value)| sample.labels.get(name) == Some(*value))
+            })
+            .and_then(|sample| match sample.value {
+                Value::Gauge(value) | Value::Counter(value) | Value::Untyped(value) => Some(value),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn test_export_apply_status() {
+        let mut metrics = LighthouseMetrics::default();
+        metrics.upsert_apply_status(
+            "node1",
+            &NodeApplyStatus {
+                success: false,
+                error: Some("backend failed".to_string()),
+                revision: Some(7),
+                backend: "uci".to_string(),
+            },
+            SystemTime::now(),
+        );
+
+        let state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: SystemTime::now(),
+            revision: 0,
+            epoch: 1,
+        };
+        let export = metrics.export_prometheus(&state, &MeshHealth::default(), SystemTime::now());
+        assert!(export
+            .contains("lighthouse_node_apply_success{hostname=\"node1\",backend=\"uci\"} 0\n"));
+        assert!(export.contains("lighthouse_node_applied_revision{hostname=\"node1\"} 7\n"));
+    }
+
+    #[test]
+    fn test_retain_nodes() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        let mut state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: now,
+            revision: 1,
+            epoch: 1,
+        };
+        state
+            .nodes
+            .insert("node1".to_string(), lease("node1", now, now));
+
+        let mut metrics = LighthouseMetrics::default();
+        for (hostname, peer_hostname) in [("node1", "node2"), ("node2", "node1")] {
+            metrics.upsert_metrics(
+                &NodeMetricsPushRequest {
+                    hostname: hostname.to_string(),
+                    interface: "wg0".to_string(),
+                    listening_port: 51820,
+                    peers: vec![NodeMetricsPushRequestPeer {
+                        hostname: peer_hostname.to_string(),
+                        endpoint: format!("{}:51820", peer_hostname),
+                        latest_handshake: 0,
+                        transfer_rx: 0,
+                        transfer_tx: 0,
+                        persistent_keepalive: 25,
+                        rtt_seconds: None,
+                        loss_ratio: None,
+                    }],
+                },
+                now,
+            );
+            metrics.upsert_apply_status(
+                hostname,
+                &NodeApplyStatus {
+                    success: true,
+                    error: None,
+                    revision: Some(1),
+                    backend: "wg-quick".to_string(),
+                },
+                now,
+            );
+            metrics.record_node_key(hostname, "primary");
+        }
+
+        // the lease of node2 expired
+        metrics.retain_nodes(&state);
+        assert!(metrics.get_metrics("node2").is_none());
+        assert!(metrics.get_metrics("node1").unwrap().peers.is_empty());
+        assert!(metrics.get_apply_status("node2").is_none());
+        assert!(metrics.get_traffic_statuses().is_empty());
+
+        let export = metrics.export_prometheus(&state, &MeshHealth::default(), now);
+        assert!(export.contains("hostname=\"node1\""));
+        assert!(!export.contains("node2"));
+    }
+
+    #[test]
+    fn test_export_prometheus_text_format() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        let mut state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: now,
+            revision: 1,
+            epoch: 1,
+        };
+        state.nodes.insert(
+            "node1".to_string(),
+            lease(
+                "node1",
+                now - Duration::from_secs(5),
+                now - Duration::from_secs(3600),
+            ),
+        );
+        state
+            .nodes
+            .insert("node2".to_string(), lease("node2", now, now));
+        let pair = PeerPair::new("node1".to_string(), "node2".to_string());
+        state.preshared_keys.insert(pair.clone(), "psk".to_string());
+        state
+            .preshared_keys_created
+            .insert(pair, now - Duration::from_secs(60));
+
+        let mut metrics = LighthouseMetrics::default();
+        let mut request = NodeMetricsPushRequest {
+            hostname: "node1".to_string(),
+            interface: "wg0".to_string(),
+            listening_port: 51820,
+            peers: vec![NodeMetricsPushRequestPeer {
+                hostname: "node2".to_string(),
+                endpoint: "203.0.113.2:51820".to_string(),
+                latest_handshake: 1_720_000_000 - 42,
+                transfer_rx: 500,
+                transfer_tx: 1000,
+                persistent_keepalive: 25,
+                rtt_seconds: Some(0.0015),
+                loss_ratio: Some(0.25),
+            }],
+        };
+        metrics.upsert_metrics(&request, now - Duration::from_secs(10));
+        request.peers[0].transfer_rx = 1000;
+        request.peers[0].transfer_tx = 2000;
+        metrics.upsert_metrics(&request, now);
+        metrics.upsert_metrics(
+            &NodeMetricsPushRequest {
+                hostname: "node2".to_string(),
+                interface: "wg0".to_string(),
+                listening_port: 51820,
+                peers: vec![NodeMetricsPushRequestPeer {
+                    hostname: "node1".to_string(),
+                    endpoint: "203.0.113.1:51820".to_string(),
+                    latest_handshake: 0,
+                    transfer_rx: 0,
+                    transfer_tx: 0,
+                    persistent_keepalive: 25,
+                    rtt_seconds: None,
+                    loss_ratio: None,
+                }],
+            },
+            now,
+        );
+        metrics.record_pull(PullResult::Ok, Duration::from_millis(20));
+        metrics.record_pull(PullResult::NotModified, Duration::from_millis(2));
+        metrics.record_pull(PullResult::NotModified, Duration::from_secs(20));
+        metrics.record_auth_failure(AuthFailure::InvalidLighthouseKey);
+        metrics.record_rejected_request(RejectReason::RateLimited);
+        metrics.record_ban();
+        metrics.record_node_key("node1", "next");
+
+        let health = evaluate_mesh_health(&state, &metrics, now, 3);
+        let export = metrics.export_prometheus(&state, &health, now);
+        assert!(export.contains("# HELP lighthouse_nodes "));
+        assert!(export.contains("# TYPE lighthouse_pull_duration_seconds histogram\n"));
+        let scrape = scrape(&export);
+
+        assert_eq!(sample_value(&scrape, "lighthouse_nodes", &[]), Some(2.0));
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_node_last_seen_age_seconds",
+                &[("hostname", "node1")]
+            ),
+            Some(5.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_node_key_age_seconds",
+                &[("hostname", "node1")]
+            ),
+            Some(3600.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_psk_age_seconds",
+                &[("hostname", "node1"), ("peer_hostname", "node2")]
+            ),
+            Some(60.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_handshake_age_seconds",
+                &[("hostname", "node1"), ("peer_hostname", "node2")]
+            ),
+            Some(42.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_transfer_rx",
+                &[("hostname", "node1"), ("peer_hostname", "node2")]
+            ),
+            Some(1000.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_rx_bytes_per_second",
+                &[("hostname", "node1"), ("peer_hostname", "node2")]
+            ),
+            Some(50.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_tx_bytes_per_second",
+                &[("hostname", "node1"), ("peer_hostname", "node2")]
+            ),
+            Some(100.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_rtt_seconds",
+                &[("hostname", "node1"), ("peer_hostname", "node2")]
+            ),
+            Some(0.0015)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_loss_ratio",
+                &[("hostname", "node1"), ("peer_hostname", "node2")]
+            ),
+            Some(0.25)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_loss_ratio",
+                &[("hostname", "node2")]
+            ),
+            None
+        );
+        // node2 pushed metrics only once, no rate can be computed yet
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_rx_bytes_per_second",
+                &[("hostname", "node2")]
+            ),
+            None
+        );
+        assert_eq!(metrics.get_traffic_statuses()[0].history.len(), 1);
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_endpoint_info",
+                &[
+                    ("peer_hostname", "node2"),
+                    ("endpoint", "203.0.113.2:51820")
+                ]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_pair_health",
+                &[("hostname", "node1"), ("status", "one_sided")]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_peer_pair_health",
+                &[("hostname", "node1"), ("status", "healthy")]
+            ),
+            Some(0.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_pull_requests_total",
+                &[("result", "not_modified")]
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_pull_requests_total",
+                &[("result", "error")]
+            ),
+            Some(0.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_auth_failures_total",
+                &[("reason", "invalid_lighthouse_key")]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_rejected_requests_total",
+                &[("reason", "rate_limited")]
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample_value(&scrape, "lighthouse_bans_total", &[]),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample_value(
+                &scrape,
+                "lighthouse_node_credential_info",
+                &[("hostname", "node1"), ("key", "next")]
+            ),
+            Some(1.0)
+        );
+
+        let histogram = scrape
+            .samples
+            .iter()
+            .find_map(|sample| match &sample.value {
+                Value::Histogram(buckets)
+                    if sample.metric == "lighthouse_pull_duration_seconds" =>
+                {
+                    Some(buckets.clone())
+                }
+                _ => None,
+            })
+            .expect("pull duration histogram");
+        let count = |le: f64| {
+            histogram
+                .iter()
+                .find(|bucket: &&HistogramCount| bucket.less_than == le)
+                .map(|bucket| bucket.count)
+        };
+        assert_eq!(count(0.005), Some(1.0));
+        assert_eq!(count(0.025), Some(2.0));
+        assert_eq!(count(10.0), Some(2.0));
+        assert_eq!(count(f64::INFINITY), Some(3.0));
     }
 }
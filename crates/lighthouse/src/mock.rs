@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tokio::io;
+use wgpull_shared::{command::CommandExecutor, file::FileAccessor, time::CurrentTime};
+
+/// Current time that can be moved forward in tests.
+pub struct MockCurrentTime {
+    now: Mutex<SystemTime>,
+}
+
+impl MockCurrentTime {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += duration;
+    }
+}
+
+impl CurrentTime for MockCurrentTime {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// In-memory file accessor.
+#[derive(Default)]
+pub struct MockFileAccessor {
+    pub files: Mutex<HashMap<String, String>>,
+}
+
+#[async_trait]
+impl FileAccessor for MockFileAccessor {
+    async fn write(&self, path: &str, content: &str) -> Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), content.to_string());
+        Ok(())
+    }
+
+    async fn write_atomic(&self, path: &str, content: &str) -> Result<()> {
+        self.write(path, content).await
+    }
+
+    async fn read(&self, path: &str) -> Result<String> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| anyhow!("File not found: {}", path))
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("File not found: {}", path))
+    }
+
+    async fn list_dir(&self, path: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|file| {
+                file.strip_prefix(&prefix)
+                    .is_some_and(|name| !name.contains('/'))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn set_permissions(&self, _path: &str, _permissions: Permissions) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Command executor that generates distinct pre-shared keys for `wg genpsk`.
+#[derive(Default)]
+pub struct MockCommandExecutor {
+    counter: Mutex<u8>,
+}
+
+#[async_trait]
+impl CommandExecutor for MockCommandExecutor {
+    async fn execute(&self, command: &str) -> io::Result<(String, String)> {
+        self.execute_with_args(command, &[]).await
+    }
+
+    async fn execute_with_args(
+        &self,
+        command: &str,
+        args: &[&str],
+    ) -> io::Result<(String, String)> {
+        match (command, args) {
+            ("wg", ["genpsk"]) => {
+                let mut counter = self.counter.lock().unwrap();
+                *counter += 1;
+                let key = base64::Engine::encode(
+                    &base64::engine::general_purpose::STANDARD,
+                    [*counter; 32],
+                );
+                Ok((format!("{}\n", key), String::new()))
+            }
+            _ => Err(io::Error::other("Unexpected command in test.")),
+        }
+    }
+
+    async fn execute_with_args_and_io(
+        &self,
+        command: &str,
+        args: &[&str],
+        _stdin: &str,
+    ) -> io::Result<(String, String)> {
+        self.execute_with_args(command, args).await
+    }
+}
@@ -13,6 +13,11 @@ impl PeerPair {
         let peers = if a < b { (a, b) } else { (b, a) };
         Self { peers }
     }
+
+    /// Returns the hostnames of the pair, in sorted order.
+    pub fn peers(&self) -> (&str, &str) {
+        (&self.peers.0, &self.peers.1)
+    }
 }
 
 impl Hash for PeerPair {
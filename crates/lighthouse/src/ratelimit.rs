@@ -0,0 +1,337 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+
+use super::config::RateLimitConfig;
+
+/// Interval in which idle clients are removed from the rate limiter.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Address of the client that sent a request, added to the request extensions by the rate
+/// limiting middleware.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientAddress(pub IpAddr);
+
+/// Decision of the rate limiter about a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed,
+    /// The client sent more requests than allowed, it may retry after the duration.
+    RateLimited(Duration),
+    /// The client is banned after repeated authentication failures for the duration.
+    Banned(Duration),
+}
+
+/// Rate limit and authentication failures of a single client address.
+#[derive(Debug, Clone)]
+struct ClientState {
+    /// Number of requests the client can send right now (token bucket).
+    tokens: f64,
+    /// Time the tokens were last refilled.
+    refilled: SystemTime,
+    /// Number of authentication failures since the first failure.
+    auth_failures: u32,
+    /// Time of the last authentication failure.
+    last_auth_failure: Option<SystemTime>,
+    /// Time the ban of the client ends.
+    banned_until: Option<SystemTime>,
+}
+
+/// Limits the request rate of clients by address and bans clients after repeated
+/// authentication failures.
+///
+/// The state is only kept in memory, limits and bans are reset by a restart.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    clients: HashMap<IpAddr, ClientState>,
+    last_pruned: Option<SystemTime>,
+}
+
+/// Returns the time elapsed since the earlier time, 0 if it is in the future.
+fn elapsed(now: SystemTime, earlier: SystemTime) -> Duration {
+    now.duration_since(earlier).unwrap_or_default()
+}
+
+fn is_exempt(config: &RateLimitConfig, ip: IpAddr) -> bool {
+    config.exempt.iter().any(|net| net.contains(&ip))
+}
+
+impl RateLimiter {
+    /// Checks whether a request of the client is allowed, consuming one request of its limit.
+    pub fn check(
+        &mut self,
+        ip: IpAddr,
+        config: &RateLimitConfig,
+        now: SystemTime,
+    ) -> RateLimitDecision {
+        self.prune(config, now);
+        if is_exempt(config, ip) {
+            return RateLimitDecision::Allowed;
+        }
+
+        let client = self.client(ip, config, now);
+        if let Some(banned_until) = client.banned_until {
+            if banned_until > now {
+                return RateLimitDecision::Banned(elapsed(banned_until, now));
+            }
+            client.banned_until = None;
+            client.auth_failures = 0;
+        }
+        if config.requests_per_minute == 0 {
+            return RateLimitDecision::Allowed;
+        }
+
+        let per_second = config.requests_per_minute as f64 / 60.0;
+        client.tokens = (client.tokens + elapsed(now, client.refilled).as_secs_f64() * per_second)
+            .min(config.burst as f64);
+        client.refilled = now;
+        if client.tokens < 1.0 {
+            let retry_after = Duration::from_secs_f64((1.0 - client.tokens) / per_second);
+            return RateLimitDecision::RateLimited(retry_after);
+        }
+        client.tokens -= 1.0;
+        RateLimitDecision::Allowed
+    }
+
+    /// Records an authentication failure of the client, returns true if the client is banned
+    /// because of it.
+    pub fn record_auth_failure(
+        &mut self,
+        ip: IpAddr,
+        config: &RateLimitConfig,
+        now: SystemTime,
+    ) -> bool {
+        if config.max_auth_failures == 0 || is_exempt(config, ip) {
+            return false;
+        }
+
+        let client = self.client(ip, config, now);
+        let window = Duration::from_secs(config.ban_seconds);
+        if client
+            .last_auth_failure
+            .is_some_and(|last| elapsed(now, last) >= window)
+        {
+            client.auth_failures = 0;
+        }
+        client.auth_failures += 1;
+        client.last_auth_failure = Some(now);
+
+        if client.auth_failures >= config.max_auth_failures && client.banned_until.is_none() {
+            client.banned_until = Some(now + window);
+            return true;
+        }
+        false
+    }
+
+    /// Records a successful authentication of the client, forgetting its previous failures.
+    ///
+    /// Nodes falling back to their secondary keys are rejected with their primary keys on
+    /// every request, they are not banned as long as the fallback authenticates.
+    pub fn record_auth_success(&mut self, ip: IpAddr) {
+        if let Some(client) = self.clients.get_mut(&ip) {
+            client.auth_failures = 0;
+            client.last_auth_failure = None;
+        }
+    }
+
+    fn client(
+        &mut self,
+        ip: IpAddr,
+        config: &RateLimitConfig,
+        now: SystemTime,
+    ) -> &mut ClientState {
+        self.clients.entry(ip).or_insert_with(|| ClientState {
+            tokens: config.burst as f64,
+            refilled: now,
+            auth_failures: 0,
+            last_auth_failure: None,
+            banned_until: None,
+        })
+    }
+
+    /// Removes clients without ban or recent authentication failures whose rate limit has
+    /// been fully refilled, at most once every `PRUNE_INTERVAL`.
+    fn prune(&mut self, config: &RateLimitConfig, now: SystemTime) {
+        if self
+            .last_pruned
+            .is_some_and(|last_pruned| elapsed(now, last_pruned) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+        self.last_pruned = Some(now);
+
+        let refill = if config.requests_per_minute == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(config.burst as f64 * 60.0 / config.requests_per_minute as f64)
+        };
+        let window = Duration::from_secs(config.ban_seconds);
+        self.clients.retain(|_, client| {
+            client.banned_until.is_some_and(|until| until > now)
+                || client
+                    .last_auth_failure
+                    .is_some_and(|last| elapsed(now, last) < window)
+                || elapsed(now, client.refilled) < refill
+        });
+    }
+}
+
+/// Returns the address of the client, taken from the `X-Forwarded-For` header if the request
+/// was sent by a trusted reverse proxy.
+///
+/// The header is read from right to left, skipping trusted proxies, so clients can't spoof
+/// their address by sending the header themselves.
+pub fn client_address(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        time::{Duration, SystemTime},
+    };
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{client_address, RateLimitDecision, RateLimiter};
+    use crate::config::RateLimitConfig;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_minute: 60,
+            burst: 3,
+            max_auth_failures: 2,
+            ban_seconds: 600,
+            exempt: vec!["10.0.0.0/8".parse().unwrap()],
+            trusted_proxies: vec!["127.0.0.1/32".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config = config();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        let client: IpAddr = "203.0.113.1".parse().unwrap();
+        let mut limiter = RateLimiter::default();
+
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check(client, &config, now),
+                RateLimitDecision::Allowed
+            );
+        }
+        assert_eq!(
+            limiter.check(client, &config, now),
+            RateLimitDecision::RateLimited(Duration::from_secs(1))
+        );
+        // other and exempt clients are not affected
+        assert_eq!(
+            limiter.check("203.0.113.2".parse().unwrap(), &config, now),
+            RateLimitDecision::Allowed
+        );
+        for _ in 0..10 {
+            assert_eq!(
+                limiter.check("10.1.2.3".parse().unwrap(), &config, now),
+                RateLimitDecision::Allowed
+            );
+        }
+
+        // one request per second is refilled
+        let now = now + Duration::from_secs(1);
+        assert_eq!(
+            limiter.check(client, &config, now),
+            RateLimitDecision::Allowed
+        );
+        assert!(matches!(
+            limiter.check(client, &config, now),
+            RateLimitDecision::RateLimited(_)
+        ));
+    }
+
+    #[test]
+    fn test_ban_after_auth_failures() {
+        let config = config();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        let client: IpAddr = "203.0.113.1".parse().unwrap();
+        let mut limiter = RateLimiter::default();
+
+        assert!(!limiter.record_auth_failure(client, &config, now));
+        // failures older than the ban time are forgotten
+        let now = now + Duration::from_secs(600);
+        assert!(!limiter.record_auth_failure(client, &config, now));
+        assert!(limiter.record_auth_failure(client, &config, now));
+        assert_eq!(
+            limiter.check(client, &config, now + Duration::from_secs(100)),
+            RateLimitDecision::Banned(Duration::from_secs(500))
+        );
+
+        // the ban ends after the ban time
+        let now = now + Duration::from_secs(600);
+        assert_eq!(
+            limiter.check(client, &config, now),
+            RateLimitDecision::Allowed
+        );
+        assert!(!limiter.record_auth_failure(client, &config, now));
+    }
+
+    #[test]
+    fn test_no_ban_after_key_fallback() {
+        let config = config();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        let client: IpAddr = "203.0.113.1".parse().unwrap();
+        let mut limiter = RateLimiter::default();
+
+        // the primary key is rejected, the secondary key is accepted with every pull
+        for _ in 0..10 {
+            assert!(!limiter.record_auth_failure(client, &config, now));
+            limiter.record_auth_success(client);
+        }
+        assert_eq!(
+            limiter.check(client, &config, now),
+            RateLimitDecision::Allowed
+        );
+    }
+
+    #[test]
+    fn test_client_address() {
+        let trusted = config().trusted_proxies;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("198.51.100.7, 203.0.113.1, 127.0.0.1"),
+        );
+
+        // the header of untrusted clients is ignored
+        let client: IpAddr = "203.0.113.9".parse().unwrap();
+        assert_eq!(client_address(client, &headers, &trusted), client);
+
+        let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            client_address(proxy, &headers, &trusted),
+            "203.0.113.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_address(proxy, &HeaderMap::new(), &trusted), proxy);
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::Path;
+
+use anyhow::Result;
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use wgpull_shared::config::load_config;
+
+use super::{
+    config::{LighthouseConfig, LighthouseConfigFile},
+    context::{LighthouseContext, LighthouseContextProvider},
+};
+
+/// Replaces the configuration of the context with the reloaded configuration.
+///
+/// The listener, state store and background tasks are only setup at startup, changes to their
+/// settings keep the current values until the next restart, the names of these settings are
+/// returned.
+pub fn apply_config(
+    context: &mut LighthouseContext,
+    mut config: LighthouseConfig,
+) -> Vec<&'static str> {
+    let current = &context.config;
+    let mut restart_required = Vec::new();
+    if config.bindhost != current.bindhost {
+        restart_required.push("bindhost");
+        config.bindhost = current.bindhost;
+    }
+    if config.port != current.port {
+        restart_required.push("port");
+        config.port = current.port;
+    }
+    if config.state_file != current.state_file {
+        restart_required.push("state_file");
+        config.state_file = current.state_file.clone();
+    }
+    if config.state_key_file != current.state_key_file {
+        restart_required.push("state_key_file");
+        config.state_key_file = current.state_key_file.clone();
+    }
+    if config.state_store != current.state_store {
+        restart_required.push("state_store");
+        config.state_store = current.state_store.clone();
+    }
+    if config.replication != current.replication {
+        restart_required.push("replication");
+        config.replication = current.replication.clone();
+    }
+    if config.snapshot != current.snapshot {
+        restart_required.push("snapshot");
+        config.snapshot = current.snapshot.clone();
+    }
+
+    context.config = config;
+    restart_required
+}
+
+/// Reloads the configuration file into the context, returns the names of the changed settings
+/// that require a restart.
+pub async fn reload_config(
+    provider: &LighthouseContextProvider,
+    config_path: &Path,
+) -> Result<Vec<&'static str>> {
+    let config = load_config::<LighthouseConfigFile>(config_path)?;
+    let mut context = provider.context.lock().await;
+    let restart_required = apply_config(&mut context, config.lighthouse);
+
+    info!("Reloaded configuration from {:?}", config_path);
+    if !restart_required.is_empty() {
+        warn!(
+            "Changes to {} require a restart of the lighthouse.",
+            restart_required.join(", ")
+        );
+    }
+    Ok(restart_required)
+}
+
+/// Reloads the configuration whenever the lighthouse receives a SIGHUP.
+pub async fn reload_on_hangup(provider: LighthouseContextProvider) {
+    let Some(config_path) = provider.config_path.clone() else {
+        return;
+    };
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("Failed to listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if let Err(err) = reload_config(&provider, &config_path).await {
+            error!("Failed to reload configuration: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use super::apply_config;
+    use crate::{
+        config::LighthouseConfigFile,
+        context::LighthouseContext,
+        mock::{MockCommandExecutor, MockCurrentTime, MockFileAccessor},
+    };
+
+    fn lighthouse_config(extra: &str) -> LighthouseConfigFile {
+        toml::from_str(&format!(
+            r#"
+            [lighthouse]
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            state_file = "/lighthouse.state"
+            {}
+            "#,
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_reloaded_config() {
+        let mut context = LighthouseContext::init(
+            lighthouse_config("node_timeout_seconds = 300").lighthouse,
+            Arc::new(MockCurrentTime::new(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+            )),
+            Arc::new(MockFileAccessor::default()),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+
+        let mut config = lighthouse_config(
+            r#"
+            node_timeout_seconds = 600
+            admin_key = "admin"
+            "#,
+        )
+        .lighthouse;
+        config.port = 2002;
+        config.state_file = "/moved.state".to_string();
+
+        let restart_required = apply_config(&mut context, config);
+        assert_eq!(restart_required, vec!["port", "state_file"]);
+        assert_eq!(context.config.node_timeout_seconds, 600);
+        assert_eq!(context.config.admin_key.as_deref(), Some("admin"));
+        assert_eq!(context.config.port, 2001);
+        assert_eq!(context.config.state_file, "/lighthouse.state");
+    }
+}
@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Result};
+use axum::http::{HeaderMap, HeaderValue};
+use log::{info, warn};
+use std::time::Duration;
+use wgpull_shared::{client::HttpClient, headers::HEADER_LIGHTHOUSE_KEY};
+
+use super::{
+    config::ReplicationConfig, context::LighthouseContextProvider, state::LighthouseState,
+};
+
+/// Fetches the state of the leader lighthouse and replaces the local state with it.
+pub async fn sync_from_leader(
+    provider: &LighthouseContextProvider,
+    config: &ReplicationConfig,
+    http_client: &(dyn HttpClient + Send + Sync),
+) -> Result<()> {
+    let leader_url = config
+        .leader_url
+        .as_ref()
+        .ok_or_else(|| anyhow!("Follower lighthouse has no leader_url configured."))?;
+    let url = format!(
+        "{}/api/v1/replication/state",
+        leader_url.trim_end_matches('/')
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(HEADER_LIGHTHOUSE_KEY, HeaderValue::from_str(&config.key)?);
+
+    let response = http_client.get(&url, headers).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Leader responded with status: {}",
+            response.status()
+        ));
+    }
+    let state: LighthouseState = serde_json::from_str(&response.text().await?)?;
+
+    let mut context = provider.context.lock().await;
+    context.replace_state(state).await?;
+
+    Ok(())
+}
+
+/// Replicates the state of the leader lighthouse in the configured interval.
+///
+/// While the leader is reachable the follower rejects node requests, once the leader
+/// is unreachable for longer than the failover time the follower serves the nodes
+/// with the last replicated state until the leader is back.
+pub async fn follow_leader(
+    provider: LighthouseContextProvider,
+    config: ReplicationConfig,
+    http_client: Box<dyn HttpClient + Send + Sync>,
+) {
+    info!(
+        "Replicating lighthouse state from leader: {}",
+        config.leader_url.as_deref().unwrap_or("")
+    );
+    if config
+        .leader_url
+        .as_deref()
+        .is_some_and(|url| url.starts_with("http://"))
+    {
+        warn!("Replicating the lighthouse state over http://, the pre-shared keys of all nodes are sent unencrypted.");
+    }
+    let mut leader_reachable = true;
+
+    loop {
+        match sync_from_leader(&provider, &config, http_client.as_ref()).await {
+            Ok(()) => {
+                if !leader_reachable {
+                    info!("Leader lighthouse is reachable again, replicating its state.");
+                }
+                leader_reachable = true;
+            }
+            Err(err) => {
+                if leader_reachable {
+                    warn!("Failed to replicate state from leader: {}", err);
+                }
+                leader_reachable = false;
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.interval_seconds)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sync_from_leader;
+    use crate::{
+        config::{LighthouseConfig, ReplicationConfig, ReplicationRole},
+        context::{LighthouseContext, LighthouseContextProvider},
+        make_router,
+        mock::{MockCommandExecutor, MockCurrentTime, MockFileAccessor},
+        store::StateStoreType,
+    };
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+    use wgpull_shared::{client::SystemHttpClient, request::NodePullRequest};
+
+    const WG_PUBKEY_1: &str = "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=";
+    const WG_PUBKEY_2: &str = "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=";
+    const WG_PUBKEY_3: &str = "2Phnw7Nb4sAojXiSfN7UrS4uyaFRmOtBvU8MdWePwkw=";
+
+    fn lighthouse_config(replication: ReplicationConfig) -> LighthouseConfig {
+        LighthouseConfig {
+            lighthouse_key: "lighthouse".to_string(),
+            node_key: "node".to_string(),
+            additional_keys: vec![],
+            port: 0,
+            bindhost: "127.0.0.1".parse().unwrap(),
+            key_rotation_interval_seconds: 0,
+            key_rotation_tod: (2, 3).into(),
+            node_timeout_seconds: 300,
+            state_file: "/lighthouse.state".to_string(),
+            state_key_file: None,
+            state_store: StateStoreType::Toml,
+            watch_timeout_seconds: 60,
+            admin_key: None,
+            replication: Some(replication),
+            snapshot: None,
+            health_stale_factor: 3,
+            signing_key: None,
+            response_ttl_seconds: 86400,
+            rate_limit: Default::default(),
+            metrics: Default::default(),
+        }
+    }
+
+    async fn lighthouse(
+        replication: ReplicationConfig,
+        time: Arc<MockCurrentTime>,
+    ) -> LighthouseContextProvider {
+        let context = LighthouseContext::init(
+            lighthouse_config(replication),
+            time,
+            Arc::new(MockFileAccessor::default()),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+        LighthouseContextProvider::new(context)
+    }
+
+    fn pull_request(hostname: &str, public_key: &str) -> NodePullRequest {
+        NodePullRequest {
+            hostname: hostname.to_string(),
+            endpoint: hostname.to_string(),
+            public_key: public_key.to_string(),
+            listen_port: 30000,
+            persistent_keepalive: 0,
+            allowed_ips: vec![],
+            route_allowed_ips: false,
+            last_apply: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicate_state_to_followers() {
+        let time = Arc::new(MockCurrentTime::new(SystemTime::now()));
+
+        // start the leader lighthouse with two nodes in-process
+        let leader = lighthouse(
+            ReplicationConfig {
+                role: ReplicationRole::Leader,
+                key: "replicate".to_string(),
+                leader_url: None,
+                allow_insecure: false,
+                interval_seconds: 10,
+                failover_seconds: 60,
+            },
+            time.clone(),
+        )
+        .await;
+        {
+            let mut context = leader.context.lock().await;
+            context
+                .node_pull(&pull_request("node1", WG_PUBKEY_1))
+                .await
+                .unwrap();
+            context
+                .node_pull(&pull_request("node2", WG_PUBKEY_2))
+                .await
+                .unwrap();
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let leader_url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = make_router(leader.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let follower_config = ReplicationConfig {
+            role: ReplicationRole::Follower,
+            key: "replicate".to_string(),
+            leader_url: Some(leader_url),
+            // the in-process leader is only reachable over http://
+            allow_insecure: true,
+            interval_seconds: 10,
+            failover_seconds: 60,
+        };
+        let http_client = SystemHttpClient::new(5).unwrap();
+
+        // two followers replicate the state of the leader
+        for _ in 0..2 {
+            let follower = lighthouse(follower_config.clone(), time.clone()).await;
+            // a fresh follower waits for the leader before serving nodes
+            assert!(!follower.context.lock().await.is_serving());
+
+            sync_from_leader(&follower, &follower_config, &http_client)
+                .await
+                .unwrap();
+
+            let follower = follower.context.lock().await;
+            let leader = leader.context.lock().await;
+            assert_eq!(follower.state.nodes.len(), 2);
+            assert_eq!(follower.state.preshared_keys, leader.state.preshared_keys);
+            assert_eq!(follower.state.revision, leader.state.revision);
+            // the leader is reachable, nodes are rejected by the follower
+            assert!(!follower.is_serving());
+        }
+
+        // a follower serves nodes once the leader is unreachable for the failover time
+        let follower = lighthouse(follower_config.clone(), time.clone()).await;
+        sync_from_leader(&follower, &follower_config, &http_client)
+            .await
+            .unwrap();
+        let unsynced = lighthouse(follower_config.clone(), time.clone()).await;
+        time.advance(Duration::from_secs(61));
+        assert!(follower.context.lock().await.is_serving());
+        // as does a follower that never reached the leader since its start
+        assert!(unsynced.context.lock().await.is_serving());
+
+        // the state modified during the failover gets its own epoch and isn't replaced
+        // once the leader is back
+        {
+            let mut context = follower.context.lock().await;
+            context
+                .node_pull(&pull_request("node3", WG_PUBKEY_3))
+                .await
+                .unwrap();
+            assert!(context.modified_locally);
+            assert_ne!(context.state.epoch, leader.context.lock().await.state.epoch);
+        }
+        assert!(sync_from_leader(&follower, &follower_config, &http_client)
+            .await
+            .is_err());
+        {
+            let context = follower.context.lock().await;
+            assert_eq!(context.state.nodes.len(), 3);
+            assert!(!context.is_serving());
+        }
+
+        // the leader rejects followers with an invalid replication key
+        let invalid_config = ReplicationConfig {
+            key: "invalid".to_string(),
+            ..follower_config
+        };
+        assert!(sync_from_leader(&follower, &invalid_config, &http_client)
+            .await
+            .is_err());
+    }
+}
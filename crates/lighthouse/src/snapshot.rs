@@ -0,0 +1,232 @@
+use std::{path::Path, time::SystemTime};
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{error, info};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use wgpull_shared::file::FileAccessor;
+
+use super::{
+    archive::{export_state, read_passphrase},
+    config::SnapshotConfig,
+    context::LighthouseContextProvider,
+};
+
+/// Format of the UTC timestamp in the snapshot file names.
+const SNAPSHOT_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Returns the name of the snapshot file of the state file, without its timestamp.
+fn snapshot_prefix(state_file: &str) -> String {
+    let file_name = Path::new(state_file)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}.snapshot.", file_name)
+}
+
+/// Returns the path of a snapshot of the state file taken at the given time.
+///
+/// Snapshots are stored next to the state file, the UTC timestamp sorts chronologically.
+pub fn snapshot_path(state_file: &str, time: SystemTime) -> String {
+    let time: DateTime<Utc> = time.into();
+    format!(
+        "{}.snapshot.{}",
+        state_file,
+        time.format(SNAPSHOT_TIME_FORMAT)
+    )
+}
+
+/// Returns true if the file name is a snapshot with the prefix, other files like leftover
+/// temporary files of interrupted writes are not.
+fn is_snapshot_name(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix).is_some_and(|timestamp| {
+        NaiveDateTime::parse_from_str(timestamp, SNAPSHOT_TIME_FORMAT).is_ok()
+    })
+}
+
+/// Removes the oldest snapshots of the state file exceeding the number of snapshots to keep,
+/// returns the removed snapshot paths.
+pub async fn prune_snapshots(
+    state_file: &str,
+    keep: usize,
+    accessor: &dyn FileAccessor,
+) -> Result<Vec<String>> {
+    let directory = match Path::new(state_file).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().to_string(),
+        _ => ".".to_string(),
+    };
+    let prefix = snapshot_prefix(state_file);
+
+    let mut snapshots: Vec<String> = accessor
+        .list_dir(&directory)
+        .await?
+        .into_iter()
+        .filter(|path| {
+            Path::new(path)
+                .file_name()
+                .is_some_and(|name| is_snapshot_name(&name.to_string_lossy(), &prefix))
+        })
+        .collect();
+    snapshots.sort();
+
+    let remove = snapshots.len().saturating_sub(keep);
+    let removed: Vec<String> = snapshots.into_iter().take(remove).collect();
+    for path in &removed {
+        accessor.remove(path).await?;
+    }
+    Ok(removed)
+}
+
+/// Writes an encrypted snapshot of the current state next to the state file and prunes old
+/// snapshots, returns the path of the snapshot.
+pub async fn take_snapshot(
+    provider: &LighthouseContextProvider,
+    config: &SnapshotConfig,
+) -> Result<String> {
+    // only hold the lock to copy the state, encryption is comparatively slow
+    let (state, state_file, now, file_accessor) = {
+        let context = provider.context.lock().await;
+        (
+            context.state.clone(),
+            context.config.state_file.clone(),
+            context.time.now(),
+            context.file_accessor.clone(),
+        )
+    };
+
+    let passphrase = read_passphrase(&config.passphrase_file, file_accessor.as_ref()).await?;
+    let archive = export_state(&state, &passphrase)?;
+
+    let path = snapshot_path(&state_file, now);
+    file_accessor.write_atomic(&path, &archive).await?;
+
+    for removed in prune_snapshots(&state_file, config.keep, file_accessor.as_ref()).await? {
+        info!("Removed old state snapshot {}", removed);
+    }
+
+    Ok(path)
+}
+
+/// Takes snapshots of the lighthouse state in the configured interval.
+pub async fn run_snapshots(provider: LighthouseContextProvider, config: SnapshotConfig) {
+    let mut ticker = interval(Duration::from_secs(config.interval_seconds));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick completes immediately, the first snapshot is taken after one interval
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        match take_snapshot(&provider, &config).await {
+            Ok(path) => info!("Saved state snapshot to {}", path),
+            Err(err) => error!("Error saving state snapshot: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use wgpull_shared::file::FileAccessor;
+
+    use super::{prune_snapshots, snapshot_path, take_snapshot};
+    use crate::{
+        archive::import_state,
+        config::{LighthouseConfig, SnapshotConfig},
+        context::{LighthouseContext, LighthouseContextProvider},
+        mock::{MockCommandExecutor, MockCurrentTime, MockFileAccessor},
+    };
+
+    #[test]
+    fn test_snapshot_path() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        assert_eq!(
+            snapshot_path("/var/lib/lighthouse.state", time),
+            "/var/lib/lighthouse.state.snapshot.20240703T094640Z"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_take_snapshot_with_retention() {
+        let config: LighthouseConfig = toml::from_str(
+            r#"
+            lighthouse_key = "lighthouse"
+            node_key = "node"
+            port = 2001
+            bindhost = "127.0.0.1"
+            key_rotation_interval_seconds = 0
+            key_rotation_tod = [2, 3]
+            node_timeout_seconds = 300
+            state_file = "/var/lib/lighthouse.state"
+
+            [snapshot]
+            passphrase_file = "/etc/passphrase"
+            keep = 2
+            "#,
+        )
+        .unwrap();
+        let snapshot_config: SnapshotConfig = config.snapshot.clone().unwrap();
+        assert_eq!(snapshot_config.interval_seconds, 3600);
+
+        let time = Arc::new(MockCurrentTime::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000),
+        ));
+        let accessor = Arc::new(MockFileAccessor::default());
+        accessor.write("/etc/passphrase", "secret").await.unwrap();
+        accessor
+            .write("/var/lib/other.state.snapshot.20000101T000000Z", "")
+            .await
+            .unwrap();
+        // leftover of an interrupted write, it doesn't count as a snapshot
+        accessor
+            .write(
+                "/var/lib/lighthouse.state.snapshot.20990101T000000Z.1234.0.tmp",
+                "",
+            )
+            .await
+            .unwrap();
+        let context = LighthouseContext::init(
+            config,
+            time.clone(),
+            accessor.clone(),
+            Arc::new(MockCommandExecutor::default()),
+        )
+        .await
+        .unwrap();
+        let provider = LighthouseContextProvider::new(context);
+
+        let mut paths = Vec::new();
+        for _ in 0..3 {
+            paths.push(take_snapshot(&provider, &snapshot_config).await.unwrap());
+            time.advance(Duration::from_secs(3600));
+        }
+
+        // the oldest snapshot is removed, snapshots of other state files are kept
+        let mut files = accessor.list_dir("/var/lib").await.unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "/var/lib/lighthouse.state.snapshot.20240703T104640Z".to_string(),
+                "/var/lib/lighthouse.state.snapshot.20240703T114640Z".to_string(),
+                "/var/lib/lighthouse.state.snapshot.20990101T000000Z.1234.0.tmp".to_string(),
+                "/var/lib/other.state.snapshot.20000101T000000Z".to_string(),
+            ]
+        );
+        assert_eq!(files[..2], paths[1..]);
+
+        let archive = accessor.read(&paths[2]).await.unwrap();
+        let mut state = import_state(&paths[2], &archive, "secret").unwrap();
+        let current = provider.context.lock().await.state.clone();
+        state.epoch = current.epoch;
+        assert_eq!(state, current);
+
+        let removed = prune_snapshots("/var/lib/lighthouse.state", 0, accessor.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(removed.len(), 2);
+    }
+}
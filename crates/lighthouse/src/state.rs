@@ -1,22 +1,77 @@
 use anyhow::Result;
 use chrono::prelude::Timelike;
 use log::info;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use sha2::{Digest, Sha256};
 use std::{
     collections::{hash_map::Entry, HashMap},
     sync::Arc,
     time::SystemTime,
 };
 
+use toml::Value;
 use wgpull_shared::{
-    command::CommandExecutor, file::FileAccessor, request::NodePullRequest,
-    response::NodePullResponsePeer, time::CurrentTime, wg::WireguardCommand,
+    command::CommandExecutor,
+    crypto::SecretKey,
+    file::FileAccessor,
+    request::NodePullRequest,
+    response::NodePullResponsePeer,
+    state::{
+        read_versioned_state_file, serialize_versioned_state, table_mut, SecretFields,
+        StateMigration,
+    },
+    time::CurrentTime,
+    wg::WireguardCommand,
 };
 
+use super::config::RotationWindow;
 use super::peer_pair::PeerPair;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Migrations of the lighthouse state file, see `StateMigration`.
+pub(crate) const LIGHTHOUSE_STATE_MIGRATIONS: &[StateMigration] =
+    &[migrate_v0_to_v1, migrate_v1_to_v2, migrate_v2_to_v3];
+
+/// Version 1 introduces peer configuration revisions.
+fn migrate_v0_to_v1(state: &mut toml::Table) -> Result<(), String> {
+    state.insert("revision".to_string(), Value::Integer(0));
+    if let Some(nodes) = table_mut(state, "nodes")? {
+        for (hostname, node) in nodes.iter_mut() {
+            let Value::Table(node) = node else {
+                return Err(format!("nodes.{} is not a table", hostname));
+            };
+            node.insert("peers_revision".to_string(), Value::Integer(0));
+            node.insert("peers_digest".to_string(), Value::String(String::new()));
+        }
+    }
+    Ok(())
+}
+
+/// Version 2 tracks the creation time of pre-shared keys, unknown for existing keys.
+fn migrate_v1_to_v2(state: &mut toml::Table) -> Result<(), String> {
+    state.insert(
+        "preshared_keys_created".to_string(),
+        Value::Array(Vec::new()),
+    );
+    Ok(())
+}
+
+/// Version 3 identifies the state by an epoch, which is part of the ETag of pull responses.
+fn migrate_v2_to_v3(state: &mut toml::Table) -> Result<(), String> {
+    state.insert(
+        "epoch".to_string(),
+        Value::Integer(new_state_epoch() as i64),
+    );
+    Ok(())
+}
+
+/// Creates a random epoch for a state whose revision counter starts over.
+pub fn new_state_epoch() -> u64 {
+    rand::thread_rng().gen_range(1..=u32::MAX as u64)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LighthouseNodeLease {
     /// Time the node was last announced or did a pull.
     pub last_seen: SystemTime,
@@ -44,6 +99,12 @@ pub struct LighthouseNodeLease {
 
     /// Whether or not the allowed ips should route through the wireguard interface.
     pub route_allowed_ips: bool,
+
+    /// Revision of the peer configuration last returned to the node.
+    pub peers_revision: u64,
+
+    /// Digest of the peer configuration last returned to the node.
+    pub peers_digest: String,
 }
 
 impl LighthouseNodeLease {
@@ -57,10 +118,32 @@ impl LighthouseNodeLease {
             && self.allowed_ips == other.allowed_ips
             && self.route_allowed_ips == other.route_allowed_ips
     }
+
+    /// Creates the peer configuration of this node as returned to other nodes.
+    pub fn to_peer_response(&self, preshared_key: String) -> NodePullResponsePeer {
+        NodePullResponsePeer {
+            hostname: self.hostname.clone(),
+            public_key: self.public_key.clone(),
+            preshared_key,
+            endpoint_host: self.endpoint_host.clone(),
+            endpoint_port: self.endpoint_port,
+            allowed_ips: self.allowed_ips.clone(),
+            persistent_keepalive: self.persistent_keepalive,
+            route_allowed_ips: self.route_allowed_ips,
+            signed_endpoint: None,
+        }
+    }
+}
+
+/// Creates a digest of the peer configuration returned to a node.
+fn peers_digest(peers: &[NodePullResponsePeer]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_string(peers)?);
+    Ok(hex::encode(hasher.finalize()))
 }
 
 #[serde_as]
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LighthouseState {
     /// All known nodes in the network that are known to the lighthouse.
     pub nodes: HashMap<String, LighthouseNodeLease>,
@@ -71,48 +154,68 @@ pub struct LighthouseState {
     #[serde_as(as = "Vec<(_, _)>")]
     pub preshared_keys: HashMap<PeerPair, String>,
 
+    /// Time the pre-shared keys were generated, used to export their age.
+    #[serde_as(as = "Vec<(_, _)>")]
+    pub preshared_keys_created: HashMap<PeerPair, SystemTime>,
+
     /// Timestamp when the lighthouse state was last modified.
     /// Keeps track of changed nodes as well as new/changed pershared key pairs.
     pub last_modified: SystemTime,
+
+    /// Monotonically increasing revision counter, incremented whenever the peer
+    /// configuration of any node changes.
+    pub revision: u64,
+
+    /// Random identifier of the revision counter, a new epoch is created whenever the counter
+    /// starts over (new or imported state), so revisions of different states never match.
+    pub epoch: u64,
+}
+
+impl SecretFields for LighthouseState {
+    fn secrets_mut(&mut self) -> Vec<&mut String> {
+        self.preshared_keys.values_mut().collect()
+    }
 }
 
 impl LighthouseState {
     /// Load state from a file in TOML format, if the state file is not present return None without error.
     /// Otherwise return the state or an error in case the state is corrupted or filesystem issues.
+    /// State files of older versions are migrated to the current version.
+    /// Pre-shared keys encrypted in the state file are decrypted with the key.
     pub async fn from_file(
         path: &str,
         accessor: &dyn FileAccessor,
+        key: Option<&SecretKey>,
     ) -> Result<Option<LighthouseState>> {
         info!("Restoring lighthouse state from {}", path);
-        let contents = match accessor.read(path).await {
-            Ok(contents) => contents,
-            Err(_) => {
-                info!("No lighthouse state file found, starting with empty state.");
-                return Ok(None);
-            }
-        };
-
-        let state = match toml::from_str(&contents) {
-            Ok(state) => state,
-            Err(_) => return Err(anyhow::anyhow!("Error parsing state file.")),
-        };
+        let state =
+            read_versioned_state_file(path, accessor, LIGHTHOUSE_STATE_MIGRATIONS, key).await?;
+        if state.is_none() {
+            info!("No lighthouse state file found, starting with empty state.");
+        }
 
-        Ok(Some(state))
+        Ok(state)
     }
 
-    /// Saves the state to disk in TOML format.
-    pub async fn save(&self, path: &str, accessor: &dyn FileAccessor) -> Result<()> {
+    /// Saves the state to disk in TOML format, replacing the state file atomically.
+    /// Pre-shared keys are encrypted if a key is given.
+    pub async fn save(
+        &self,
+        path: &str,
+        accessor: &dyn FileAccessor,
+        key: Option<&SecretKey>,
+    ) -> Result<()> {
         info!("Saving lighthouse state to {}", path);
-        // Convert the state to a TOML string.
-        let content = toml::to_string(self)?;
+        // Convert the state to a versioned TOML string.
+        let content = serialize_versioned_state(self, LIGHTHOUSE_STATE_MIGRATIONS, key)?;
         // Write the TOML string to the state file.
-        accessor.write(path, &content).await?;
+        accessor.write_atomic(path, &content).await?;
         Ok(())
     }
 
     /// Update or insert a node lease from a pull request.
-    /// It keeps the last rotation time of the node lease, but updates the last_modified time
-    /// if the node lease has changed.
+    /// It keeps the last rotation time and peer revision of the node lease, but updates the
+    /// last_modified time if the node lease has changed.
     pub fn upsert_node_lease_from_pull_request(
         &mut self,
         request: &NodePullRequest,
@@ -125,6 +228,11 @@ impl LighthouseState {
             .map(|node| node.last_rotation)
             .min()
             .unwrap_or_else(|| time.now());
+        let (peers_revision, peers_digest) = self
+            .nodes
+            .get(&request.hostname)
+            .map(|lease| (lease.peers_revision, lease.peers_digest.clone()))
+            .unwrap_or_default();
         let new_lease = LighthouseNodeLease {
             last_seen: time.now(),
             last_rotation,
@@ -135,10 +243,12 @@ impl LighthouseState {
             persistent_keepalive: request.persistent_keepalive,
             allowed_ips: request.allowed_ips.clone(),
             route_allowed_ips: request.route_allowed_ips,
+            peers_revision,
+            peers_digest,
         };
         // update last_modified if new lease has changed or is new
         if let Some(existing_lease) = self.nodes.get(&request.hostname) {
-            if existing_lease.compare_to(&new_lease) {
+            if !existing_lease.compare_to(&new_lease) {
                 self.last_modified = time.now();
             }
         } else {
@@ -154,12 +264,16 @@ impl LighthouseState {
             .nodes
             .iter()
             .filter(|(_, node)| {
-                let duration = now.duration_since(node.last_seen).unwrap();
+                let duration = now.duration_since(node.last_seen).unwrap_or_default();
                 duration.as_secs() > expiration_seconds
             })
             .map(|(hostname, _)| hostname.clone())
             .collect();
 
+        if !expired_nodes.is_empty() {
+            self.last_modified = now;
+        }
+
         for hostname in expired_nodes {
             self.nodes.remove(&hostname);
         }
@@ -171,51 +285,101 @@ impl LighthouseState {
         &mut self,
         hostname: &str,
         executor: Arc<dyn CommandExecutor>,
+        time: &(dyn CurrentTime + Send + Sync),
     ) -> Vec<NodePullResponsePeer> {
         let wireguard_command = WireguardCommand::new(executor.as_ref());
 
-        let mut peers: Vec<NodePullResponsePeer> = Vec::new();
-
-        for node in self.nodes.values() {
-            if node.hostname != hostname {
-                // create or retrieve pre-shared key for this peer pair:
-                info!(
-                    "Creating or retrieving pre-shared key for peer pair: {} and {}",
-                    hostname, node.hostname
-                );
-
-                let preshared_key = match self
-                    .preshared_keys
-                    .entry(PeerPair::new(hostname.to_string(), node.hostname.clone()))
-                {
-                    Entry::Occupied(entry) => entry.get().clone(),
-                    Entry::Vacant(entry) => {
-                        let psk = wireguard_command
-                            .generate_psk()
-                            .await
-                            .expect("Lighthouse failed to generate pre-shared key.");
-                        entry.insert(psk.clone());
-                        psk
-                    }
-                };
-
-                peers.push(NodePullResponsePeer {
-                    hostname: node.hostname.clone(),
-                    public_key: node.public_key.clone(),
-                    preshared_key,
-                    endpoint_host: node.endpoint_host.clone(),
-                    endpoint_port: node.endpoint_port,
-                    allowed_ips: node.allowed_ips.clone(),
-                    persistent_keepalive: node.persistent_keepalive,
-                    route_allowed_ips: node.route_allowed_ips,
-                });
+        let peer_hostnames: Vec<String> = self
+            .nodes
+            .values()
+            .filter(|node| node.hostname != hostname)
+            .map(|node| node.hostname.clone())
+            .collect();
+        for peer_hostname in peer_hostnames {
+            // create or retrieve pre-shared key for this peer pair:
+            info!(
+                "Creating or retrieving pre-shared key for peer pair: {} and {}",
+                hostname, peer_hostname
+            );
+
+            let pair = PeerPair::new(hostname.to_string(), peer_hostname);
+            if let Entry::Vacant(entry) = self.preshared_keys.entry(pair.clone()) {
+                let psk = wireguard_command
+                    .generate_psk()
+                    .await
+                    .expect("Lighthouse failed to generate pre-shared key.");
+                entry.insert(psk);
+                self.preshared_keys_created.insert(pair, time.now());
             }
         }
 
-        // sort peers by hostname:
+        self.peers_of_node(hostname)
+            .expect("Pre-shared keys exist for all peers of the node.")
+    }
+
+    /// Builds the peers of a node from the leases of the other nodes, sorted by their hostname.
+    /// Returns None if the pre-shared key of a peer pair doesn't exist yet.
+    fn peers_of_node(&self, hostname: &str) -> Option<Vec<NodePullResponsePeer>> {
+        let mut peers = self
+            .nodes
+            .values()
+            .filter(|node| node.hostname != hostname)
+            .map(|node| {
+                let pair = PeerPair::new(hostname.to_string(), node.hostname.clone());
+                self.preshared_keys
+                    .get(&pair)
+                    .map(|preshared_key| node.to_peer_response(preshared_key.clone()))
+            })
+            .collect::<Option<Vec<_>>>()?;
         peers.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        Some(peers)
+    }
 
-        peers
+    /// Returns the revision of the peer configuration of the node, the revision is
+    /// incremented if the peers differ from the ones last returned to the node.
+    pub fn update_peers_revision(
+        &mut self,
+        hostname: &str,
+        peers: &[NodePullResponsePeer],
+        time: &dyn CurrentTime,
+    ) -> Result<u64> {
+        let digest = peers_digest(peers)?;
+
+        let Some(node) = self.nodes.get_mut(hostname) else {
+            return Ok(self.revision);
+        };
+
+        if node.peers_digest != digest {
+            self.revision += 1;
+            info!(
+                "Peer configuration of node {} changed, revision {}.",
+                hostname, self.revision
+            );
+            node.peers_revision = self.revision;
+            node.peers_digest = digest;
+            self.last_modified = time.now();
+        }
+
+        Ok(node.peers_revision)
+    }
+
+    /// Returns true if the peer configuration of the node differs from the one last returned
+    /// to it, or if the node doesn't know the current revision of its peer configuration.
+    pub fn has_peers_changed(&self, hostname: &str, revision: Option<u64>) -> bool {
+        let Some(lease) = self.nodes.get(hostname) else {
+            return true;
+        };
+        if revision != Some(lease.peers_revision) {
+            return true;
+        }
+
+        // a new peer without pre-shared key yet
+        let Some(peers) = self.peers_of_node(hostname) else {
+            return true;
+        };
+        peers_digest(&peers)
+            .map(|digest| digest != lease.peers_digest)
+            .unwrap_or(true)
     }
 
     /// Determines if the node should regenerate keys based on the regeneration interval and time of day.
@@ -225,7 +389,7 @@ impl LighthouseState {
         &mut self,
         hostname: &str,
         interval_seconds: u64,
-        tod: (u8, u8),
+        tod: &RotationWindow,
         time: &dyn CurrentTime,
     ) -> Result<bool> {
         if interval_seconds == 0 {
@@ -237,9 +401,10 @@ impl LighthouseState {
             let hour = time.now_chrono().hour() as u8;
             let duration = now.duration_since(node.last_rotation)?;
 
-            if duration.as_secs() >= interval_seconds && (hour >= tod.0) && (hour < tod.1) {
+            if duration.as_secs() >= interval_seconds && tod.contains(hour) {
                 info!("Rotating keys for node {}.", hostname);
                 node.last_rotation = now;
+                self.last_modified = now;
                 return Ok(true);
             }
         }
@@ -250,12 +415,17 @@ impl LighthouseState {
 
 #[cfg(test)]
 mod tests {
-    use super::{LighthouseNodeLease, LighthouseState};
+    use super::{LighthouseNodeLease, LighthouseState, PeerPair};
+    use crate::mock::MockFileAccessor;
     use std::{
         collections::HashMap,
         time::{Duration, SystemTime},
     };
-    use wgpull_shared::{request::NodePullRequest, time::CurrentTime};
+    use wgpull_shared::crypto::SecretKey;
+    use wgpull_shared::{
+        file::FileAccessor, request::NodePullRequest, response::NodePullResponsePeer,
+        time::CurrentTime,
+    };
 
     struct MockCurrentTime {
         now: SystemTime,
@@ -281,7 +451,10 @@ mod tests {
         let mut state = LighthouseState {
             nodes: HashMap::new(),
             preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
             last_modified: now,
+            revision: 0,
+            epoch: 1,
         };
         let mut time = MockCurrentTime { now };
         let mut node1 = NodePullRequest {
@@ -292,6 +465,7 @@ mod tests {
             persistent_keepalive: 0,
             allowed_ips: vec![],
             route_allowed_ips: false,
+            last_apply: None,
         };
 
         state.upsert_node_lease_from_pull_request(&node1, &time);
@@ -323,7 +497,10 @@ mod tests {
         let mut state = LighthouseState {
             nodes: HashMap::new(),
             preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
             last_modified: now,
+            revision: 0,
+            epoch: 1,
         };
 
         let node1 = LighthouseNodeLease {
@@ -336,6 +513,8 @@ mod tests {
             persistent_keepalive: 0,
             allowed_ips: vec![],
             route_allowed_ips: false,
+            peers_revision: 0,
+            peers_digest: String::new(),
         };
         let node2 = LighthouseNodeLease {
             last_seen: ten_seconds_ago,
@@ -347,6 +526,8 @@ mod tests {
             persistent_keepalive: 0,
             allowed_ips: vec![],
             route_allowed_ips: false,
+            peers_revision: 0,
+            peers_digest: String::new(),
         };
         let node3 = LighthouseNodeLease {
             last_seen: now,
@@ -358,6 +539,8 @@ mod tests {
             persistent_keepalive: 0,
             allowed_ips: vec![],
             route_allowed_ips: false,
+            peers_revision: 0,
+            peers_digest: String::new(),
         };
 
         state.nodes.insert("node1".to_string(), node1);
@@ -388,4 +571,184 @@ mod tests {
         assert!(state2.nodes.contains_key("node2"));
         assert!(state2.nodes.contains_key("node3"));
     }
+
+    #[test]
+    fn test_context_update_peers_revision() {
+        let now = SystemTime::now();
+        let mut state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: now,
+            revision: 0,
+            epoch: 1,
+        };
+        let time = MockCurrentTime { now };
+        let node1 = NodePullRequest {
+            public_key: WG_PUBKEY_1.to_string(),
+            hostname: "node1".to_string(),
+            endpoint: "node1".to_string(),
+            listen_port: 30000,
+            persistent_keepalive: 0,
+            allowed_ips: vec![],
+            route_allowed_ips: false,
+            last_apply: None,
+        };
+        state.upsert_node_lease_from_pull_request(&node1, &time);
+
+        let mut peers = vec![NodePullResponsePeer {
+            hostname: "node2".to_string(),
+            public_key: WG_PUBKEY_2.to_string(),
+            preshared_key: WG_PUBKEY_3.to_string(),
+            endpoint_host: "node2".to_string(),
+            endpoint_port: 30000,
+            allowed_ips: vec![],
+            persistent_keepalive: 0,
+            route_allowed_ips: false,
+            signed_endpoint: None,
+        }];
+
+        // the first peer view creates a new revision:
+        let revision = state.update_peers_revision("node1", &peers, &time).unwrap();
+        assert_eq!(revision, 1);
+
+        // the same peers and another pull keep the revision:
+        state.upsert_node_lease_from_pull_request(&node1, &time);
+        let revision = state.update_peers_revision("node1", &peers, &time).unwrap();
+        assert_eq!(revision, 1);
+
+        // a changed peer increments the revision:
+        peers[0].endpoint_port = 42;
+        let revision = state.update_peers_revision("node1", &peers, &time).unwrap();
+        assert_eq!(revision, 2);
+        assert_eq!(state.revision, 2);
+    }
+
+    #[test]
+    fn test_context_has_peers_changed() {
+        let now = SystemTime::now();
+        let mut state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: now,
+            revision: 0,
+            epoch: 1,
+        };
+        let time = MockCurrentTime { now };
+        let mut request = NodePullRequest {
+            public_key: WG_PUBKEY_1.to_string(),
+            hostname: "node1".to_string(),
+            endpoint: "node1".to_string(),
+            listen_port: 30000,
+            persistent_keepalive: 0,
+            allowed_ips: vec![],
+            route_allowed_ips: false,
+            last_apply: None,
+        };
+        state.upsert_node_lease_from_pull_request(&request, &time);
+        request.hostname = "node2".to_string();
+        request.public_key = WG_PUBKEY_2.to_string();
+        state.upsert_node_lease_from_pull_request(&request, &time);
+        state.preshared_keys.insert(
+            PeerPair::new("node1".to_string(), "node2".to_string()),
+            WG_PUBKEY_3.to_string(),
+        );
+
+        let peers = vec![state
+            .nodes
+            .get("node2")
+            .unwrap()
+            .to_peer_response(WG_PUBKEY_3.to_string())];
+        let revision = state.update_peers_revision("node1", &peers, &time).unwrap();
+
+        assert!(!state.has_peers_changed("node1", Some(revision)));
+        assert!(state.has_peers_changed("node1", Some(revision + 1)));
+        assert!(state.has_peers_changed("node1", None));
+        assert!(state.has_peers_changed("node3", Some(revision)));
+
+        // a changed endpoint of the peer changes the peers of node1:
+        request.endpoint = "node2.example.com".to_string();
+        state.upsert_node_lease_from_pull_request(&request, &time);
+        assert!(state.has_peers_changed("node1", Some(revision)));
+    }
+
+    #[tokio::test]
+    async fn test_state_migrate_v0_fixture() {
+        let accessor = MockFileAccessor::default();
+        let v0 = include_str!("../fixtures/lighthouse_state_v0.toml");
+        accessor.write("/state", v0).await.unwrap();
+
+        let state = LighthouseState::from_file("/state", &accessor, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.nodes.len(), 2);
+        assert_eq!(state.revision, 0);
+        assert_ne!(state.epoch, 0);
+        let node2 = state.nodes.get("node2").unwrap();
+        assert_eq!(node2.endpoint_host, "node2.example.com");
+        assert_eq!(node2.peers_revision, 0);
+        assert_eq!(
+            state
+                .preshared_keys
+                .get(&PeerPair::new("node2".to_string(), "node1".to_string())),
+            Some(&WG_PUBKEY_3.to_string())
+        );
+        assert!(state.preshared_keys_created.is_empty());
+
+        // the old file is kept as backup and the state file is migrated
+        assert_eq!(accessor.read("/state.v0.bak").await.unwrap(), v0);
+        let migrated = accessor.read("/state").await.unwrap();
+        assert!(migrated.starts_with("version = 3\n"));
+        let restored = LighthouseState::from_file("/state", &accessor, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored, state);
+    }
+
+    #[tokio::test]
+    async fn test_state_from_file_parse_error() {
+        let accessor = MockFileAccessor::default();
+        accessor
+            .write("/state", "version = 1\n[state]\nnodes = 42\n")
+            .await
+            .unwrap();
+
+        let err = LighthouseState::from_file("/state", &accessor, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("/state"));
+    }
+
+    #[tokio::test]
+    async fn test_state_encrypted_preshared_keys() {
+        let accessor = MockFileAccessor::default();
+        let key = SecretKey::from_material("key").unwrap();
+        let state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::from([(
+                PeerPair::new("node1".to_string(), "node2".to_string()),
+                WG_PUBKEY_3.to_string(),
+            )]),
+            preshared_keys_created: HashMap::from([(
+                PeerPair::new("node1".to_string(), "node2".to_string()),
+                SystemTime::UNIX_EPOCH,
+            )]),
+            last_modified: SystemTime::UNIX_EPOCH,
+            revision: 0,
+            epoch: 1,
+        };
+        state.save("/state", &accessor, Some(&key)).await.unwrap();
+        assert!(!accessor.read("/state").await.unwrap().contains(WG_PUBKEY_3));
+
+        let restored = LighthouseState::from_file("/state", &accessor, Some(&key))
+            .await
+            .unwrap();
+        assert_eq!(restored, Some(state));
+        assert!(LighthouseState::from_file("/state", &accessor, None)
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,80 @@
+use serde::Serialize;
+use wgpull_shared::{request::NodeApplyStatus, time::unix_timestamp};
+
+use super::{metrics::LighthouseApplyStatus, state::LighthouseNodeLease};
+
+/// The last apply result of a node as exposed by the admin api.
+#[derive(Debug, Clone, Serialize)]
+pub struct LighthouseNodeApplyStatus {
+    /// Whether or not the node applied its configuration successfully.
+    pub success: bool,
+
+    /// The error message reported by the node in case the apply failed.
+    pub error: Option<String>,
+
+    /// The configuration revision that was applied.
+    pub revision: Option<u64>,
+
+    /// The backend the node uses to configure its interface.
+    pub backend: String,
+
+    /// Time the lighthouse received the apply result (unix timestamp).
+    pub reported_at: u64,
+}
+
+/// The status of a node known to the lighthouse as exposed by the admin api.
+#[derive(Debug, Clone, Serialize)]
+pub struct LighthouseNodeStatus {
+    /// The hostname of the node.
+    pub hostname: String,
+
+    /// The node's public key.
+    pub public_key: String,
+
+    /// Wireguard endpoint (hostname/ip).
+    pub endpoint_host: String,
+
+    /// Wireguard endpoint port.
+    pub endpoint_port: u32,
+
+    /// Wireguard allowed IPs.
+    pub allowed_ips: Vec<String>,
+
+    /// Time the node was last announced or did a pull (unix timestamp).
+    pub last_seen: u64,
+
+    /// When the last rotation of node keys occurred (unix timestamp).
+    pub last_rotation: u64,
+
+    /// The last apply result reported by the node, none if the node has not reported one yet.
+    pub last_apply: Option<LighthouseNodeApplyStatus>,
+}
+
+impl LighthouseNodeStatus {
+    pub fn new(lease: &LighthouseNodeLease, apply: Option<&LighthouseApplyStatus>) -> Self {
+        Self {
+            hostname: lease.hostname.clone(),
+            public_key: lease.public_key.clone(),
+            endpoint_host: lease.endpoint_host.clone(),
+            endpoint_port: lease.endpoint_port,
+            allowed_ips: lease.allowed_ips.clone(),
+            last_seen: unix_timestamp(lease.last_seen),
+            last_rotation: unix_timestamp(lease.last_rotation),
+            last_apply: apply.map(|apply| {
+                let NodeApplyStatus {
+                    success,
+                    error,
+                    revision,
+                    backend,
+                } = apply.status.clone();
+                LighthouseNodeApplyStatus {
+                    success,
+                    error,
+                    revision,
+                    backend,
+                    reported_at: unix_timestamp(apply.reported_at),
+                }
+            }),
+        }
+    }
+}
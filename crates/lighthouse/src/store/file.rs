@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use wgpull_shared::{crypto::SecretKey, file::FileAccessor};
+
+use super::{super::state::LighthouseState, interface::StateStore};
+
+/// Stores the lighthouse state in a TOML file.
+pub struct FileStateStore {
+    path: String,
+    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+    /// Key to encrypt the pre-shared keys in the state file.
+    key: Option<SecretKey>,
+}
+
+impl FileStateStore {
+    pub fn new(
+        path: &str,
+        file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+        key: Option<SecretKey>,
+    ) -> Self {
+        Self {
+            path: path.to_string(),
+            file_accessor,
+            key,
+        }
+    }
+}
+
+#[async_trait]
+impl StateStore for FileStateStore {
+    async fn load(&self) -> Result<Option<LighthouseState>> {
+        LighthouseState::from_file(&self.path, self.file_accessor.as_ref(), self.key.as_ref()).await
+    }
+
+    async fn save(&self, state: &LighthouseState) -> Result<()> {
+        state
+            .save(&self.path, self.file_accessor.as_ref(), self.key.as_ref())
+            .await
+    }
+}
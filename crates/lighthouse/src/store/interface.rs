@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+use wgpull_shared::{crypto::SecretKey, file::FileAccessor};
+
+use super::{super::state::LighthouseState, file::FileStateStore, sqlite::SqliteStateStore};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateStoreType {
+    /// The state is stored in a TOML file, rewritten on every save.
+    #[default]
+    Toml,
+    /// The state is stored in an embedded SQLite database, updating only changed rows.
+    Sqlite,
+}
+
+/// Persistent storage of the lighthouse state.
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    /// Loads the stored state, returns none if no state was stored yet.
+    async fn load(&self) -> Result<Option<LighthouseState>>;
+
+    /// Stores the state, replacing the previously stored state.
+    async fn save(&self, state: &LighthouseState) -> Result<()>;
+}
+
+/// Opens the configured state store at the given path, pre-shared keys are encrypted with the
+/// key if given.
+pub fn open_state_store(
+    store: &StateStoreType,
+    path: &str,
+    file_accessor: Arc<dyn FileAccessor + Send + Sync>,
+    key: Option<SecretKey>,
+) -> Result<Box<dyn StateStore>> {
+    Ok(match store {
+        StateStoreType::Toml => Box::new(FileStateStore::new(path, file_accessor, key)),
+        StateStoreType::Sqlite => Box::new(SqliteStateStore::open(path, key)?),
+    })
+}
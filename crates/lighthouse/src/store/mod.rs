@@ -0,0 +1,7 @@
+mod file;
+mod interface;
+mod sqlite;
+
+pub use file::FileStateStore;
+pub use interface::{open_state_store, StateStore, StateStoreType};
+pub use sqlite::SqliteStateStore;
@@ -0,0 +1,542 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    os::unix::fs::OpenOptionsExt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use tokio::task;
+use wgpull_shared::{
+    crypto::{is_encrypted_secret, SecretKey},
+    file::check_private_file,
+};
+
+use super::{
+    super::{
+        peer_pair::PeerPair,
+        state::{new_state_epoch, LighthouseNodeLease, LighthouseState},
+    },
+    interface::StateStore,
+};
+
+/// Schema migrations, the schema version is the number of applied migrations.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY NOT NULL,
+        value INTEGER NOT NULL
+    );
+    CREATE TABLE nodes (
+        hostname TEXT PRIMARY KEY NOT NULL,
+        last_seen INTEGER NOT NULL,
+        last_rotation INTEGER NOT NULL,
+        public_key TEXT NOT NULL,
+        endpoint_host TEXT NOT NULL,
+        endpoint_port INTEGER NOT NULL,
+        persistent_keepalive INTEGER NOT NULL,
+        allowed_ips TEXT NOT NULL,
+        route_allowed_ips INTEGER NOT NULL,
+        peers_revision INTEGER NOT NULL,
+        peers_digest TEXT NOT NULL
+    );
+    CREATE TABLE preshared_keys (
+        peer_a TEXT NOT NULL,
+        peer_b TEXT NOT NULL,
+        preshared_key TEXT NOT NULL,
+        PRIMARY KEY (peer_a, peer_b)
+    );
+",
+    "
+    ALTER TABLE preshared_keys ADD COLUMN created INTEGER;
+",
+];
+
+const META_LAST_MODIFIED: &str = "last_modified";
+const META_REVISION: &str = "revision";
+const META_EPOCH: &str = "epoch";
+
+/// Stores the lighthouse state in an embedded SQLite database.
+///
+/// Keeps a copy of the last stored state to only update the rows of changed nodes and
+/// pre-shared keys when saving, instead of rewriting the entire state.
+///
+/// The blocking database calls run on the blocking thread pool of tokio.
+pub struct SqliteStateStore {
+    database: Arc<SqliteDatabase>,
+}
+
+struct SqliteDatabase {
+    connection: Mutex<Connection>,
+    stored: Mutex<Option<LighthouseState>>,
+    /// Key to encrypt the pre-shared keys in the database.
+    key: Option<SecretKey>,
+}
+
+fn to_nanos(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+fn from_nanos(nanos: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos.max(0) as u64)
+}
+
+impl SqliteStateStore {
+    /// Opens or creates the database at the given path, migrating the schema to the latest version.
+    ///
+    /// New databases are created only readable and writable by the owner before SQLite opens
+    /// them, SQLite creates its journal files with the same permissions. Existing databases
+    /// readable by group or others are rejected.
+    pub fn open(path: &str, key: Option<SecretKey>) -> Result<Self> {
+        info!("Opening lighthouse state database {}", path);
+        check_private_file(path)?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)?;
+        let connection = Connection::open(path)?;
+        Self::with_connection(connection, key)
+    }
+
+    pub fn with_connection(mut connection: Connection, key: Option<SecretKey>) -> Result<Self> {
+        SqliteDatabase::migrate(&mut connection)?;
+        Ok(Self {
+            database: Arc::new(SqliteDatabase {
+                connection: Mutex::new(connection),
+                stored: Mutex::new(None),
+                key,
+            }),
+        })
+    }
+}
+
+impl SqliteDatabase {
+    fn load(&self) -> Result<Option<LighthouseState>> {
+        let connection = self.connection.lock().unwrap();
+        let state = self.load_state(&connection)?;
+        *self.stored.lock().unwrap() = state.clone();
+        Ok(state)
+    }
+
+    fn save(&self, state: LighthouseState) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let mut stored = self.stored.lock().unwrap();
+
+        let transaction = connection.transaction()?;
+        self.save_changes(&transaction, stored.as_ref(), &state)?;
+        transaction.commit()?;
+
+        *stored = Some(state);
+        Ok(())
+    }
+
+    /// Encrypts the pre-shared key if a key is configured.
+    fn encrypt_preshared_key(&self, preshared_key: &str) -> Result<String> {
+        match &self.key {
+            Some(key) => Ok(key.encrypt_secret(preshared_key)?),
+            None => Ok(preshared_key.to_string()),
+        }
+    }
+
+    /// Decrypts the pre-shared key, plaintext keys are accepted either way.
+    fn decrypt_preshared_key(&self, preshared_key: &str) -> Result<String> {
+        if !is_encrypted_secret(preshared_key) {
+            return Ok(preshared_key.to_string());
+        }
+        match &self.key {
+            Some(key) => Ok(key.decrypt_secret(preshared_key)?),
+            None => Err(anyhow!(
+                "State database contains encrypted pre-shared keys, but no state key is configured"
+            )),
+        }
+    }
+
+    /// Applies all pending schema migrations, tracking the schema version in `user_version`.
+    fn migrate(connection: &mut Connection) -> Result<()> {
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(anyhow!(
+                "State database schema version {} is newer than supported version {}.",
+                version,
+                MIGRATIONS.len()
+            ));
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrating state database to schema version {}", index + 1);
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", index + 1)?;
+            transaction.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn load_state(&self, connection: &Connection) -> Result<Option<LighthouseState>> {
+        let get_meta = |key: &str| -> Result<Option<i64>> {
+            Ok(connection
+                .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                    row.get(0)
+                })
+                .optional()?)
+        };
+
+        let Some(last_modified) = get_meta(META_LAST_MODIFIED)? else {
+            return Ok(None);
+        };
+        let revision = get_meta(META_REVISION)?.unwrap_or(0);
+        // databases of older versions get an epoch on their first load
+        let epoch = match get_meta(META_EPOCH)? {
+            Some(epoch) => epoch as u64,
+            None => {
+                let epoch = new_state_epoch();
+                connection.execute(
+                    "INSERT INTO meta (key, value) VALUES (?1, ?2)",
+                    params![META_EPOCH, epoch as i64],
+                )?;
+                epoch
+            }
+        };
+
+        let mut nodes = HashMap::new();
+        let mut statement = connection.prepare(
+            "SELECT hostname, last_seen, last_rotation, public_key, endpoint_host, endpoint_port,
+                    persistent_keepalive, allowed_ips, route_allowed_ips, peers_revision, peers_digest
+             FROM nodes",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let allowed_ips: String = row.get(7)?;
+            let lease = LighthouseNodeLease {
+                hostname: row.get(0)?,
+                last_seen: from_nanos(row.get(1)?),
+                last_rotation: from_nanos(row.get(2)?),
+                public_key: row.get(3)?,
+                endpoint_host: row.get(4)?,
+                endpoint_port: row.get(5)?,
+                persistent_keepalive: row.get(6)?,
+                allowed_ips: serde_json::from_str(&allowed_ips)?,
+                route_allowed_ips: row.get(8)?,
+                peers_revision: row.get::<_, i64>(9)? as u64,
+                peers_digest: row.get(10)?,
+            };
+            nodes.insert(lease.hostname.clone(), lease);
+        }
+
+        let mut preshared_keys = HashMap::new();
+        let mut preshared_keys_created = HashMap::new();
+        let mut statement = connection
+            .prepare("SELECT peer_a, peer_b, preshared_key, created FROM preshared_keys")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let pair = PeerPair::new(row.get(0)?, row.get(1)?);
+            let preshared_key: String = row.get(2)?;
+            if let Some(created) = row.get::<_, Option<i64>>(3)? {
+                preshared_keys_created.insert(pair.clone(), from_nanos(created));
+            }
+            preshared_keys.insert(pair, self.decrypt_preshared_key(&preshared_key)?);
+        }
+
+        Ok(Some(LighthouseState {
+            nodes,
+            preshared_keys,
+            preshared_keys_created,
+            last_modified: from_nanos(last_modified),
+            revision: revision as u64,
+            epoch,
+        }))
+    }
+
+    fn upsert_node(transaction: &Transaction, lease: &LighthouseNodeLease) -> Result<()> {
+        transaction.execute(
+            "INSERT OR REPLACE INTO nodes (hostname, last_seen, last_rotation, public_key,
+                endpoint_host, endpoint_port, persistent_keepalive, allowed_ips,
+                route_allowed_ips, peers_revision, peers_digest)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                lease.hostname,
+                to_nanos(lease.last_seen),
+                to_nanos(lease.last_rotation),
+                lease.public_key,
+                lease.endpoint_host,
+                lease.endpoint_port,
+                lease.persistent_keepalive,
+                serde_json::to_string(&lease.allowed_ips)?,
+                lease.route_allowed_ips,
+                lease.peers_revision as i64,
+                lease.peers_digest,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Writes the rows that differ between the stored and the new state.
+    fn save_changes(
+        &self,
+        transaction: &Transaction,
+        stored: Option<&LighthouseState>,
+        state: &LighthouseState,
+    ) -> Result<()> {
+        let set_meta = |key: &str, value: i64| -> Result<()> {
+            transaction.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?;
+            Ok(())
+        };
+        if stored.map(|stored| stored.last_modified) != Some(state.last_modified) {
+            set_meta(META_LAST_MODIFIED, to_nanos(state.last_modified))?;
+        }
+        if stored.map(|stored| stored.revision) != Some(state.revision) {
+            set_meta(META_REVISION, state.revision as i64)?;
+        }
+        if stored.map(|stored| stored.epoch) != Some(state.epoch) {
+            set_meta(META_EPOCH, state.epoch as i64)?;
+        }
+
+        for (hostname, lease) in &state.nodes {
+            if stored.and_then(|stored| stored.nodes.get(hostname)) != Some(lease) {
+                Self::upsert_node(transaction, lease)?;
+            }
+        }
+        for (pair, preshared_key) in &state.preshared_keys {
+            let created = state.preshared_keys_created.get(pair);
+            let unchanged = stored.is_some_and(|stored| {
+                stored.preshared_keys.get(pair) == Some(preshared_key)
+                    && stored.preshared_keys_created.get(pair) == created
+            });
+            if !unchanged {
+                let (peer_a, peer_b) = pair.peers();
+                transaction.execute(
+                    "INSERT OR REPLACE INTO preshared_keys (peer_a, peer_b, preshared_key, created)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        peer_a,
+                        peer_b,
+                        self.encrypt_preshared_key(preshared_key)?,
+                        created.map(|created| to_nanos(*created)),
+                    ],
+                )?;
+            }
+        }
+
+        match stored {
+            Some(stored) => {
+                for hostname in stored.nodes.keys() {
+                    if !state.nodes.contains_key(hostname) {
+                        transaction.execute("DELETE FROM nodes WHERE hostname = ?1", [hostname])?;
+                    }
+                }
+                for pair in stored.preshared_keys.keys() {
+                    if !state.preshared_keys.contains_key(pair) {
+                        let (peer_a, peer_b) = pair.peers();
+                        transaction.execute(
+                            "DELETE FROM preshared_keys WHERE peer_a = ?1 AND peer_b = ?2",
+                            [peer_a, peer_b],
+                        )?;
+                    }
+                }
+            }
+            None => {
+                // nothing is known about the stored rows, remove all rows not in the state
+                let mut statement = transaction.prepare("SELECT hostname FROM nodes")?;
+                let hostnames = statement
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                for hostname in hostnames {
+                    if !state.nodes.contains_key(&hostname) {
+                        transaction
+                            .execute("DELETE FROM nodes WHERE hostname = ?1", [&hostname])?;
+                    }
+                }
+                let mut statement =
+                    transaction.prepare("SELECT peer_a, peer_b FROM preshared_keys")?;
+                let pairs = statement
+                    .query_map([], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (peer_a, peer_b) in pairs {
+                    let pair = PeerPair::new(peer_a.clone(), peer_b.clone());
+                    if !state.preshared_keys.contains_key(&pair) {
+                        transaction.execute(
+                            "DELETE FROM preshared_keys WHERE peer_a = ?1 AND peer_b = ?2",
+                            [peer_a, peer_b],
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StateStore for SqliteStateStore {
+    async fn load(&self) -> Result<Option<LighthouseState>> {
+        let database = self.database.clone();
+        task::spawn_blocking(move || database.load()).await?
+    }
+
+    async fn save(&self, state: &LighthouseState) -> Result<()> {
+        let database = self.database.clone();
+        let state = state.clone();
+        task::spawn_blocking(move || database.save(state)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SqliteStateStore, MIGRATIONS};
+    use crate::{
+        peer_pair::PeerPair,
+        state::{LighthouseNodeLease, LighthouseState},
+        store::StateStore,
+    };
+    use rusqlite::Connection;
+    use std::{
+        collections::HashMap,
+        fs::Permissions,
+        os::unix::fs::PermissionsExt,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+    use wgpull_shared::crypto::SecretKey;
+
+    fn into_connection(store: SqliteStateStore) -> Connection {
+        let database = Arc::into_inner(store.database).unwrap();
+        database.connection.into_inner().unwrap()
+    }
+
+    fn lease(hostname: &str, now: SystemTime) -> LighthouseNodeLease {
+        LighthouseNodeLease {
+            last_seen: now,
+            last_rotation: now,
+            public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+            hostname: hostname.to_string(),
+            endpoint_host: hostname.to_string(),
+            endpoint_port: 30000,
+            persistent_keepalive: 25,
+            allowed_ips: vec!["10.0.0.1/32".to_string()],
+            route_allowed_ips: true,
+            peers_revision: 1,
+            peers_digest: "digest".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_save_and_load() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let connection = Connection::open_in_memory().unwrap();
+        let store = SqliteStateStore::with_connection(connection, None).unwrap();
+        assert!(store.load().await.unwrap().is_none());
+
+        let mut state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: now,
+            revision: 3,
+            epoch: 1,
+        };
+        state.nodes.insert("node1".to_string(), lease("node1", now));
+        state.nodes.insert("node2".to_string(), lease("node2", now));
+        state.preshared_keys.insert(
+            PeerPair::new("node2".to_string(), "node1".to_string()),
+            "psk".to_string(),
+        );
+        state
+            .preshared_keys_created
+            .insert(PeerPair::new("node2".to_string(), "node1".to_string()), now);
+        store.save(&state).await.unwrap();
+        assert_eq!(store.load().await.unwrap(), Some(state.clone()));
+
+        // update a single node and remove the other one including its pre-shared key
+        state.nodes.get_mut("node1").unwrap().last_seen = now + Duration::from_secs(30);
+        state.nodes.remove("node2");
+        state.preshared_keys.clear();
+        state.preshared_keys_created.clear();
+        state.revision = 4;
+        store.save(&state).await.unwrap();
+        assert_eq!(store.load().await.unwrap(), Some(state));
+    }
+
+    #[test]
+    fn test_sqlite_store_private_file() {
+        let path = std::env::temp_dir().join(format!("wgpull-sqlite-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        drop(SqliteStateStore::open(path, None).unwrap());
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // a database readable by others is rejected
+        std::fs::set_permissions(path, Permissions::from_mode(0o644)).unwrap();
+        assert!(SqliteStateStore::open(path, None).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_store_schema_version() {
+        let connection = Connection::open_in_memory().unwrap();
+        let store = SqliteStateStore::with_connection(connection, None).unwrap();
+        let connection = store.database.connection.lock().unwrap();
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        // a database with a newer schema is rejected
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(connection);
+        let connection = into_connection(store);
+        assert!(SqliteStateStore::with_connection(connection, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store_encrypted_preshared_keys() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let key = SecretKey::from_material("key").unwrap();
+        let connection = Connection::open_in_memory().unwrap();
+        let store = SqliteStateStore::with_connection(connection, Some(key)).unwrap();
+
+        let mut state = LighthouseState {
+            nodes: HashMap::new(),
+            preshared_keys: HashMap::new(),
+            preshared_keys_created: HashMap::new(),
+            last_modified: now,
+            revision: 1,
+            epoch: 1,
+        };
+        state.preshared_keys.insert(
+            PeerPair::new("node2".to_string(), "node1".to_string()),
+            "psk".to_string(),
+        );
+        store.save(&state).await.unwrap();
+        assert_eq!(store.load().await.unwrap(), Some(state));
+
+        let connection = into_connection(store);
+        let stored: String = connection
+            .query_row("SELECT preshared_key FROM preshared_keys", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(stored.starts_with("enc:v1:"));
+
+        // encrypted pre-shared keys can't be loaded without the key
+        let store = SqliteStateStore::with_connection(connection, None).unwrap();
+        assert!(store.load().await.is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::{collections::VecDeque, time::SystemTime};
+
+use serde::Serialize;
+use wgpull_shared::time::unix_timestamp;
+
+/// Number of rate samples kept in the history of each link.
+pub const TRAFFIC_HISTORY_LENGTH: usize = 120;
+
+/// Transfer rates of a link between two metric pushes of a node.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrafficSample {
+    /// Time of the metric push the rates were computed at (unix timestamp).
+    pub timestamp: u64,
+
+    /// Bytes per second received from the peer.
+    pub rx_bytes_per_second: f64,
+
+    /// Bytes per second sent to the peer.
+    pub tx_bytes_per_second: f64,
+}
+
+/// Traffic of a node with one of its peers, computed from the cumulative transfer counters
+/// pushed by the node.
+pub struct LinkTraffic {
+    last_rx: i64,
+    last_tx: i64,
+    last_time: SystemTime,
+    history: VecDeque<TrafficSample>,
+}
+
+/// Returns the bytes transferred between two counter values.
+///
+/// Wireguard counters reset whenever the interface is recreated, a counter lower than
+/// before restarted from zero in the meantime.
+fn counter_delta(previous: i64, current: i64) -> i64 {
+    if current >= previous {
+        current - previous
+    } else {
+        current
+    }
+}
+
+impl LinkTraffic {
+    pub fn new(rx: i64, tx: i64, time: SystemTime) -> Self {
+        Self {
+            last_rx: rx,
+            last_tx: tx,
+            last_time: time,
+            history: VecDeque::with_capacity(TRAFFIC_HISTORY_LENGTH),
+        }
+    }
+
+    /// Records the transfer counters of the latest metric push, adding the rates since
+    /// the previous push to the history.
+    pub fn record(&mut self, rx: i64, tx: i64, time: SystemTime) {
+        // ignore pushes that don't advance in time, e.g. after the clock was set back
+        if let Ok(elapsed) = time.duration_since(self.last_time) {
+            let seconds = elapsed.as_secs_f64();
+            if seconds > 0.0 {
+                if self.history.len() == TRAFFIC_HISTORY_LENGTH {
+                    self.history.pop_front();
+                }
+                self.history.push_back(TrafficSample {
+                    timestamp: unix_timestamp(time),
+                    rx_bytes_per_second: counter_delta(self.last_rx, rx) as f64 / seconds,
+                    tx_bytes_per_second: counter_delta(self.last_tx, tx) as f64 / seconds,
+                });
+            }
+        }
+        self.last_rx = rx;
+        self.last_tx = tx;
+        self.last_time = time;
+    }
+
+    /// Returns the most recent rates, none until the node pushed metrics twice.
+    pub fn latest(&self) -> Option<&TrafficSample> {
+        self.history.back()
+    }
+
+    /// Returns the rate history, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &TrafficSample> {
+        self.history.iter()
+    }
+}
+
+/// Traffic history of a link as exposed by the admin api.
+#[derive(Clone, Debug, Serialize)]
+pub struct LinkTrafficStatus {
+    /// Hostname of the node that pushed the metrics.
+    pub hostname: String,
+
+    /// Hostname of the peer as seen by the node.
+    pub peer_hostname: String,
+
+    /// Rates between consecutive metric pushes, oldest first.
+    pub history: Vec<TrafficSample>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{LinkTraffic, TrafficSample, TRAFFIC_HISTORY_LENGTH};
+
+    #[test]
+    fn test_link_traffic_rates() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        let mut traffic = LinkTraffic::new(1000, 5000, start);
+        assert_eq!(traffic.latest(), None);
+
+        traffic.record(3000, 6000, start + Duration::from_secs(10));
+        assert_eq!(
+            traffic.latest(),
+            Some(&TrafficSample {
+                timestamp: 1_720_000_010,
+                rx_bytes_per_second: 200.0,
+                tx_bytes_per_second: 100.0,
+            })
+        );
+
+        // the interface was recreated, counters restarted from zero
+        traffic.record(500, 7000, start + Duration::from_secs(20));
+        let latest = traffic.latest().unwrap();
+        assert_eq!(latest.rx_bytes_per_second, 50.0);
+        assert_eq!(latest.tx_bytes_per_second, 100.0);
+
+        // pushes without elapsed time don't produce a sample
+        traffic.record(600, 7000, start + Duration::from_secs(20));
+        assert_eq!(traffic.history().count(), 2);
+    }
+
+    #[test]
+    fn test_link_traffic_history_is_bounded() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut traffic = LinkTraffic::new(0, 0, start);
+        for i in 1..=(TRAFFIC_HISTORY_LENGTH as u64 + 5) {
+            traffic.record(i as i64, 0, start + Duration::from_secs(i));
+        }
+        assert_eq!(traffic.history().count(), TRAFFIC_HISTORY_LENGTH);
+        assert_eq!(traffic.history().next().unwrap().timestamp, 6);
+    }
+}
@@ -1,15 +1,23 @@
+use std::time::SystemTime;
+
 use anyhow::Result;
-use log::error;
-use reqwest::header::{HeaderMap, HeaderValue};
+use log::{error, warn};
+use reqwest::header::{HeaderMap, HeaderValue, ETAG, IF_NONE_MATCH};
+use reqwest::{Response, StatusCode};
 use serde::Serialize;
 use thiserror::Error;
 use wgpull_shared::client::HttpClient;
 use wgpull_shared::validation::Validated;
 
 use wgpull_shared::challenge::ChallengeResponse;
-use wgpull_shared::headers::{HEADER_LIGHTHOUSE_KEY, HEADER_NODE_CHALLENGE, HEADER_NODE_RESPONSE};
-use wgpull_shared::request::{NodeMetricsPushRequest, NodePullRequest};
-use wgpull_shared::response::NodePullResponse;
+use wgpull_shared::headers::{
+    format_revision_etag, HEADER_LIGHTHOUSE_KEY, HEADER_NODE_CHALLENGE, HEADER_NODE_RESPONSE,
+    HEADER_SIGNATURE, HEADER_SIGNED_PAYLOAD,
+};
+use wgpull_shared::request::{NodeMetricsPushRequest, NodePullRequest, NodeWatchRequest};
+use wgpull_shared::response::{NodePullNotModified, NodePullResponse, NodeWatchResponse};
+use wgpull_shared::signing::{PublicKey, SignedDocument};
+use wgpull_shared::time::unix_timestamp;
 
 use super::config::NodeConfig;
 
@@ -19,33 +27,128 @@ pub enum AgentError {
     ClientError(String),
     #[error("Error serializing/unserializing a request or response")]
     ClientSerializationError(String),
+    #[error("Lighthouse rejected the keys")]
+    KeyRejected,
     #[error("Challenge Response Error")]
     ChallengeResponseIncorrect,
     #[error("Challenge Response Missing in response")]
     NoChallengeResponse,
     #[error("Error validating request to send")]
     RequestValidationError,
+    #[error("Response signature missing")]
+    SignatureMissing,
+    #[error("Invalid response signature: {0}")]
+    InvalidSignature(String),
+    #[error("Signed response is for node {0}")]
+    WrongNode(String),
+    #[error("Signed response expired")]
+    ResponseExpired,
+    #[error("Signed response is for configuration {0}")]
+    WrongRevision(String),
 }
 
-pub struct NodeAgent<'a, T: HttpClient + ?Sized> {
-    lighthouse_url: String,
+/// Verifies the signature of a pull response with the lighthouse public key, returns the
+/// response if it is signed for the node and not expired.
+pub fn verify_pull_response(
+    body: &str,
+    signature: Option<&str>,
+    key: &PublicKey,
+    hostname: &str,
+    now: u64,
+) -> Result<NodePullResponse, AgentError> {
+    let signed = SignedDocument {
+        payload: body.to_string(),
+        signature: signature.ok_or(AgentError::SignatureMissing)?.to_string(),
+    };
+    let response: NodePullResponse = signed
+        .verify(key)
+        .map_err(|err| AgentError::InvalidSignature(err.to_string()))?;
+    match response.hostname.as_deref() {
+        Some(signed_hostname) if signed_hostname == hostname => {}
+        other => {
+            return Err(AgentError::WrongNode(
+                other.unwrap_or("unknown").to_string(),
+            ))
+        }
+    }
+    if response
+        .expires_at
+        .is_none_or(|expires_at| expires_at <= now)
+    {
+        return Err(AgentError::ResponseExpired);
+    }
+    Ok(response)
+}
+
+/// Verifies the signed confirmation of a not modified pull response with the lighthouse public
+/// key, succeeds if it confirms the ETag the node knows for the node and is not expired.
+pub fn verify_not_modified(
+    payload: Option<&str>,
+    signature: Option<&str>,
+    key: &PublicKey,
+    hostname: &str,
+    known_etag: Option<&str>,
+    now: u64,
+) -> Result<(), AgentError> {
+    let signed = SignedDocument {
+        payload: payload.ok_or(AgentError::SignatureMissing)?.to_string(),
+        signature: signature.ok_or(AgentError::SignatureMissing)?.to_string(),
+    };
+    let not_modified: NodePullNotModified = signed
+        .verify(key)
+        .map_err(|err| AgentError::InvalidSignature(err.to_string()))?;
+    if not_modified.hostname != hostname {
+        return Err(AgentError::WrongNode(not_modified.hostname));
+    }
+    if Some(not_modified.etag.as_str()) != known_etag {
+        return Err(AgentError::WrongRevision(not_modified.etag));
+    }
+    if not_modified.expires_at <= now {
+        return Err(AgentError::ResponseExpired);
+    }
+    Ok(())
+}
+
+/// Lighthouse key and the node key the lighthouse answers the challenges with.
+#[derive(Debug, Clone, PartialEq)]
+struct AgentKeys {
     lighthouse_key: String,
     node_key: String,
+}
+
+/// Returns the primary keys of the configuration, followed by the secondary keys if configured.
+fn agent_keys(config: &NodeConfig) -> Vec<AgentKeys> {
+    let mut keys = vec![AgentKeys {
+        lighthouse_key: config.lighthouse_key.clone(),
+        node_key: config.node_key.clone(),
+    }];
+    if let Some(lighthouse_key) = &config.secondary_lighthouse_key {
+        keys.push(AgentKeys {
+            lighthouse_key: lighthouse_key.clone(),
+            node_key: config
+                .secondary_node_key
+                .clone()
+                .unwrap_or_else(|| config.node_key.clone()),
+        });
+    }
+    keys
+}
+
+pub struct NodeAgent<'a, T: HttpClient + ?Sized> {
+    lighthouse_urls: Vec<String>,
+    /// Primary keys, followed by the secondary keys if configured.
+    keys: Vec<AgentKeys>,
+    /// Public key to verify signed pull responses, unsigned responses are accepted if not set.
+    public_key: Option<PublicKey>,
     client: &'a T,
 }
 
 impl<'a, T: HttpClient + ?Sized> NodeAgent<'a, T> {
     pub fn from_node_config(config: &NodeConfig, client: &'a T) -> Result<Self> {
         Ok(Self {
-            lighthouse_url: format!(
-                "{}://{}:{}/{}",
-                config.get_lighthouse_scheme(),
-                config.lighthouse_host,
-                config.lighthouse_port,
-                config.lighthouse_path_prefix
-            ),
-            lighthouse_key: config.lighthouse_key.clone(),
-            node_key: config.node_key.clone(),
+            lighthouse_urls: config.get_lighthouse_urls(),
+            keys: agent_keys(config),
+            public_key: config.get_lighthouse_public_key()?,
             client,
         })
     }
@@ -55,6 +158,20 @@ impl<'a, T: HttpClient + ?Sized> NodeAgent<'a, T> {
         path: &'static str,
         request: &Body,
     ) -> Result<String, AgentError> {
+        let resp = self.send(path, request, HeaderMap::new()).await?;
+        resp.text()
+            .await
+            .map_err(|err| AgentError::ClientError(err.to_string()))
+    }
+
+    /// Sends the request to the lighthouses in the configured order, failing over to the
+    /// next lighthouse on errors. The error of the last lighthouse is returned if all fail.
+    async fn send<Body: Serialize + Validated>(
+        &self,
+        path: &'static str,
+        request: &Body,
+        headers: HeaderMap,
+    ) -> Result<Response, AgentError> {
         request.validate().map_err(|err| {
             error!("Error validating the request to send: {}", err.to_string());
             AgentError::RequestValidationError
@@ -62,12 +179,63 @@ impl<'a, T: HttpClient + ?Sized> NodeAgent<'a, T> {
         let body = serde_json::to_string(request)
             .map_err(|err| AgentError::ClientSerializationError(err.to_string()))?;
 
-        let challenge = ChallengeResponse::new(self.node_key.clone());
+        let mut result = Err(AgentError::ClientError(
+            "No lighthouse configured".to_string(),
+        ));
+        for lighthouse_url in &self.lighthouse_urls {
+            let url = format!("{}{}", lighthouse_url, path);
+            result = self.send_to(&url, body.clone(), headers.clone()).await;
+            match &result {
+                Ok(_) => break,
+                Err(err) => warn!("Request to lighthouse {} failed: {}", url, err),
+            }
+        }
+        result
+    }
+
+    /// Sends the request to a single lighthouse with the primary keys, falling back to the
+    /// secondary keys if the lighthouse rejects the primary keys.
+    async fn send_to(
+        &self,
+        url: &str,
+        body: String,
+        headers: HeaderMap,
+    ) -> Result<Response, AgentError> {
+        let mut result = Err(AgentError::ClientError("No keys configured".to_string()));
+        for (index, keys) in self.keys.iter().enumerate() {
+            if index > 0 {
+                warn!(
+                    "Lighthouse {} rejected the primary keys, trying the secondary keys",
+                    url
+                );
+            }
+            result = self
+                .send_with_keys(url, body.clone(), headers.clone(), keys)
+                .await;
+            if !matches!(
+                result,
+                Err(AgentError::KeyRejected | AgentError::ChallengeResponseIncorrect)
+            ) {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Sends the request to a single lighthouse and verifies the challenge response, the
+    /// response is returned if the status is either successful or not modified.
+    async fn send_with_keys(
+        &self,
+        url: &str,
+        body: String,
+        mut headers: HeaderMap,
+        keys: &AgentKeys,
+    ) -> Result<Response, AgentError> {
+        let challenge = ChallengeResponse::new(keys.node_key.clone());
 
-        let mut headers = HeaderMap::new();
         headers.insert(
             HEADER_LIGHTHOUSE_KEY,
-            HeaderValue::from_str(&self.lighthouse_key).unwrap(),
+            HeaderValue::from_str(&keys.lighthouse_key).unwrap(),
         );
         headers.insert(
             HEADER_NODE_CHALLENGE,
@@ -75,21 +243,22 @@ impl<'a, T: HttpClient + ?Sized> NodeAgent<'a, T> {
         );
         headers.insert("Content-Type", HeaderValue::from_static("application/json"));
 
-        let url = format!("{}{}", self.lighthouse_url, path);
-        let resp = self.client.post(&url, headers, body).await;
+        let resp = self.client.post(url, headers, body).await;
 
         match resp {
             Ok(resp) => {
-                if resp.status().is_success() {
+                if resp.status().is_success() || resp.status() == StatusCode::NOT_MODIFIED {
                     if let Some(challenge_response) = resp.headers().get(HEADER_NODE_RESPONSE) {
                         if challenge.verify(challenge_response.to_str().unwrap()) {
-                            Ok(resp.text().await.unwrap())
+                            Ok(resp)
                         } else {
                             Err(AgentError::ChallengeResponseIncorrect)
                         }
                     } else {
                         Err(AgentError::NoChallengeResponse)
                     }
+                } else if resp.status() == StatusCode::UNAUTHORIZED {
+                    Err(AgentError::KeyRejected)
                 } else {
                     Err(AgentError::ClientError(format!(
                         "Response Status: {}",
@@ -101,15 +270,74 @@ impl<'a, T: HttpClient + ?Sized> NodeAgent<'a, T> {
         }
     }
 
-    pub async fn pull_wireguard(&self, request: NodePullRequest) -> Result<NodePullResponse> {
-        let response = self.post("api/v1/pull", &request).await?;
+    /// Pulls the peer configuration from the lighthouse with the ETag of the response, returns
+    /// none if the lighthouse responds that the configuration did not change since the ETag
+    /// the node knows.
+    ///
+    /// If the lighthouse public key is configured, the response must be signed by the
+    /// lighthouse for this node and not be expired, this includes not modified responses. The
+    /// ETag of a signed response is taken from the signed epoch and revision.
+    pub async fn pull_wireguard(
+        &self,
+        request: NodePullRequest,
+        known_etag: Option<&str>,
+    ) -> Result<Option<(NodePullResponse, Option<String>)>> {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = known_etag.and_then(|etag| HeaderValue::from_str(etag).ok()) {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
 
-        let response: NodePullResponse = serde_json::from_str(&response)
-            .map_err(|err| AgentError::ClientSerializationError(err.to_string()))?;
+        let response = self.send("api/v1/pull", &request, headers).await?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG.as_str());
+        let signature = header(HEADER_SIGNATURE);
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(key) = &self.public_key {
+                verify_not_modified(
+                    header(HEADER_SIGNED_PAYLOAD).as_deref(),
+                    signature.as_deref(),
+                    key,
+                    &request.hostname,
+                    known_etag,
+                    unix_timestamp(SystemTime::now()),
+                )?;
+            }
+            return Ok(None);
+        }
+        let body = response
+            .text()
+            .await
+            .map_err(|err| AgentError::ClientError(err.to_string()))?;
+
+        let response: NodePullResponse = match &self.public_key {
+            Some(key) => verify_pull_response(
+                &body,
+                signature.as_deref(),
+                key,
+                &request.hostname,
+                unix_timestamp(SystemTime::now()),
+            )?,
+            None => serde_json::from_str(&body)
+                .map_err(|err| AgentError::ClientSerializationError(err.to_string()))?,
+        };
 
         response.validate()?;
 
-        Ok(response)
+        // the ETag header is not covered by the signature
+        let etag = match (&self.public_key, response.epoch) {
+            (Some(_), Some(epoch)) => Some(format_revision_etag(epoch, response.revision)),
+            (Some(_), None) => None,
+            (None, _) => etag,
+        };
+
+        Ok(Some((response, etag)))
     }
 
     pub async fn push_metrics(&self, request: NodeMetricsPushRequest) -> Result<()> {
@@ -117,4 +345,183 @@ impl<'a, T: HttpClient + ?Sized> NodeAgent<'a, T> {
 
         Ok(())
     }
+
+    /// Waits for changes of the peer configuration, returns true if the node should pull.
+    pub async fn watch(&self, request: NodeWatchRequest) -> Result<bool> {
+        let response = self.post("api/v1/watch", &request).await?;
+
+        let response: NodeWatchResponse = serde_json::from_str(&response)
+            .map_err(|err| AgentError::ClientSerializationError(err.to_string()))?;
+
+        Ok(response.changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpull_shared::{
+        config::parse_config,
+        response::{NodePullNotModified, NodePullResponse},
+        signing::SigningKey,
+    };
+
+    use super::{agent_keys, verify_not_modified, verify_pull_response, AgentError, AgentKeys};
+    use crate::config::NodeConfigFile;
+
+    fn response(hostname: &str, expires_at: u64) -> NodePullResponse {
+        NodePullResponse {
+            regenerate_keys: false,
+            peers: vec![],
+            revision: 3,
+            signed_endpoint: None,
+            hostname: Some(hostname.to_string()),
+            expires_at: Some(expires_at),
+            epoch: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_verify_pull_response() {
+        let key = SigningKey::from_material("signing").unwrap();
+        let public_key = key.public_key();
+        let signed = key.sign(&response("node1", 2_000)).unwrap();
+
+        let verified = verify_pull_response(
+            &signed.payload,
+            Some(&signed.signature),
+            &public_key,
+            "node1",
+            1_000,
+        )
+        .unwrap();
+        assert_eq!(verified.revision, 3);
+
+        assert!(matches!(
+            verify_pull_response(&signed.payload, None, &public_key, "node1", 1_000),
+            Err(AgentError::SignatureMissing)
+        ));
+        let modified = signed.payload.replace("\"revision\":3", "\"revision\":4");
+        assert!(matches!(
+            verify_pull_response(
+                &modified,
+                Some(&signed.signature),
+                &public_key,
+                "node1",
+                1_000
+            ),
+            Err(AgentError::InvalidSignature(_))
+        ));
+        assert!(matches!(
+            verify_pull_response(
+                &signed.payload,
+                Some(&signed.signature),
+                &public_key,
+                "node2",
+                1_000
+            ),
+            Err(AgentError::WrongNode(_))
+        ));
+        assert!(matches!(
+            verify_pull_response(
+                &signed.payload,
+                Some(&signed.signature),
+                &public_key,
+                "node1",
+                2_000
+            ),
+            Err(AgentError::ResponseExpired)
+        ));
+    }
+
+    #[test]
+    fn test_verify_not_modified() {
+        let key = SigningKey::from_material("signing").unwrap();
+        let public_key = key.public_key();
+        let signed = key
+            .sign(&NodePullNotModified {
+                hostname: "node1".to_string(),
+                etag: "\"1-3\"".to_string(),
+                expires_at: 2_000,
+            })
+            .unwrap();
+        let verify = |hostname: &str, etag: Option<&str>, now: u64| {
+            verify_not_modified(
+                Some(&signed.payload),
+                Some(&signed.signature),
+                &public_key,
+                hostname,
+                etag,
+                now,
+            )
+        };
+
+        assert!(verify("node1", Some("\"1-3\""), 1_000).is_ok());
+        assert!(matches!(
+            verify_not_modified(None, None, &public_key, "node1", Some("\"1-3\""), 1_000),
+            Err(AgentError::SignatureMissing)
+        ));
+        let modified = signed.payload.replace("2000", "3000");
+        assert!(matches!(
+            verify_not_modified(
+                Some(&modified),
+                Some(&signed.signature),
+                &public_key,
+                "node1",
+                Some("\"1-3\""),
+                1_000
+            ),
+            Err(AgentError::InvalidSignature(_))
+        ));
+        assert!(matches!(
+            verify("node2", Some("\"1-3\""), 1_000),
+            Err(AgentError::WrongNode(_))
+        ));
+        assert!(matches!(
+            verify("node1", Some("\"1-2\""), 1_000),
+            Err(AgentError::WrongRevision(_))
+        ));
+        assert!(matches!(
+            verify("node1", Some("\"1-3\""), 2_000),
+            Err(AgentError::ResponseExpired)
+        ));
+    }
+
+    #[test]
+    fn test_agent_keys() {
+        let no_file = |_: &str| Err("no files".to_string());
+        let contents = include_str!("../../../node.toml");
+        let config = parse_config::<NodeConfigFile>(contents, std::iter::empty(), no_file).unwrap();
+        let primary = AgentKeys {
+            lighthouse_key: "change_me".to_string(),
+            node_key: "change_me".to_string(),
+        };
+        assert_eq!(agent_keys(&config.node), vec![primary.clone()]);
+
+        // the secondary keys are tried after the primary keys
+        let contents = contents.replace(
+            "\nnode_key = \"change_me\"\n",
+            "\nnode_key = \"change_me\"\nsecondary_lighthouse_key = \"previous\"\n",
+        );
+        let config =
+            parse_config::<NodeConfigFile>(&contents, std::iter::empty(), no_file).unwrap();
+        let secondary = AgentKeys {
+            lighthouse_key: "previous".to_string(),
+            node_key: "change_me".to_string(),
+        };
+        assert_eq!(
+            agent_keys(&config.node),
+            vec![primary.clone(), secondary.clone()]
+        );
+
+        let contents = contents.replace(
+            "\nsecondary_lighthouse_key = \"previous\"\n",
+            "\nsecondary_lighthouse_key = \"previous\"\nsecondary_node_key = \"node-previous\"\n",
+        );
+        let config =
+            parse_config::<NodeConfigFile>(&contents, std::iter::empty(), no_file).unwrap();
+        assert_eq!(
+            agent_keys(&config.node)[1].node_key,
+            "node-previous".to_string()
+        );
+    }
 }
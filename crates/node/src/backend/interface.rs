@@ -15,6 +15,16 @@ pub enum BackendType {
     Uci,
 }
 
+impl BackendType {
+    /// Returns the name of the backend as used in the configuration file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackendType::Systemd => "systemd",
+            BackendType::Uci => "uci",
+        }
+    }
+}
+
 #[async_trait]
 pub trait Backend {
     /// Check for compatibility of the backend with the current system.
@@ -24,6 +34,9 @@ pub trait Backend {
     /// This returns true if the state of the system has changed.
     async fn update_local_state(&self, state: &NodeState) -> Result<bool>;
 
+    /// Renders the configuration the backend would apply for the given node state.
+    fn render_config(&self, state: &NodeState) -> String;
+
     /// Gets the hostname of the local system.
     async fn get_hostname(&self) -> Result<String>;
 }
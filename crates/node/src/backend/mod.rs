@@ -2,6 +2,6 @@ mod interface;
 mod systemd;
 mod uci;
 
-pub use interface::{get_backend_impl, BackendType};
+pub use interface::{get_backend_impl, Backend, BackendType};
 pub use systemd::SystemdConfig;
 pub use uci::UciConfig;
@@ -158,6 +158,20 @@ impl Backend for SystemdBackend {
         true
     }
 
+    fn render_config(&self, state: &NodeState) -> String {
+        let netdev_path =
+            Path::new(&self.config.path).join(format!("{}.netdev", self.config.interface));
+        let network_path =
+            Path::new(&self.config.path).join(format!("{}.network", self.config.interface));
+        format!(
+            "# {}\n{}\n# {}\n{}",
+            netdev_path.display(),
+            self.get_interface_netdev_contents(state),
+            network_path.display(),
+            self.get_interface_network_contents(state)
+        )
+    }
+
     async fn update_local_state(&self, state: &NodeState) -> Result<bool> {
         info!(
             "Node systemd backend update of local state, updating with {} peers",
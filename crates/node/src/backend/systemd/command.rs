@@ -6,7 +6,7 @@ pub struct SystemdCommand<'a, T: CommandExecutor + ?Sized> {
 }
 
 impl<'a, T: CommandExecutor + ?Sized> SystemdCommand<'a, T> {
-    pub fn new(executor: &'a T) -> SystemdCommand<T> {
+    pub fn new(executor: &'a T) -> SystemdCommand<'a, T> {
         Self { executor }
     }
 
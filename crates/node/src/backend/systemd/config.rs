@@ -1,10 +1,12 @@
 use serde::Deserialize;
 
+use crate::config::InterfaceName;
+
 /// Systemd backend configuration of a node.
 #[derive(Debug, Clone, Deserialize)]
 pub struct SystemdConfig {
     /// The name of the WireGuard interface (wg0).
-    pub interface: String,
+    pub interface: InterfaceName,
     /// The path to systemd-networkd interface files (/etc/systemd/network).
     pub path: String,
     /// Reload networkctl after changes to interface files.
@@ -23,16 +23,9 @@ impl UciBackend {
             executor,
         }
     }
-}
-
-#[async_trait]
-impl Backend for UciBackend {
-    async fn is_compatible(&self) -> bool {
-        let command = UciCommand::new(self.executor.as_ref());
-        command.test_uci().await
-    }
 
-    async fn update_local_state(&self, state: &NodeState) -> Result<bool> {
+    /// Returns the uci wireguard configuration of the interface matching the state.
+    fn get_uci_config(&self, state: &NodeState) -> UciWireguardConfig {
         let uci_peers = state
             .peers
             .iter()
@@ -48,12 +41,24 @@ impl Backend for UciBackend {
             })
             .collect();
 
-        let uci_config = UciWireguardConfig {
+        UciWireguardConfig {
             private_key: state.private_key.clone(),
             listen_port: state.listen_port,
             addresses: state.address.clone(),
             peers: uci_peers,
-        };
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for UciBackend {
+    async fn is_compatible(&self) -> bool {
+        let command = UciCommand::new(self.executor.as_ref());
+        command.test_uci().await
+    }
+
+    async fn update_local_state(&self, state: &NodeState) -> Result<bool> {
+        let uci_config = self.get_uci_config(state);
 
         let command = UciCommand::new(self.executor.as_ref());
 
@@ -89,6 +94,10 @@ impl Backend for UciBackend {
         }
     }
 
+    fn render_config(&self, state: &NodeState) -> String {
+        self.get_uci_config(state).to_batch(&self.config.interface)
+    }
+
     async fn get_hostname(&self) -> Result<String> {
         let command = UciCommand::new(self.executor.as_ref());
         let hostname = command.get_hostname().await?;
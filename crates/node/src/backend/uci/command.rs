@@ -37,6 +37,60 @@ pub struct UciWireguardConfig {
     pub peers: Vec<UciWireguardPeer>,
 }
 
+impl UciWireguardConfig {
+    /// Renders the configuration of the interface as `uci batch` commands.
+    pub fn to_batch(&self, interface: &str) -> String {
+        let section = format!("wireguard_{}", interface);
+        let mut batch = String::new();
+        batch.push_str(&format!("set network.{}=interface\n", interface));
+        batch.push_str(&format!("set network.{}.proto='wireguard'\n", interface));
+        batch.push_str(&format!(
+            "set network.{}.private_key='{}'\n",
+            interface, self.private_key
+        ));
+        batch.push_str(&format!(
+            "set network.{}.listen_port='{}'\n",
+            interface, self.listen_port
+        ));
+        batch.push_str(&format!(
+            "set network.{}.addresses='{}'\n",
+            interface, self.addresses
+        ));
+
+        for peer in &self.peers {
+            let key = format!("network.@{}[-1]", section);
+            batch.push_str(&format!("add network {}\n", section));
+            batch.push_str(&format!("set {}.public_key='{}'\n", key, peer.public_key));
+            batch.push_str(&format!(
+                "set {}.preshared_key='{}'\n",
+                key, peer.preshared_key
+            ));
+            batch.push_str(&format!("set {}.description='{}'\n", key, peer.description));
+            batch.push_str(&format!(
+                "set {}.endpoint_host='{}'\n",
+                key, peer.endpoint_host
+            ));
+            batch.push_str(&format!(
+                "set {}.endpoint_port='{}'\n",
+                key, peer.endpoint_port
+            ));
+            batch.push_str(&format!(
+                "set {}.persistent_keepalive='{}'\n",
+                key, peer.persistent_keepalive
+            ));
+            batch.push_str(&format!(
+                "set {}.route_allowed_ips='{}'\n",
+                key,
+                if peer.route_allowed_ips { "1" } else { "0" }
+            ));
+            for allowed_ip in &peer.allowed_ips {
+                batch.push_str(&format!("add_list {}.allowed_ips='{}'\n", key, allowed_ip));
+            }
+        }
+        batch
+    }
+}
+
 pub struct UciCommand<'a, T: CommandExecutor + ?Sized> {
     executor: &'a T,
 }
@@ -1,8 +1,10 @@
 use serde::Deserialize;
 
+use crate::config::InterfaceName;
+
 /// UciConfig backend configuration of a node.
 #[derive(Debug, Clone, Deserialize)]
 pub struct UciConfig {
     /// The name of the WireGuard interface (wg0).
-    pub interface: String,
+    pub interface: InterfaceName,
 }
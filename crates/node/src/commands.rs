@@ -0,0 +1,216 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use wgpull_shared::{
+    client::HttpClient,
+    command::CommandExecutor,
+    file::{check_private_file, FileAccessor},
+    state::{load_state_key, SecretFields},
+    wg::{WireguardCommand, WireguardInfo},
+};
+
+use super::{
+    backend::get_backend_impl, config::NodeConfigFile, context::NodeContext, state::NodeState,
+};
+
+/// Loads the local state without generating a new one, fails if the node has no state yet.
+async fn load_state(config: &NodeConfigFile, accessor: &dyn FileAccessor) -> Result<NodeState> {
+    let key = load_state_key(config.node.state_key_file.as_deref(), accessor).await?;
+    NodeState::from_file(&config.node.state_file, accessor, key.as_ref())
+        .await?
+        .ok_or_else(|| anyhow!("No node state found in {}", config.node.state_file))
+}
+
+/// Formats the local state and the live wireguard status for humans, peers are resolved
+/// to their hostname by their public key.
+pub fn format_status(state: &NodeState, info: Option<&WireguardInfo>, now: SystemTime) -> String {
+    let mut status = String::new();
+    status.push_str(&format!("hostname:   {}\n", state.hostname));
+    status.push_str(&format!("public key: {}\n", state.public_key));
+    status.push_str(&format!("address:    {}\n", state.address));
+    status.push_str(&format!(
+        "endpoint:   {}:{}\n",
+        state.endpoint, state.listen_port
+    ));
+    match state.revision {
+        Some(revision) => status.push_str(&format!("revision:   {}\n", revision)),
+        None => status.push_str("revision:   never pulled\n"),
+    }
+    status.push_str(&format!("peers:      {}\n", state.peers.len()));
+
+    let Some(info) = info else {
+        status.push_str("\nwireguard interface is not up\n");
+        return status;
+    };
+    status.push_str(&format!(
+        "\ninterface {} listening on {}\n",
+        info.interface, info.listening_port
+    ));
+    for peer in &info.peers {
+        let handshake = if peer.latest_handshake == 0 {
+            "never".to_string()
+        } else {
+            let handshake = UNIX_EPOCH + Duration::from_secs(peer.latest_handshake);
+            let age = now
+                .duration_since(handshake)
+                .map(|age| age.as_secs())
+                .unwrap_or(0);
+            format!("{}s ago", age)
+        };
+        status.push_str(&format!(
+            "\npeer {} ({})\n  endpoint:         {}\n  latest handshake: {}\n  transfer:         {} B received, {} B sent\n",
+            state.get_hostname_by_public_key(&peer.public_key),
+            peer.public_key,
+            peer.endpoint,
+            handshake,
+            peer.transfer_rx,
+            peer.transfer_tx
+        ));
+    }
+    status
+}
+
+/// Returns the local state and live wireguard status.
+pub async fn show_status(
+    config: &NodeConfigFile,
+    executor: Arc<dyn CommandExecutor>,
+    accessor: Arc<dyn FileAccessor>,
+) -> Result<String> {
+    let state = load_state(config, accessor.as_ref()).await?;
+    let info = WireguardCommand::new(executor.as_ref()).collect().await?;
+    Ok(format_status(&state, info.as_ref(), SystemTime::now()))
+}
+
+/// Returns the configuration the backend applies for the local state, secrets are redacted
+/// unless requested.
+pub async fn show_config(
+    config: &NodeConfigFile,
+    executor: Arc<dyn CommandExecutor>,
+    accessor: Arc<dyn FileAccessor>,
+    show_secrets: bool,
+) -> Result<String> {
+    let mut state = load_state(config, accessor.as_ref()).await?;
+    if !show_secrets {
+        state.redact_secrets();
+    }
+    let backend = get_backend_impl(config.wireguard.backend.clone(), config, executor, accessor);
+    Ok(backend.render_config(&state))
+}
+
+/// Checks the configuration and the files it refers to without starting the node, returns
+/// a summary of the checked configuration.
+pub async fn check_config(
+    config: &NodeConfigFile,
+    accessor: Arc<dyn FileAccessor>,
+) -> Result<String> {
+    check_private_file(&config.node.state_file)?;
+    load_state_key(config.node.state_key_file.as_deref(), accessor.as_ref()).await?;
+
+    Ok(format!(
+        "Configuration OK, pulling from {}, state {}\n",
+        config.node.get_lighthouse_urls().join(", "),
+        config.node.state_file
+    ))
+}
+
+/// Removes the local state and enrolls the node again with new keys.
+pub async fn reset(
+    config: &NodeConfigFile,
+    executor: Arc<dyn CommandExecutor>,
+    accessor: Arc<dyn FileAccessor>,
+    http_client: Arc<dyn HttpClient>,
+) -> Result<()> {
+    if accessor.read(&config.node.state_file).await.is_ok() {
+        accessor.remove(&config.node.state_file).await?;
+        info!("Removed node state {}", config.node.state_file);
+    }
+
+    let mut context = NodeContext::init(config, executor, accessor, http_client).await?;
+    context.pull_wireguard_once().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use wgpull_shared::wg::{PeerInfo, WireguardInfo};
+
+    use super::format_status;
+    use crate::state::{NodePeer, NodeState};
+
+    #[test]
+    fn test_format_status() {
+        let state = NodeState {
+            hostname: "node1".to_string(),
+            private_key: "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=".to_string(),
+            public_key: "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=".to_string(),
+            address: "10.140.0.10/24".to_string(),
+            endpoint: "node1.example.com".to_string(),
+            listen_port: 52720,
+            persistent_keepalive: 15,
+            allowed_ips: vec!["10.140.0.10/32".to_string()],
+            peers: vec![NodePeer {
+                hostname: "node2".to_string(),
+                public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+                preshared_key: "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=".to_string(),
+                endpoint_host: "node2.example.com".to_string(),
+                endpoint_port: 52720,
+                allowed_ips: vec!["10.140.0.11/32".to_string()],
+                persistent_keepalive: 15,
+                route_allowed_ips: true,
+                signed_endpoint: None,
+            }],
+            route_allowed_ips: true,
+            revision: Some(3),
+            etag: None,
+            last_pull: None,
+            signed_endpoint: None,
+        };
+        let info = WireguardInfo {
+            interface: "wg0".to_string(),
+            public_key: state.public_key.clone(),
+            private_key: state.private_key.clone(),
+            listening_port: 52720,
+            peers: vec![
+                PeerInfo {
+                    interface: "wg0".to_string(),
+                    public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+                    private_key: "(none)".to_string(),
+                    endpoint: "203.0.113.2:52720".to_string(),
+                    allowed_ips: "10.140.0.11/32".to_string(),
+                    latest_handshake: 1_720_000_000 - 42,
+                    transfer_rx: 1000,
+                    transfer_tx: 2000,
+                    persistent_keepalive: 15,
+                },
+                PeerInfo {
+                    interface: "wg0".to_string(),
+                    public_key: "other".to_string(),
+                    private_key: "(none)".to_string(),
+                    endpoint: "(none)".to_string(),
+                    allowed_ips: "10.140.0.12/32".to_string(),
+                    latest_handshake: 0,
+                    transfer_rx: 0,
+                    transfer_tx: 0,
+                    persistent_keepalive: 0,
+                },
+            ],
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+
+        let status = format_status(&state, Some(&info), now);
+        assert!(status.contains("revision:   3\n"));
+        assert!(status.contains("peer node2 (sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=)\n"));
+        assert!(status.contains("latest handshake: 42s ago\n"));
+        assert!(status.contains("peer unknown (other)\n"));
+        assert!(status.contains("latest handshake: never\n"));
+        assert!(!status.contains(&state.private_key));
+
+        let status = format_status(&state, None, now);
+        assert!(status.ends_with("wireguard interface is not up\n"));
+    }
+}
@@ -1,27 +1,162 @@
+use std::{fmt, net::SocketAddr, ops::Deref, time::Duration};
+
 use super::backend::{BackendType, SystemdConfig, UciConfig};
+use super::schedule::Backoff;
+use ipnet::IpNet;
 use serde::Deserialize;
+use wgpull_shared::{
+    config::{ConfigFile, ConfigProblems},
+    signing::{PublicKey, SigningError},
+    validation::{validate_hostname_or_ip, Validated, ValidationError},
+};
+
+/// Maximum length of a network interface name (IFNAMSIZ without the terminating null byte).
+const MAX_INTERFACE_NAME_LENGTH: usize = 15;
+
+/// Name of a network interface, e.g. `wg0`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct InterfaceName(String);
+
+impl Deref for InterfaceName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for InterfaceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Validated for InterfaceName {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.0.is_empty() {
+            return Err(ValidationError::EmptyValue("interface"));
+        }
+        if self.0.len() > MAX_INTERFACE_NAME_LENGTH {
+            return Err(ValidationError::InvalidFormat(
+                "interface",
+                "Interface name is longer than 15 characters",
+            ));
+        }
+        if self.0 == "."
+            || self.0 == ".."
+            || !self
+                .0
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            return Err(ValidationError::InvalidFormat(
+                "interface",
+                "Invalid character in interface name",
+            ));
+        }
+        Ok(())
+    }
+}
 
 /// Node configuration.
 #[derive(Debug, Clone, Deserialize)]
 pub struct NodeConfig {
     /// Hostname or IP address of the lighthouse server.
+    #[serde(default)]
     pub lighthouse_host: String,
     /// Port of the lighthouse server.
+    #[serde(default)]
     pub lighthouse_port: u16,
     /// Path prefix of the lighthouse server.
+    #[serde(default)]
     pub lighthouse_path_prefix: String,
     /// Whether or not to use SSL when connecting to the lighthouse.
+    #[serde(default)]
     pub lighthouse_ssl: bool,
+    /// URLs of lighthouse servers to use in order of preference, failing over to the
+    /// next one on errors. Takes precedence over the host/port/prefix/ssl options.
+    #[serde(default)]
+    pub lighthouse_urls: Vec<String>,
     /// Key used by the lighthouse to authenticate the nodes.
     pub lighthouse_key: String,
     /// Key used by node to authenticate with the lighthouse server.
     pub node_key: String,
+    /// Lighthouse key to fall back to if the lighthouse rejects the primary key, e.g. the
+    /// previous key during a key rotation.
+    #[serde(default)]
+    pub secondary_lighthouse_key: Option<String>,
+    /// Node key paired with the secondary lighthouse key, defaults to the node key.
+    #[serde(default)]
+    pub secondary_node_key: Option<String>,
     /// Time inbetween each pull of the lighthouse's node configuration.
     pub pull_interval: u32,
     /// Time inbetween each push of the node's metrics to the lighthouse.
     pub metrics_interval: u32,
     /// State file to store the node's state.
     pub state_file: String,
+    /// File containing the key to encrypt the private and pre-shared keys in the state file,
+    /// falls back to the `WGPULL_STATE_KEY` environment variable and the `wgpull-state-key`
+    /// systemd credential.
+    #[serde(default)]
+    pub state_key_file: Option<String>,
+    /// Whether or not to watch the lighthouse for changes to pull immediately.
+    #[serde(default)]
+    pub watch: bool,
+    /// Time in seconds a single watch request waits for changes.
+    #[serde(default = "default_watch_timeout")]
+    pub watch_timeout: u32,
+    /// Whether or not to ping the tunnel address of each peer to measure latency and loss.
+    #[serde(default)]
+    pub probe_peers: bool,
+    /// Number of pings sent to each peer with every metrics push.
+    #[serde(default = "default_probe_count")]
+    pub probe_count: u32,
+    /// Address to serve the local prometheus metrics and status on, disabled if not set.
+    #[serde(default)]
+    pub status_listen: Option<SocketAddr>,
+    /// Time in seconds before the first retry of a failed pull or metrics push, doubled with
+    /// every consecutive failure.
+    #[serde(default = "default_retry_initial_seconds")]
+    pub retry_initial_seconds: u32,
+    /// Maximum time in seconds between retries of failed pulls or metrics pushes.
+    #[serde(default = "default_retry_max_seconds")]
+    pub retry_max_seconds: u32,
+    /// Time in seconds without a successful pull after which the cached peers are removed,
+    /// the peers are kept forever if set to 0.
+    #[serde(default)]
+    pub lighthouse_lost_grace_seconds: u32,
+    /// Public key of the lighthouse signing key (`wgpull-lighthouse public-key`) to verify the
+    /// documents signed by the lighthouse, pull responses must be signed if set.
+    #[serde(default)]
+    pub lighthouse_public_key: Option<String>,
+    /// Whether or not to exchange the signed endpoints with the peers while the lighthouse is
+    /// unreachable, requires the lighthouse public key.
+    #[serde(default)]
+    pub gossip: bool,
+    /// Port to serve the signed endpoints to the peers on, on the tunnel address of the node.
+    #[serde(default = "default_gossip_port")]
+    pub gossip_port: u16,
+}
+
+fn default_gossip_port() -> u16 {
+    52721
+}
+
+fn default_watch_timeout() -> u32 {
+    60
+}
+
+fn default_probe_count() -> u32 {
+    3
+}
+
+fn default_retry_initial_seconds() -> u32 {
+    2
+}
+
+fn default_retry_max_seconds() -> u32 {
+    300
 }
 
 impl NodeConfig {
@@ -32,6 +167,47 @@ impl NodeConfig {
             "http"
         }
     }
+
+    /// Returns the backoff of retries after failed pulls or metrics pushes.
+    pub fn get_retry_backoff(&self) -> Backoff {
+        Backoff {
+            initial: Duration::from_secs(self.retry_initial_seconds as u64),
+            max: Duration::from_secs(self.retry_max_seconds as u64),
+        }
+    }
+
+    /// Returns the public key to verify documents signed by the lighthouse, none if not
+    /// configured.
+    pub fn get_lighthouse_public_key(&self) -> Result<Option<PublicKey>, SigningError> {
+        self.lighthouse_public_key
+            .as_deref()
+            .map(PublicKey::from_base64)
+            .transpose()
+    }
+
+    /// Returns the base URLs of the lighthouse servers in order of preference.
+    pub fn get_lighthouse_urls(&self) -> Vec<String> {
+        if self.lighthouse_urls.is_empty() {
+            return vec![format!(
+                "{}://{}:{}/{}",
+                self.get_lighthouse_scheme(),
+                self.lighthouse_host,
+                self.lighthouse_port,
+                self.lighthouse_path_prefix
+            )];
+        }
+
+        self.lighthouse_urls
+            .iter()
+            .map(|url| {
+                if url.ends_with('/') {
+                    url.clone()
+                } else {
+                    format!("{}/", url)
+                }
+            })
+            .collect()
+    }
 }
 
 /// Wireguard configuration of a node.
@@ -41,7 +217,7 @@ pub struct WireguardConfig {
     pub backend: BackendType,
 
     /// IP Address of the wireguard node.
-    pub address: String,
+    pub address: IpNet,
 
     /// Public IP Address or Hostname of the wireguard node.
     /// If set to discover the public IP address will be discovered using the
@@ -55,7 +231,7 @@ pub struct WireguardConfig {
     pub persistent_keepalive: u32,
 
     /// List of IP addresses to allow incoming connections from (AllowedIPs).
-    pub allowed_ips: Vec<String>,
+    pub allowed_ips: Vec<IpNet>,
 
     /// Whether or not the allowed ips should route through the wireguard interface.
     pub route_allowed_ips: bool,
@@ -73,3 +249,142 @@ pub struct NodeConfigFile {
     /// UCI configuration.
     pub uci: UciConfig,
 }
+
+impl ConfigFile for NodeConfigFile {
+    const SECRET_KEYS: &'static [&'static str] = &[
+        "node.lighthouse_key",
+        "node.node_key",
+        "node.secondary_lighthouse_key",
+        "node.secondary_node_key",
+    ];
+
+    fn check(&self, problems: &mut ConfigProblems) {
+        let node = &self.node;
+        if node.lighthouse_urls.is_empty() {
+            problems.check(
+                "node.lighthouse_host",
+                validate_hostname_or_ip("lighthouse_host", &node.lighthouse_host),
+            );
+            if node.lighthouse_port == 0 {
+                problems.push("node.lighthouse_port", "Invalid port number");
+            }
+        }
+        for (index, url) in node.lighthouse_urls.iter().enumerate() {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(
+                    format!("node.lighthouse_urls[{}]", index),
+                    "Expected an http:// or https:// URL",
+                );
+            }
+        }
+        if node.lighthouse_key.is_empty() {
+            problems.push("node.lighthouse_key", "Value is empty");
+        }
+        if node.node_key.is_empty() {
+            problems.push("node.node_key", "Value is empty");
+        }
+        if node.secondary_lighthouse_key.as_deref() == Some("") {
+            problems.push("node.secondary_lighthouse_key", "Value is empty");
+        }
+        match node.secondary_node_key.as_deref() {
+            Some("") => problems.push("node.secondary_node_key", "Value is empty"),
+            Some(_) if node.secondary_lighthouse_key.is_none() => problems.push(
+                "node.secondary_node_key",
+                "Requires a secondary_lighthouse_key",
+            ),
+            _ => {}
+        }
+        if node.pull_interval == 0 {
+            problems.push("node.pull_interval", "Expected at least one second");
+        }
+        if node.watch_timeout == 0 {
+            problems.push("node.watch_timeout", "Expected at least one second");
+        }
+        if node.retry_initial_seconds == 0 {
+            problems.push("node.retry_initial_seconds", "Expected at least one second");
+        }
+        if node.retry_max_seconds < node.retry_initial_seconds {
+            problems.push(
+                "node.retry_max_seconds",
+                "Expected at least retry_initial_seconds",
+            );
+        }
+        match node.get_lighthouse_public_key() {
+            Ok(None) if node.gossip => {
+                problems.push("node.gossip", "Gossip requires the lighthouse_public_key");
+            }
+            Err(err) => problems.push("node.lighthouse_public_key", err.to_string()),
+            _ => {}
+        }
+        if node.gossip && node.gossip_port == 0 {
+            problems.push("node.gossip_port", "Invalid port number");
+        }
+        if node.probe_peers && node.probe_count == 0 {
+            problems.push("node.probe_count", "Expected at least one ping");
+        }
+
+        let wireguard = &self.wireguard;
+        if wireguard.endpoint != "discover" {
+            problems.check(
+                "wireguard.endpoint",
+                validate_hostname_or_ip("endpoint", &wireguard.endpoint),
+            );
+        }
+        if wireguard.listen_port == 0 || wireguard.listen_port > u16::MAX as u32 {
+            problems.push("wireguard.listen_port", "Invalid port number");
+        }
+
+        problems.check("systemd.interface", self.systemd.interface.validate());
+        problems.check("uci.interface", self.uci.interface.validate());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpull_shared::config::{parse_config, ConfigError};
+
+    use super::NodeConfigFile;
+
+    #[test]
+    fn test_node_config_problems() {
+        let contents = include_str!("../../../node.toml");
+        let no_file = |_: &str| Err("no files".to_string());
+        let config = parse_config::<NodeConfigFile>(contents, std::iter::empty(), no_file).unwrap();
+        assert_eq!(config.wireguard.address.to_string(), "10.140.0.10/24");
+        assert_eq!(&*config.systemd.interface, "wg0");
+
+        let contents = contents
+            .replace("pull_interval = 30", "pull_interval = 0")
+            .replace("watch_timeout = 60", "watch_timeout = 0")
+            .replace("gossip = false", "gossip = true")
+            .replace("interface = \"wg0\"", "interface = \"wireguard/0\"");
+        let Err(ConfigError::InvalidConfig(problems)) =
+            parse_config::<NodeConfigFile>(&contents, std::iter::empty(), no_file)
+        else {
+            panic!("expected invalid config");
+        };
+        let keys: Vec<&str> = problems
+            .iter()
+            .map(|problem| problem.key.as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "node.pull_interval",
+                "node.watch_timeout",
+                "node.gossip",
+                "systemd.interface",
+                "uci.interface"
+            ]
+        );
+        assert!(problems.iter().all(|problem| problem.line.is_some()));
+
+        let contents = contents.replace("10.140.0.10/24", "10.140.0.300/24");
+        let Err(ConfigError::InvalidConfig(problems)) =
+            parse_config::<NodeConfigFile>(&contents, std::iter::empty(), no_file)
+        else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(problems[0].key, "wireguard.address");
+    }
+}
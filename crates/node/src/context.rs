@@ -1,15 +1,34 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::SystemTime,
+};
 
-use super::{agent::NodeAgent, config::NodeConfigFile, state::NodeState};
-use crate::backend::get_backend_impl;
+use super::{
+    agent::NodeAgent,
+    config::NodeConfigFile,
+    gossip::fetch_endpoint_records,
+    probe::{peer_tunnel_address, probe_peers, ProbeResult},
+    state::{NodeState, LAST_PULL_REFRESH_SECONDS},
+    status::NodeStatus,
+};
+use crate::backend::{get_backend_impl, Backend};
 use crate::state::NodeError;
 use anyhow::Result;
-use log::info;
+use futures_util::future::join_all;
+use log::{debug, info, warn};
+use tokio::sync::Mutex;
 use wgpull_shared::{
     client::HttpClient,
     command::CommandExecutor,
+    crypto::SecretKey,
     file::FileAccessor,
-    request::{NodeMetricsPushRequest, NodeMetricsPushRequestPeer, NodePullRequest},
+    request::{
+        NodeApplyStatus, NodeMetricsPushRequest, NodeMetricsPushRequestPeer, NodePullRequest,
+    },
+    state::load_state_key,
+    time::unix_timestamp,
     validation::Validated,
     wg::{WireguardCommand, WireguardInfo},
 };
@@ -20,6 +39,14 @@ pub struct NodeContext {
     pub executor: Arc<dyn CommandExecutor>,
     pub file_accessor: Arc<dyn FileAccessor>,
     pub http_client: Arc<dyn HttpClient>,
+    /// Result of the last apply of the local state, reported to the lighthouse with the next pull.
+    pub last_apply: Option<NodeApplyStatus>,
+    /// Key to encrypt the secrets in the state file.
+    pub state_key: Option<SecretKey>,
+    /// Status of the node shared with the local status endpoint.
+    pub status: Arc<Mutex<NodeStatus>>,
+    /// Whether or not regenerated keys still have to be announced to the lighthouse by a pull.
+    pub keys_pending: bool,
 }
 
 impl NodeContext {
@@ -29,14 +56,37 @@ impl NodeContext {
         file_accessor: Arc<dyn FileAccessor>,
         http_client: Arc<dyn HttpClient>,
     ) -> Result<Self> {
-        match NodeState::from_file(&config.node.state_file, file_accessor.as_ref()).await? {
-            Some(state) => {
+        let state_key = load_state_key(
+            config.node.state_key_file.as_deref(),
+            file_accessor.as_ref(),
+        )
+        .await?;
+        match NodeState::from_file(
+            &config.node.state_file,
+            file_accessor.as_ref(),
+            state_key.as_ref(),
+        )
+        .await?
+        {
+            Some(mut state) => {
+                // the configuration takes precedence over the stored state, the changes are
+                //   applied with the first pull
+                state.reconcile_wireguard_config(&config.wireguard);
+                // the grace period of states saved before the time of the last pull was
+                //   stored starts now
+                state
+                    .last_pull
+                    .get_or_insert(unix_timestamp(SystemTime::now()));
                 let context = NodeContext {
+                    status: Arc::new(Mutex::new(NodeStatus::new(&state))),
                     config: config.clone(),
                     state,
                     executor,
                     file_accessor,
                     http_client,
+                    last_apply: None,
+                    state_key,
+                    keys_pending: false,
                 };
                 Ok(context)
             }
@@ -50,70 +100,320 @@ impl NodeContext {
                 )
                 .await?;
                 let context = NodeContext {
+                    status: Arc::new(Mutex::new(NodeStatus::new(&state))),
                     config: config.clone(),
                     state,
                     executor,
                     file_accessor,
                     http_client,
+                    last_apply: None,
+                    state_key,
+                    keys_pending: false,
                 };
                 Ok(context)
             }
         }
     }
 
+    /// Pulls and applies the peer configuration, recording the result in the node status.
     pub async fn pull_wireguard(&mut self) -> Result<()> {
+        let result = self.pull_and_apply().await;
+
+        let mut status = self.status.lock().await;
+        status.hostname = self.state.hostname.clone();
+        status.revision = self.state.revision;
+        status.last_pull = Some(SystemTime::now());
+        status.last_pull_error = result.as_ref().err().map(|err| err.to_string());
+        status.last_apply = self.last_apply.clone();
+        status.peer_hostnames = self
+            .state
+            .peers
+            .iter()
+            .map(|peer| (peer.public_key.clone(), peer.hostname.clone()))
+            .collect();
+        status.endpoint_records = self.state.endpoint_records();
+
+        result
+    }
+
+    /// Pulls and applies the peer configuration once, pulling again if the lighthouse requested
+    /// new keys to announce them.
+    pub async fn pull_wireguard_once(&mut self) -> Result<()> {
+        self.pull_wireguard().await?;
+        if self.keys_pending {
+            self.pull_wireguard().await?;
+        }
+        Ok(())
+    }
+
+    async fn pull_and_apply(&mut self) -> Result<()> {
         info!("Pulling Wireguard configuration.");
         let agent = NodeAgent::from_node_config(&self.config.node, self.http_client.as_ref())?;
 
-        let request: NodePullRequest = self.state.clone().into();
-        let response = agent.pull_wireguard(request).await?;
+        let mut request: NodePullRequest = self.state.clone().into();
+        request.last_apply = self.last_apply.clone();
+        let response = agent
+            .pull_wireguard(request, self.state.etag.as_deref())
+            .await;
+        self.status
+            .lock()
+            .await
+            .record_lighthouse_request(response.is_ok(), SystemTime::now());
+        let response = response?;
+        // the request announced the current keys
+        self.keys_pending = false;
+        let now = unix_timestamp(SystemTime::now());
+        let refresh_last_pull = self
+            .state
+            .last_pull
+            .is_none_or(|last_pull| now.saturating_sub(last_pull) >= LAST_PULL_REFRESH_SECONDS);
+
+        match response {
+            Some((response, etag)) => {
+                info!(
+                    "Received configuration of {} peers from lighthouse (revision {}).",
+                    response.peers.len(),
+                    response.revision
+                );
+
+                // update state from response, replacing all peers and regenerate keys if requested
+                self.keys_pending = self
+                    .state
+                    .update_from_pull_response(&response, etag, self.executor.clone())
+                    .await?;
+                self.state.last_pull = Some(now);
+            }
+            None => {
+                // the configuration was confirmed by the lighthouse
+                self.state.last_pull = Some(now);
+                // skip reconciling the backend, unless the last apply failed or this is the
+                //   first pull since startup
+                if matches!(&self.last_apply, Some(last_apply) if last_apply.success) {
+                    info!("Peer configuration not modified since last pull.");
+                    if refresh_last_pull {
+                        self.save_state().await?;
+                    }
+                    return Ok(());
+                }
+                info!("Peer configuration not modified, but not applied yet.");
+            }
+        }
+
+        // configure the local system to match the state, remembering the result for the lighthouse
+        self.apply_and_record().await?;
+
+        // save state to disk
+        self.save_state().await
+    }
+
+    /// Configures the local system to match the cached state, so the peers are reachable even
+    /// if the lighthouse is not, does nothing if no configuration was received yet.
+    ///
+    /// Cached peers are removed first if the lighthouse was lost for longer than the grace
+    /// period.
+    pub async fn apply_cached_state(&mut self) -> Result<()> {
+        if self.state.revision.is_none() && self.state.peers.is_empty() {
+            return Ok(());
+        }
+        if self.expire_peers() {
+            self.save_state().await?;
+        }
 
         info!(
-            "Received configuration of {} peers from lighthouse.",
-            response.peers.len()
+            "Applying cached configuration of {} peers.",
+            self.state.peers.len()
         );
+        let result = self.apply_and_record().await;
+        self.status.lock().await.last_apply = self.last_apply.clone();
+        result
+    }
 
-        // update state from response, replacing all peers and regenerate keys if requested
+    /// Removes and unconfigures the cached peers once the lighthouse was lost for longer than
+    /// the grace period, called after failed pulls.
+    pub async fn expire_cached_peers(&mut self) -> Result<()> {
+        if !self.expire_peers() {
+            return Ok(());
+        }
+        let result = self.apply_local_state().await;
+        self.last_apply = None;
+        self.save_state().await?;
+        result.map(|_| ())
+    }
+
+    fn expire_peers(&mut self) -> bool {
+        let peers = self.state.peers.len();
+        let grace_seconds = self.config.node.lighthouse_lost_grace_seconds as u64;
+        let expired = self
+            .state
+            .expire_peers(unix_timestamp(SystemTime::now()), grace_seconds);
+        if expired {
+            warn!(
+                "No successful pull for more than {}s, removed {} cached peers.",
+                grace_seconds, peers
+            );
+        }
+        expired
+    }
+
+    async fn save_state(&self) -> Result<()> {
         self.state
-            .update_from_pull_response(&response, self.executor.clone())
-            .await?;
+            .save(
+                &self.config.node.state_file,
+                self.file_accessor.as_ref(),
+                self.state_key.as_ref(),
+            )
+            .await
+    }
+
+    /// Generates new local keys and pulls immediately to announce the new public key.
+    pub async fn rotate_keys(&mut self) -> Result<()> {
+        info!("Regenerating local keys.");
+        self.state.regenerate_keys(self.executor.clone()).await?;
+        self.save_state().await?;
+        self.pull_wireguard().await
+    }
 
-        // get backend by configuration
-        let backend = get_backend_impl(
+    /// Replaces the configuration with a reloaded one and reconciles the changed wireguard
+    /// settings into the state, these are applied with the next pull.
+    ///
+    /// The state file, status endpoint and watch task are only setup at startup, changes to
+    /// their settings keep the current values until the next restart.
+    pub async fn reload_config(&mut self, mut config: NodeConfigFile) -> Result<()> {
+        let current = &self.config.node;
+        let mut restart_required = Vec::new();
+        if config.node.state_file != current.state_file {
+            restart_required.push("state_file");
+            config.node.state_file = current.state_file.clone();
+        }
+        if config.node.state_key_file != current.state_key_file {
+            restart_required.push("state_key_file");
+            config.node.state_key_file = current.state_key_file.clone();
+        }
+        if config.node.status_listen != current.status_listen {
+            restart_required.push("status_listen");
+            config.node.status_listen = current.status_listen;
+        }
+        if config.node.gossip != current.gossip || config.node.gossip_port != current.gossip_port {
+            restart_required.push("gossip");
+            config.node.gossip = current.gossip;
+            config.node.gossip_port = current.gossip_port;
+        }
+        if config.node.watch != current.watch || config.node.watch_timeout != current.watch_timeout
+        {
+            restart_required.push("watch");
+            config.node.watch = current.watch;
+            config.node.watch_timeout = current.watch_timeout;
+        }
+        if !restart_required.is_empty() {
+            warn!(
+                "Changes to {} require a restart of the node.",
+                restart_required.join(", ")
+            );
+        }
+
+        let changed = self.state.reconcile_wireguard_config(&config.wireguard);
+        self.config = config;
+        if !changed.is_empty() {
+            info!("Reloaded configuration changed {}.", changed.join(", "));
+            // apply the changes with the next pull even if the peers are not modified
+            self.last_apply = None;
+            self.save_state().await?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the signed endpoints known to the peers and updates the changed endpoints of the
+    /// peers, used to spread endpoint changes while the lighthouse is unreachable.
+    pub async fn gossip_endpoints(&mut self) -> Result<()> {
+        let Some(key) = self.config.node.get_lighthouse_public_key()? else {
+            return Ok(());
+        };
+        let addrs: Vec<SocketAddr> = self
+            .state
+            .peers
+            .iter()
+            .filter_map(peer_tunnel_address)
+            .filter_map(|addr| addr.parse::<IpAddr>().ok())
+            .map(|addr| SocketAddr::new(addr, self.config.node.gossip_port))
+            .collect();
+        let responses = join_all(addrs.iter().map(|addr| {
+            fetch_endpoint_records(self.http_client.as_ref(), *addr, &self.config.node.node_key)
+        }))
+        .await;
+
+        let mut records = Vec::new();
+        for (addr, response) in addrs.iter().zip(responses) {
+            match response {
+                Ok(response) => records.extend(response),
+                Err(err) => debug!("Failed to gossip with peer {}: {}", addr, err),
+            }
+        }
+        let updated = self.state.merge_endpoint_records(&records, &key);
+        self.status.lock().await.endpoint_records = self.state.endpoint_records();
+        if updated.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            "Updated endpoints of {} from peers, lighthouse unreachable.",
+            updated.join(", ")
+        );
+        self.apply_and_record().await?;
+        self.save_state().await
+    }
+
+    fn backend(&self) -> Box<dyn Backend> {
+        get_backend_impl(
             self.config.wireguard.backend.clone(),
             &self.config,
             self.executor.clone(),
             self.file_accessor.clone(),
-        );
+        )
+    }
+
+    /// Configures the local system to match the state, remembering the result for the lighthouse.
+    async fn apply_and_record(&mut self) -> Result<()> {
+        let result = self.apply_local_state().await;
+        self.last_apply = Some(NodeApplyStatus {
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+            revision: self.state.revision,
+            backend: self.config.wireguard.backend.name().to_string(),
+        });
+        result.map(|_| ())
+    }
+
+    /// Configures the local wireguard interface to match the state using the configured backend.
+    async fn apply_local_state(&self) -> Result<bool> {
+        let backend = self.backend();
         if !backend.is_compatible().await {
             return Err(NodeError::BackendNotCompatible.into());
         }
 
-        // configure the local system to match the state
-        backend.update_local_state(&self.state).await?;
-
-        // save state to disk
-        self.state
-            .save(&self.config.node.state_file, self.file_accessor.as_ref())
-            .await?;
-
-        Ok(())
+        backend.update_local_state(&self.state).await
     }
 
     fn metrics_push_request_from_info(
         &self,
         info: WireguardInfo,
+        probes: &HashMap<String, ProbeResult>,
     ) -> Result<NodeMetricsPushRequest> {
         let peers = info
             .peers
             .into_iter()
-            .map(|peer| NodeMetricsPushRequestPeer {
-                hostname: self.state.get_hostname_by_public_key(&peer.public_key),
-                endpoint: peer.endpoint,
-                latest_handshake: peer.latest_handshake,
-                transfer_rx: peer.transfer_rx,
-                transfer_tx: peer.transfer_tx,
-                persistent_keepalive: peer.persistent_keepalive,
+            .map(|peer| {
+                let hostname = self.state.get_hostname_by_public_key(&peer.public_key);
+                let probe = probes.get(&hostname);
+                NodeMetricsPushRequestPeer {
+                    endpoint: peer.endpoint,
+                    latest_handshake: peer.latest_handshake,
+                    transfer_rx: peer.transfer_rx,
+                    transfer_tx: peer.transfer_tx,
+                    persistent_keepalive: peer.persistent_keepalive,
+                    rtt_seconds: probe.and_then(|probe| probe.rtt_seconds),
+                    loss_ratio: probe.map(|probe| probe.loss_ratio),
+                    hostname,
+                }
             })
             .collect();
 
@@ -133,9 +433,24 @@ impl NodeContext {
         // collect local wireguard metrics:
         let info = wireguard_command.collect().await?;
         if let Some(metrics) = info {
-            let request = self.metrics_push_request_from_info(metrics)?;
+            let probes = if self.config.node.probe_peers {
+                probe_peers(
+                    self.executor.clone(),
+                    &self.state.peers,
+                    self.config.node.probe_count,
+                )
+                .await
+            } else {
+                HashMap::new()
+            };
+            let request = self.metrics_push_request_from_info(metrics, &probes)?;
             // push metrics to lighthouse:
-            agent.push_metrics(request).await?;
+            let result = agent.push_metrics(request).await;
+            self.status
+                .lock()
+                .await
+                .record_lighthouse_request(result.is_ok(), SystemTime::now());
+            result?;
         }
         Ok(())
     }
@@ -0,0 +1,94 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use log::{info, warn};
+use reqwest::header::HeaderValue;
+use tokio::sync::Mutex;
+use wgpull_shared::{
+    client::HttpClient, crypto::verify_key, headers::HEADER_LIGHTHOUSE_KEY,
+    response::NodeGossipResponse, signing::SignedDocument,
+};
+
+use super::status::NodeStatus;
+
+/// Time to wait before trying to bind the gossip endpoint again, the tunnel address might not
+/// be configured yet.
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Shared state of the gossip endpoint.
+#[derive(Clone)]
+pub struct NodeGossipProvider {
+    /// Node keys accepted from peers, the secondary node key is accepted during a key rotation.
+    pub node_keys: Vec<String>,
+    pub status: Arc<Mutex<NodeStatus>>,
+}
+
+/// Serves the signed endpoints of the node and its peers to peers authenticated with the
+/// primary or secondary node key in <HEADER_LIGHTHOUSE_KEY>.
+async fn get_gossip_handler(
+    State(provider): State<NodeGossipProvider>,
+    headers: HeaderMap,
+) -> Result<Json<NodeGossipResponse>, StatusCode> {
+    let received_key = headers
+        .get(HEADER_LIGHTHOUSE_KEY)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    if !provider
+        .node_keys
+        .iter()
+        .any(|node_key| verify_key(received_key, node_key))
+    {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let status = provider.status.lock().await;
+    Ok(Json(NodeGossipResponse {
+        records: status.endpoint_records.clone(),
+    }))
+}
+
+fn make_router(provider: NodeGossipProvider) -> Router {
+    Router::new()
+        .route("/api/v1/gossip", get(get_gossip_handler))
+        .with_state(provider)
+}
+
+/// Serves the gossip endpoint on the tunnel address of the node until the process exits,
+/// binding is retried until the tunnel address is configured.
+pub async fn serve_gossip(addr: SocketAddr, provider: NodeGossipProvider) -> Result<()> {
+    let listener = loop {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => break listener,
+            Err(err) => {
+                warn!("Failed to bind gossip endpoint to {}: {}", addr, err);
+                tokio::time::sleep(BIND_RETRY_INTERVAL).await;
+            }
+        }
+    };
+    info!("Node gossip listening on: {}", addr);
+    axum::serve(listener, make_router(provider)).await?;
+    Ok(())
+}
+
+/// Fetches the signed endpoints known to the peer serving the gossip endpoint at the address.
+pub async fn fetch_endpoint_records(
+    client: &dyn HttpClient,
+    addr: SocketAddr,
+    node_key: &str,
+) -> Result<Vec<SignedDocument>> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(HEADER_LIGHTHOUSE_KEY, HeaderValue::from_str(node_key)?);
+
+    let response = client
+        .get(&format!("http://{}/api/v1/gossip", addr), headers)
+        .await?
+        .error_for_status()?;
+    let response: NodeGossipResponse = serde_json::from_str(&response.text().await?)?;
+    Ok(response.records)
+}
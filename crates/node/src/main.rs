@@ -1,74 +1,318 @@
 mod agent;
 mod backend;
+mod commands;
 mod config;
 mod context;
 mod discover;
+mod gossip;
+mod probe;
+mod schedule;
+mod server;
 mod state;
+mod status;
+mod watch;
 
+use anyhow::Result;
+use clap::{Parser, Subcommand};
 use log::{error, info};
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+    time::{sleep_until, Instant},
 };
 
 use config::NodeConfigFile;
 use context::NodeContext;
+use schedule::{Schedule, JITTER_RATIO};
 use wgpull_shared::{
     client::SystemHttpClient,
     command::SystemCommandExecutor,
     config::{discover_config_path, load_config},
-    file::SystemFileAccessor,
-    logger,
+    file::{check_private_file, SystemFileAccessor},
+    logger, service,
 };
 
+#[derive(Parser)]
+#[command(version, about = "Wireguard node pulling its peers from a lighthouse")]
+struct Cli {
+    /// Path of the configuration file, discovered in /etc/wgpull and the working directory by default.
+    #[arg(long, global = true, env = "WGPULL_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the node daemon (default).
+    Run,
+    /// Pull and apply the peer configuration once.
+    PullOnce,
+    /// Show the local state and the live wireguard status.
+    Status,
+    /// Show the configuration the backend applies for the local state.
+    ShowConfig {
+        /// Include private and pre-shared keys.
+        #[arg(long)]
+        show_secrets: bool,
+    },
+    /// Generate new local keys and announce them to the lighthouse.
+    RotateKeys,
+    /// Remove the local state and enroll the node again with new keys.
+    Reset,
+    /// Check the configuration without starting the node.
+    CheckConfig,
+}
+
+/// Initializes the node context with the system implementations.
+async fn init_context(config: &NodeConfigFile) -> Result<NodeContext> {
+    // the state file contains the private key of the node
+    check_private_file(&config.node.state_file)?;
+
+    NodeContext::init(
+        config,
+        Arc::new(SystemCommandExecutor),
+        Arc::new(SystemFileAccessor),
+        Arc::new(SystemHttpClient::new(10)?),
+    )
+    .await
+}
+
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
     // setup logger (defaults the log level to info)
     logger::setup_logger();
 
-    let config_path = discover_config_path("node.toml").expect("Failed to discover config path");
+    let config_path = discover_config_path("node.toml", cli.config.clone())
+        .expect("Failed to discover config path");
     info!("Using configuration from: {:?}", config_path);
 
-    let config = load_config::<NodeConfigFile>(&config_path).expect("Failed to load config");
-    let mut context = NodeContext::init(
-        &config,
-        Arc::new(SystemCommandExecutor),
-        Arc::new(SystemFileAccessor),
-        Arc::new(SystemHttpClient::new(10).expect("Failed to create http client")),
-    )
-    .await
-    .expect("Failed to initialize context");
+    let config = match load_config::<NodeConfigFile>(&config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Failed to load config {:?}: {}", config_path, err);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            run(config, config_path).await;
+            Ok(())
+        }
+        Command::PullOnce => match init_context(&config).await {
+            Ok(mut context) => context.pull_wireguard_once().await,
+            Err(err) => Err(err),
+        },
+        Command::Status => commands::show_status(
+            &config,
+            Arc::new(SystemCommandExecutor),
+            Arc::new(SystemFileAccessor),
+        )
+        .await
+        .map(|status| print!("{}", status)),
+        Command::ShowConfig { show_secrets } => commands::show_config(
+            &config,
+            Arc::new(SystemCommandExecutor),
+            Arc::new(SystemFileAccessor),
+            show_secrets,
+        )
+        .await
+        .map(|rendered| print!("{}", rendered)),
+        Command::RotateKeys => match init_context(&config).await {
+            Ok(mut context) => context.rotate_keys().await,
+            Err(err) => Err(err),
+        },
+        Command::Reset => match SystemHttpClient::new(10) {
+            Ok(http_client) => {
+                commands::reset(
+                    &config,
+                    Arc::new(SystemCommandExecutor),
+                    Arc::new(SystemFileAccessor),
+                    Arc::new(http_client),
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        },
+        Command::CheckConfig => commands::check_config(&config, Arc::new(SystemFileAccessor))
+            .await
+            .map(|summary| print!("{}", summary)),
+    };
 
-    if let Err(err) = context.push_metrics().await {
-        error!("Failed to push metrics: {}", err);
+    if let Err(err) = result {
+        error!("{:#}", err);
+        std::process::exit(1);
     }
-    if let Err(err) = context.pull_wireguard().await {
-        error!("Failed to pull wireguard: {}", err);
+}
+
+async fn run(config: NodeConfigFile, config_path: PathBuf) {
+    // stop between pulls on SIGTERM/SIGINT, an apply in progress is finished first
+    let shutdown = service::shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let mut context = init_context(&config)
+        .await
+        .expect("Failed to initialize context");
+
+    // serve local metrics and status, independent of the lighthouse
+    if let Some(addr) = config.node.status_listen {
+        let provider = server::NodeStatusProvider {
+            status: context.status.clone(),
+            executor: context.executor.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(err) = server::serve_status(addr, provider).await {
+                error!("Failed to serve node status: {}", err);
+            }
+        });
     }
 
-    let interval_pull = config.node.pull_interval as u64;
-    let interval_metrics = config.node.metrics_interval as u64;
+    // serve the signed endpoints to the peers on the tunnel address
+    if config.node.gossip {
+        let addr = SocketAddr::new(config.wireguard.address.addr(), config.node.gossip_port);
+        let provider = gossip::NodeGossipProvider {
+            node_keys: std::iter::once(&config.node.node_key)
+                .chain(&config.node.secondary_node_key)
+                .cloned()
+                .collect(),
+            status: context.status.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(err) = gossip::serve_gossip(addr, provider).await {
+                error!("Failed to serve gossip endpoint: {}", err);
+            }
+        });
+    }
+
+    // bring up the cached peers before the first pull, the lighthouse might be unreachable
+    if let Err(err) = context.apply_cached_state().await {
+        error!("Failed to apply cached configuration: {}", err);
+    }
+
+    // watch the lighthouse for changes in the background, notifying the loop to pull now
+    let pull_now = Arc::new(Notify::new());
+    let (revision_sender, revision_receiver) = tokio::sync::watch::channel(context.state.revision);
+    if config.node.watch {
+        let http_client = SystemHttpClient::new(config.node.watch_timeout as u64 + 10)
+            .expect("Failed to create http client");
+        tokio::spawn(watch::watch_lighthouse(
+            config.node.clone(),
+            context.state.hostname.clone(),
+            Arc::new(http_client),
+            revision_receiver,
+            pull_now.clone(),
+        ));
+    }
 
-    let mut next_pull = Instant::now() + Duration::from_secs(interval_pull);
-    let mut next_metrics_push = Instant::now() + Duration::from_secs(interval_metrics);
+    // reload the configuration on SIGHUP
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+
+    service::notify_ready();
+    let watchdog_interval = service::watchdog_interval();
+    let mut next_watchdog = Instant::now();
+
+    // the first pull and metrics push are due immediately
+    let mut pull_schedule = Schedule::new(
+        Duration::from_secs(config.node.pull_interval as u64),
+        config.node.get_retry_backoff(),
+        JITTER_RATIO,
+        Instant::now(),
+    );
+    let mut metrics_schedule = Schedule::new(
+        Duration::from_secs(config.node.metrics_interval as u64),
+        config.node.get_retry_backoff(),
+        JITTER_RATIO,
+        Instant::now(),
+    );
 
     loop {
-        let now = Instant::now();
+        if context.config.node.metrics_interval > 0 && metrics_schedule.is_due(Instant::now()) {
+            match context.push_metrics().await {
+                Ok(()) => metrics_schedule.succeeded(Instant::now()),
+                Err(err) => {
+                    error!("Failed to push metrics: {}", err);
+                    metrics_schedule.failed(Instant::now());
+                }
+            }
+        }
 
-        if now >= next_pull {
-            next_pull += Duration::from_secs(interval_pull);
-            if let Err(err) = context.pull_wireguard().await {
-                error!("Failed to pull wireguard: {}", err);
+        if pull_schedule.is_due(Instant::now()) {
+            match context.pull_wireguard().await {
+                // announce regenerated keys right away
+                Ok(()) if context.keys_pending => pull_schedule.trigger(Instant::now()),
+                Ok(()) => pull_schedule.succeeded(Instant::now()),
+                Err(err) => {
+                    error!("Failed to pull wireguard: {}", err);
+                    if let Err(err) = context.expire_cached_peers().await {
+                        error!("Failed to expire cached peers: {}", err);
+                    }
+                    if context.config.node.gossip {
+                        if let Err(err) = context.gossip_endpoints().await {
+                            error!("Failed to gossip endpoints with peers: {}", err);
+                        }
+                    }
+                    pull_schedule.failed(Instant::now());
+                    if pull_schedule.failures() > 1 {
+                        info!(
+                            "Retrying pull in {}s after {} failures.",
+                            (pull_schedule.next() - Instant::now()).as_secs(),
+                            pull_schedule.failures()
+                        );
+                    }
+                }
             }
+            revision_sender.send_replace(context.state.revision);
         }
 
-        if now >= next_metrics_push {
-            next_metrics_push += Duration::from_secs(interval_metrics);
-            if let Err(err) = context.push_metrics().await {
-                error!("Failed to push metrics: {}", err);
+        // the watchdog is pinged by the loop itself, to detect a stuck pull or apply
+        let mut wake = pull_schedule.next();
+        if context.config.node.metrics_interval > 0 {
+            wake = wake.min(metrics_schedule.next());
+        }
+        if let Some(interval) = watchdog_interval {
+            if Instant::now() >= next_watchdog {
+                next_watchdog = Instant::now() + interval;
+                service::notify_watchdog();
             }
+            wake = wake.min(next_watchdog);
         }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::select! {
+            _ = sleep_until(wake) => {}
+            _ = &mut shutdown => {
+                break;
+            }
+            _ = pull_now.notified() => {
+                pull_schedule.trigger(Instant::now());
+            }
+            _ = hangup.recv() => {
+                match load_config::<NodeConfigFile>(&config_path) {
+                    Ok(config) => {
+                        info!("Reloading configuration from {:?}", config_path);
+                        if let Err(err) = context.reload_config(config).await {
+                            error!("Failed to reload configuration: {}", err);
+                        }
+                        let node = &context.config.node;
+                        pull_schedule.configure(
+                            Duration::from_secs(node.pull_interval as u64),
+                            node.get_retry_backoff(),
+                        );
+                        metrics_schedule.configure(
+                            Duration::from_secs(node.metrics_interval as u64),
+                            node.get_retry_backoff(),
+                        );
+                        pull_schedule.trigger(Instant::now());
+                    }
+                    Err(err) => error!("Failed to reload configuration: {}", err),
+                }
+            }
+        }
     }
+
+    info!("Node stopped.");
 }
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ipnet::IpNet;
+use log::debug;
+use tokio::task::JoinSet;
+use wgpull_shared::command::CommandExecutor;
+
+use super::state::NodePeer;
+
+/// Latency and packet loss of the tunnel to a peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeResult {
+    /// Average round trip time in seconds, none if no reply was received.
+    pub rtt_seconds: Option<f64>,
+
+    /// Ratio of probes without reply (0.0 - 1.0).
+    pub loss_ratio: f64,
+}
+
+/// Returns the tunnel address of the peer to probe, the first allowed ip that is a
+/// single host address (e.g. 10.140.0.10/32).
+pub fn peer_tunnel_address(peer: &NodePeer) -> Option<String> {
+    peer.allowed_ips
+        .iter()
+        .filter_map(|allowed_ip| allowed_ip.parse::<IpNet>().ok())
+        .find(|net| net.prefix_len() == net.max_prefix_len())
+        .map(|net| net.addr().to_string())
+}
+
+/// Returns the leading number of the text, e.g. `3` for `3 packets transmitted`.
+fn leading_number(text: &str) -> Option<u32> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+/// Parses the summary of the ping command (iputils and busybox).
+pub fn parse_ping_output(output: &str) -> Option<ProbeResult> {
+    let summary = output.lines().find(|line| line.contains("transmitted"))?;
+    let mut transmitted = None;
+    let mut received = None;
+    for part in summary.split(',') {
+        if part.contains("transmitted") {
+            transmitted = leading_number(part);
+        } else if part.contains("received") {
+            received = leading_number(part);
+        }
+    }
+    let transmitted = transmitted.filter(|transmitted| *transmitted > 0)?;
+    let received = received?.min(transmitted);
+
+    // rtt min/avg/max/mdev = 0.035/0.045/0.056/0.010 ms
+    let rtt_seconds = output
+        .lines()
+        .find(|line| line.contains("min/avg/max"))
+        .and_then(|line| line.split('=').nth(1))
+        .and_then(|values| values.trim().split('/').nth(1))
+        .and_then(|average| average.trim().parse::<f64>().ok())
+        .map(|milliseconds| milliseconds / 1000.0);
+
+    Some(ProbeResult {
+        rtt_seconds: if received > 0 { rtt_seconds } else { None },
+        loss_ratio: f64::from(transmitted - received) / f64::from(transmitted),
+    })
+}
+
+/// Pings the address through the tunnel, ping fails if no reply was received which is
+/// reported as a complete loss.
+pub async fn probe_address(
+    executor: &dyn CommandExecutor,
+    address: &str,
+    count: u32,
+) -> ProbeResult {
+    let count = count.to_string();
+    let args = ["-n", "-q", "-c", count.as_str(), "-W", "1", address];
+    match executor.execute_with_args("ping", &args).await {
+        Ok((stdout, _)) => parse_ping_output(&stdout).unwrap_or(ProbeResult {
+            rtt_seconds: None,
+            loss_ratio: 1.0,
+        }),
+        Err(err) => {
+            debug!("Probing {} failed: {}", address, err);
+            ProbeResult {
+                rtt_seconds: None,
+                loss_ratio: 1.0,
+            }
+        }
+    }
+}
+
+/// Probes the tunnel addresses of all peers concurrently, returns the results by hostname.
+pub async fn probe_peers(
+    executor: Arc<dyn CommandExecutor>,
+    peers: &[NodePeer],
+    count: u32,
+) -> HashMap<String, ProbeResult> {
+    let mut probes = JoinSet::new();
+    for peer in peers {
+        let Some(address) = peer_tunnel_address(peer) else {
+            continue;
+        };
+        let executor = executor.clone();
+        let hostname = peer.hostname.clone();
+        probes.spawn(async move {
+            let result = probe_address(executor.as_ref(), &address, count).await;
+            (hostname, result)
+        });
+    }
+
+    let mut results = HashMap::new();
+    while let Some(probe) = probes.join_next().await {
+        if let Ok((hostname, result)) = probe {
+            results.insert(hostname, result);
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ping_output, peer_tunnel_address, ProbeResult};
+    use crate::state::NodePeer;
+
+    #[test]
+    fn test_parse_ping_output() {
+        let iputils = "PING 10.140.0.11 (10.140.0.11) 56(84) bytes of data.\n\n\
+            --- 10.140.0.11 ping statistics ---\n\
+            4 packets transmitted, 3 received, 25% packet loss, time 3004ms\n\
+            rtt min/avg/max/mdev = 0.912/1.500/2.100/0.400 ms\n";
+        assert_eq!(
+            parse_ping_output(iputils),
+            Some(ProbeResult {
+                rtt_seconds: Some(0.0015),
+                loss_ratio: 0.25,
+            })
+        );
+
+        let busybox = "PING 10.140.0.11 (10.140.0.11): 56 data bytes\n\n\
+            --- 10.140.0.11 ping statistics ---\n\
+            3 packets transmitted, 3 packets received, 0% packet loss\n\
+            round-trip min/avg/max = 10.000/12.000/14.000 ms\n";
+        assert_eq!(
+            parse_ping_output(busybox),
+            Some(ProbeResult {
+                rtt_seconds: Some(0.012),
+                loss_ratio: 0.0,
+            })
+        );
+
+        let lost = "3 packets transmitted, 0 received, 100% packet loss, time 2030ms\n";
+        assert_eq!(
+            parse_ping_output(lost),
+            Some(ProbeResult {
+                rtt_seconds: None,
+                loss_ratio: 1.0,
+            })
+        );
+        assert_eq!(parse_ping_output("ping: unknown host"), None);
+    }
+
+    #[test]
+    fn test_peer_tunnel_address() {
+        let mut peer = NodePeer {
+            hostname: "node2".to_string(),
+            public_key: "sB4YQaiCcRpUHRa+5ziiHK2JSaznxGF+xWFSfja00Wk=".to_string(),
+            preshared_key: "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=".to_string(),
+            endpoint_host: "node2.example.com".to_string(),
+            endpoint_port: 52720,
+            allowed_ips: vec!["10.10.0.0/16".to_string(), "10.140.0.11/32".to_string()],
+            persistent_keepalive: 15,
+            route_allowed_ips: true,
+            signed_endpoint: None,
+        };
+        assert_eq!(peer_tunnel_address(&peer), Some("10.140.0.11".to_string()));
+
+        peer.allowed_ips = vec!["10.10.0.0/16".to_string()];
+        assert_eq!(peer_tunnel_address(&peer), None);
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::Instant;
+
+/// Ratio of an interval or retry delay that is randomly added or subtracted, this spreads the
+/// requests of nodes that started at the same time (e.g. after a lighthouse restart).
+pub const JITTER_RATIO: f64 = 0.1;
+
+/// Delays of retries after failures, doubling with every failure up to the maximum.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Returns the delay of the retry after the number of consecutive failures.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Schedules a recurring task in jittered intervals, retrying failures with exponential backoff.
+///
+/// The current time is passed to every method, so the schedule can be tested without waiting.
+pub struct Schedule {
+    interval: Duration,
+    backoff: Backoff,
+    jitter_ratio: f64,
+    failures: u32,
+    next: Instant,
+    rng: StdRng,
+}
+
+impl Schedule {
+    /// Creates a schedule that is due immediately, delays are randomized by the jitter ratio
+    /// (0 disables the jitter).
+    pub fn new(interval: Duration, backoff: Backoff, jitter_ratio: f64, now: Instant) -> Self {
+        Self {
+            interval,
+            backoff,
+            jitter_ratio,
+            failures: 0,
+            next: now,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Replaces the interval and backoff, e.g. after the configuration was reloaded.
+    pub fn configure(&mut self, interval: Duration, backoff: Backoff) {
+        self.interval = interval;
+        self.backoff = backoff;
+    }
+
+    /// Time the task is due next.
+    pub fn next(&self) -> Instant {
+        self.next
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next
+    }
+
+    /// Number of consecutive failures since the last success.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Schedules the task to run immediately.
+    pub fn trigger(&mut self, now: Instant) {
+        self.next = now;
+    }
+
+    /// Records a successful run, the task is due again after the jittered interval.
+    pub fn succeeded(&mut self, now: Instant) {
+        self.failures = 0;
+        self.next = now + self.jitter(self.interval);
+    }
+
+    /// Records a failed run, the task is retried after the jittered backoff delay.
+    pub fn failed(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        self.next = now + self.jitter(self.backoff.delay(self.failures));
+    }
+
+    fn jitter(&mut self, delay: Duration) -> Duration {
+        if self.jitter_ratio <= 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + self.rng.gen_range(-self.jitter_ratio..=self.jitter_ratio);
+        delay.mul_f64(factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{Backoff, Schedule, JITTER_RATIO};
+
+    const BACKOFF: Backoff = Backoff {
+        initial: Duration::from_secs(2),
+        max: Duration::from_secs(60),
+    };
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(BACKOFF.delay(1), Duration::from_secs(2));
+        assert_eq!(BACKOFF.delay(2), Duration::from_secs(4));
+        assert_eq!(BACKOFF.delay(5), Duration::from_secs(32));
+        assert_eq!(BACKOFF.delay(6), Duration::from_secs(60));
+        assert_eq!(BACKOFF.delay(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_schedule_backoff_and_trigger() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(Duration::from_secs(30), BACKOFF, 0.0, start);
+        assert!(schedule.is_due(start));
+
+        schedule.succeeded(start);
+        assert_eq!(schedule.next(), start + Duration::from_secs(30));
+        assert!(!schedule.is_due(start + Duration::from_secs(29)));
+
+        let now = start + Duration::from_secs(30);
+        schedule.failed(now);
+        assert_eq!(schedule.next(), now + Duration::from_secs(2));
+        schedule.failed(now);
+        schedule.failed(now);
+        assert_eq!(schedule.failures(), 3);
+        assert_eq!(schedule.next(), now + Duration::from_secs(8));
+
+        schedule.trigger(now);
+        assert!(schedule.is_due(now));
+        schedule.succeeded(now);
+        assert_eq!(schedule.failures(), 0);
+        assert_eq!(schedule.next(), now + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_schedule_jitter() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(Duration::from_secs(100), BACKOFF, JITTER_RATIO, start);
+        let mut delays = Vec::new();
+        for _ in 0..50 {
+            schedule.succeeded(start);
+            let delay = schedule.next() - start;
+            assert!(delay >= Duration::from_secs(90) && delay <= Duration::from_secs(110));
+            delays.push(delay);
+        }
+        // the delays are spread, not all nodes use the same phase
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+}
@@ -0,0 +1,77 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use log::{debug, info};
+use tokio::sync::Mutex;
+use wgpull_shared::{
+    command::CommandExecutor,
+    wg::{WireguardCommand, WireguardInfo},
+};
+
+use super::status::{export_prometheus, NodeStatus, NodeStatusResponse};
+
+/// Shared state of the local status endpoint.
+#[derive(Clone)]
+pub struct NodeStatusProvider {
+    pub status: Arc<Mutex<NodeStatus>>,
+    pub executor: Arc<dyn CommandExecutor>,
+}
+
+impl NodeStatusProvider {
+    /// Collects the current wireguard metrics, none if the interface isn't up.
+    async fn collect_wireguard_info(&self) -> Option<WireguardInfo> {
+        match WireguardCommand::new(self.executor.as_ref())
+            .collect()
+            .await
+        {
+            Ok(info) => info,
+            Err(err) => {
+                debug!("Error collecting wireguard metrics: {}", err);
+                None
+            }
+        }
+    }
+}
+
+async fn get_metrics_handler(State(provider): State<NodeStatusProvider>) -> Response {
+    let info = provider.collect_wireguard_info().await;
+    let status = provider.status.lock().await;
+
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        export_prometheus(&status, info.as_ref()),
+    )
+        .into_response()
+}
+
+async fn get_status_handler(
+    State(provider): State<NodeStatusProvider>,
+) -> Json<NodeStatusResponse> {
+    let info = provider.collect_wireguard_info().await;
+    let status = provider.status.lock().await;
+
+    Json(NodeStatusResponse::new(&status, info.as_ref()))
+}
+
+fn make_router(provider: NodeStatusProvider) -> Router {
+    Router::new()
+        .route("/metrics", get(get_metrics_handler))
+        .route("/status", get(get_status_handler))
+        .with_state(provider)
+}
+
+/// Serves the local prometheus metrics and status of the node until the process exits.
+pub async fn serve_status(addr: SocketAddr, provider: NodeStatusProvider) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Node status listening on: {}", addr);
+    axum::serve(listener, make_router(provider)).await?;
+    Ok(())
+}
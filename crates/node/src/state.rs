@@ -1,20 +1,64 @@
-use std::sync::Arc;
+use std::{fmt::Debug, sync::Arc};
 
-use super::{backend::get_backend_impl, config::NodeConfigFile, discover::discover_public_ip};
+use super::{
+    backend::get_backend_impl,
+    config::{NodeConfigFile, WireguardConfig},
+    discover::discover_public_ip,
+};
 use anyhow::Result;
-use log::info;
+use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use wgpull_shared::{
-    client::HttpClient, command::CommandExecutor, file::FileAccessor, request::NodePullRequest,
-    response::NodePullResponse, wg::WireguardCommand,
+    client::HttpClient,
+    command::CommandExecutor,
+    crypto::SecretKey,
+    file::FileAccessor,
+    headers::parse_revision_etag,
+    request::NodePullRequest,
+    response::{NodePullResponse, PeerEndpointRecord},
+    signing::{PublicKey, SignedDocument},
+    state::{read_versioned_state_file, serialize_versioned_state, SecretFields, StateMigration},
+    validation::Validated,
+    wg::WireguardCommand,
 };
 
 use thiserror::Error;
 
+/// Migrations of the node state file, see `StateMigration`.
+const NODE_STATE_MIGRATIONS: &[StateMigration] = &[migrate_v0_to_v1];
+
+/// Version 1 only introduced the versioned state file, the revision is optional.
+fn migrate_v0_to_v1(_state: &mut toml::Table) -> Result<(), String> {
+    Ok(())
+}
+
+/// Interval in seconds to save the time of the last pull to the state file if the peers didn't
+/// change, limits the writes to the state file (e.g. flash storage of routers).
+pub const LAST_PULL_REFRESH_SECONDS: u64 = 3600;
+
+/// Replaces the value of a state field with the configured value, logging the change.
+fn reconcile_field<T: PartialEq + Debug>(
+    name: &'static str,
+    value: &mut T,
+    configured: T,
+    changed: &mut Vec<&'static str>,
+) {
+    if *value != configured {
+        info!(
+            "Configured {} changed from {:?} to {:?}.",
+            name, value, configured
+        );
+        *value = configured;
+        changed.push(name);
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum NodeError {
     #[error("The configured backend is not compatible with this system")]
     BackendNotCompatible,
+    #[error("Signed pull response has revision {0}, older than the known revision {1}")]
+    OutdatedRevision(u64, u64),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,6 +87,20 @@ pub struct NodePeer {
     /// Whether or not the allowed ips should route through the wireguard interface.
     /// Indicates if routes should be added for each allowed_ip entry.
     pub route_allowed_ips: bool,
+
+    /// The endpoint of the peer signed by the lighthouse, exchanged with the other peers.
+    #[serde(default)]
+    pub signed_endpoint: Option<SignedDocument>,
+}
+
+impl NodePeer {
+    /// Returns the time the signed endpoint was issued at, 0 if there is none or it isn't valid.
+    fn endpoint_issued_at(&self, key: &PublicKey) -> u64 {
+        self.signed_endpoint
+            .as_ref()
+            .and_then(|signed| signed.verify::<PeerEndpointRecord>(key).ok())
+            .map_or(0, |record| record.issued_at)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -77,6 +135,24 @@ pub struct NodeState {
     /// Whether or not the allowed ips should route through the wireguard interface.
     /// Indicates if routes should be added for each allowed_ip entry.
     pub route_allowed_ips: bool,
+
+    /// Revision of the peer configuration last received from the lighthouse.
+    #[serde(default)]
+    pub revision: Option<u64>,
+
+    /// ETag of the pull response the peers were received with, sent back to the lighthouse so
+    /// it responds with not modified if the peers didn't change.
+    #[serde(default)]
+    pub etag: Option<String>,
+
+    /// Unix timestamp of the last successful pull, only refreshed in the state file every
+    /// `LAST_PULL_REFRESH_SECONDS` if the peers didn't change.
+    #[serde(default)]
+    pub last_pull: Option<u64>,
+
+    /// The endpoint of the node signed by the lighthouse, exchanged with the peers.
+    #[serde(default)]
+    pub signed_endpoint: Option<SignedDocument>,
 }
 
 impl From<NodeState> for NodePullRequest {
@@ -89,10 +165,19 @@ impl From<NodeState> for NodePullRequest {
             persistent_keepalive: state.persistent_keepalive,
             allowed_ips: state.allowed_ips,
             route_allowed_ips: state.route_allowed_ips,
+            last_apply: None,
         }
     }
 }
 
+impl SecretFields for NodeState {
+    fn secrets_mut(&mut self) -> Vec<&mut String> {
+        let mut secrets = vec![&mut self.private_key];
+        secrets.extend(self.peers.iter_mut().map(|peer| &mut peer.preshared_key));
+        secrets
+    }
+}
+
 impl NodeState {
     pub fn get_hostname_by_public_key(&self, public_key: &str) -> String {
         self.peers
@@ -135,34 +220,125 @@ impl NodeState {
 
         Ok(NodeState {
             endpoint,
-            address: config.wireguard.address.clone(),
+            address: config.wireguard.address.to_string(),
             hostname,
             private_key: keypair.private_key,
             public_key: keypair.public_key,
             listen_port: config.wireguard.listen_port,
             persistent_keepalive: config.wireguard.persistent_keepalive,
-            allowed_ips: config.wireguard.allowed_ips.clone(),
+            allowed_ips: config
+                .wireguard
+                .allowed_ips
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
             route_allowed_ips: config.wireguard.route_allowed_ips,
             peers: Vec::new(),
+            revision: None,
+            etag: None,
+            last_pull: None,
+            signed_endpoint: None,
         })
     }
 
+    /// Updates the fields owned by the wireguard configuration, keeping the fields owned by the
+    /// state (keys, peers and revision), returns the names of the changed fields.
+    ///
+    /// A discovered endpoint is kept as long as the endpoint is configured to be discovered.
+    pub fn reconcile_wireguard_config(&mut self, config: &WireguardConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        reconcile_field(
+            "address",
+            &mut self.address,
+            config.address.to_string(),
+            &mut changed,
+        );
+        if config.endpoint != "discover" {
+            reconcile_field(
+                "endpoint",
+                &mut self.endpoint,
+                config.endpoint.clone(),
+                &mut changed,
+            );
+        }
+        reconcile_field(
+            "listen_port",
+            &mut self.listen_port,
+            config.listen_port,
+            &mut changed,
+        );
+        reconcile_field(
+            "persistent_keepalive",
+            &mut self.persistent_keepalive,
+            config.persistent_keepalive,
+            &mut changed,
+        );
+        reconcile_field(
+            "allowed_ips",
+            &mut self.allowed_ips,
+            config.allowed_ips.iter().map(ToString::to_string).collect(),
+            &mut changed,
+        );
+        reconcile_field(
+            "route_allowed_ips",
+            &mut self.route_allowed_ips,
+            config.route_allowed_ips,
+            &mut changed,
+        );
+
+        changed
+    }
+
+    /// Removes the cached peers if the last successful pull is longer ago than the grace period
+    /// (0 keeps the peers forever), returns true if peers were removed.
+    ///
+    /// The revision is reset so the next successful pull receives the full configuration again.
+    pub fn expire_peers(&mut self, now: u64, grace_seconds: u64) -> bool {
+        let Some(last_pull) = self.last_pull else {
+            return false;
+        };
+        if grace_seconds == 0
+            || self.peers.is_empty()
+            || now.saturating_sub(last_pull) < grace_seconds
+        {
+            return false;
+        }
+        self.peers.clear();
+        self.revision = None;
+        self.etag = None;
+        true
+    }
+
+    /// Replaces the private and public key of the node with a newly generated key pair.
+    pub async fn regenerate_keys(&mut self, executor: Arc<dyn CommandExecutor>) -> Result<()> {
+        let keypair = WireguardCommand::new(executor.as_ref())
+            .generate_keypair()
+            .await?;
+        self.private_key = keypair.private_key;
+        self.public_key = keypair.public_key;
+        Ok(())
+    }
+
+    /// Replaces the peers with the peers of the pull response and its ETag and regenerates the
+    /// keys if requested, returns true if the keys were regenerated.
+    ///
+    /// The lighthouse doesn't know regenerated keys until the next pull, which the caller is
+    /// expected to do immediately to keep the time peers are disconnected short.
+    ///
+    /// Signed responses older than the known revision of the same epoch are rejected, so a
+    /// captured response can't be replayed to roll back the peers before it expires.
     pub async fn update_from_pull_response(
         &mut self,
         response: &NodePullResponse,
+        etag: Option<String>,
         executor: Arc<dyn CommandExecutor>,
-    ) -> Result<()> {
-        let wireguard_command = WireguardCommand::new(executor.as_ref());
+    ) -> Result<bool> {
+        self.check_pull_revision(response)?;
 
         if response.regenerate_keys {
             info!("Regenerating keys as requested by lighthouse.");
-            // generate new keys
-            let keypair = wireguard_command.generate_keypair().await?;
-            self.private_key = keypair.private_key;
-            self.public_key = keypair.public_key;
-            // TODO the generated keys will not be known by the lighthouse, so we need to do
-            //    another pull request after this one to update the peers. This is still not
-            //    optimal, because the peers will be disconnected for a short time (a few seconds).
+            self.regenerate_keys(executor).await?;
         }
 
         // update peer list from response
@@ -178,34 +354,367 @@ impl NodeState {
                 allowed_ips: peer.allowed_ips.clone(),
                 persistent_keepalive: peer.persistent_keepalive,
                 route_allowed_ips: peer.route_allowed_ips,
+                signed_endpoint: peer.signed_endpoint.clone(),
             })
             .collect();
+        self.revision = Some(response.revision);
+        self.etag = etag;
+        self.signed_endpoint = response.signed_endpoint.clone();
 
-        Ok(())
+        Ok(response.regenerate_keys)
     }
 
-    pub async fn from_file(path: &str, accessor: &dyn FileAccessor) -> Result<Option<NodeState>> {
-        info!("Restoring node state from {}", path);
-        let contents = match accessor.read(path).await {
-            Ok(file) => file,
-            Err(_) => return Ok(None),
-        };
+    /// Fails if the signed response is for an older revision than the known one of its epoch.
+    fn check_pull_revision(&self, response: &NodePullResponse) -> Result<(), NodeError> {
+        let known = self.etag.as_deref().and_then(parse_revision_etag);
+        match (response.epoch, known) {
+            (Some(epoch), Some((known_epoch, known_revision)))
+                if epoch == known_epoch && response.revision < known_revision =>
+            {
+                Err(NodeError::OutdatedRevision(
+                    response.revision,
+                    known_revision,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
 
-        let state = match toml::from_str(&contents) {
-            Ok(state) => state,
-            Err(_) => return Err(anyhow::anyhow!("Error parsing state file.")),
-        };
+    /// Returns the signed endpoints of the node and its peers, served to the peers.
+    pub fn endpoint_records(&self) -> Vec<SignedDocument> {
+        self.signed_endpoint
+            .iter()
+            .chain(
+                self.peers
+                    .iter()
+                    .filter_map(|peer| peer.signed_endpoint.as_ref()),
+            )
+            .cloned()
+            .collect()
+    }
+
+    /// Updates the endpoints of the peers from signed endpoints received from other peers,
+    /// returns the hostnames of the updated peers.
+    ///
+    /// Only records signed by the lighthouse that are newer than the known endpoint of a known
+    /// peer (matching hostname and public key) are used, others are ignored.
+    pub fn merge_endpoint_records(
+        &mut self,
+        records: &[SignedDocument],
+        key: &PublicKey,
+    ) -> Vec<String> {
+        let mut updated = Vec::new();
+        for signed in records {
+            let record = match signed.verify::<PeerEndpointRecord>(key) {
+                Ok(record) if record.validate().is_ok() => record,
+                Ok(_) => {
+                    debug!("Ignoring invalid endpoint record.");
+                    continue;
+                }
+                Err(err) => {
+                    debug!("Ignoring endpoint record: {}", err);
+                    continue;
+                }
+            };
+            let Some(peer) = self.peers.iter_mut().find(|peer| {
+                peer.hostname == record.hostname && peer.public_key == record.public_key
+            }) else {
+                continue;
+            };
+            if record.issued_at <= peer.endpoint_issued_at(key) {
+                continue;
+            }
 
-        Ok(Some(state))
+            if peer.endpoint_host != record.endpoint_host
+                || peer.endpoint_port != record.endpoint_port
+            {
+                info!(
+                    "Endpoint of peer {} changed from {}:{} to {}:{}.",
+                    peer.hostname,
+                    peer.endpoint_host,
+                    peer.endpoint_port,
+                    record.endpoint_host,
+                    record.endpoint_port
+                );
+                peer.endpoint_host = record.endpoint_host;
+                peer.endpoint_port = record.endpoint_port;
+                if !updated.contains(&peer.hostname) {
+                    updated.push(peer.hostname.clone());
+                }
+            }
+            peer.signed_endpoint = Some(signed.clone());
+        }
+        updated
+    }
+
+    /// Restores the state from disk, secrets encrypted in the state file are decrypted with the key.
+    pub async fn from_file(
+        path: &str,
+        accessor: &dyn FileAccessor,
+        key: Option<&SecretKey>,
+    ) -> Result<Option<NodeState>> {
+        info!("Restoring node state from {}", path);
+        let state = read_versioned_state_file(path, accessor, NODE_STATE_MIGRATIONS, key).await?;
+
+        Ok(state)
     }
 
-    pub async fn save(&self, path: &str, accessor: &dyn FileAccessor) -> Result<()> {
+    /// Saves the state to disk in TOML format, replacing the state file atomically.
+    /// Secrets are encrypted if a key is given.
+    pub async fn save(
+        &self,
+        path: &str,
+        accessor: &dyn FileAccessor,
+        key: Option<&SecretKey>,
+    ) -> Result<()> {
         info!("Saving node state to {}", path);
-        // Convert the state to a TOML string.
-        let contents = toml::to_string(self)?;
+        // Convert the state to a versioned TOML string.
+        let contents = serialize_versioned_state(self, NODE_STATE_MIGRATIONS, key)?;
         // Write the TOML string to the state file.
-        accessor.write(path, &contents).await?;
+        accessor.write_atomic(path, &contents).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wgpull_shared::{
+        crypto::SecretKey,
+        headers::format_revision_etag,
+        response::{NodePullResponse, PeerEndpointRecord},
+        signing::SigningKey,
+        state::{parse_versioned_state, serialize_versioned_state},
+    };
+
+    use super::{NodeError, NodeState, NODE_STATE_MIGRATIONS};
+    use crate::config::WireguardConfig;
+
+    #[test]
+    fn test_state_migrate_v0_fixture() {
+        let v0 = include_str!("../fixtures/node_state_v0.toml");
+        let (state, version) =
+            parse_versioned_state::<NodeState>("state", v0, NODE_STATE_MIGRATIONS, None).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(state.hostname, "node1");
+        assert_eq!(state.listen_port, 52720);
+        assert_eq!(state.revision, None);
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[0].hostname, "node2");
+
+        let migrated = serialize_versioned_state(&state, NODE_STATE_MIGRATIONS, None).unwrap();
+        assert!(migrated.starts_with("version = 1\n"));
+        let (restored, version) =
+            parse_versioned_state::<NodeState>("state", &migrated, NODE_STATE_MIGRATIONS, None)
+                .unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(restored.public_key, state.public_key);
+        assert_eq!(
+            restored.peers[0].preshared_key,
+            state.peers[0].preshared_key
+        );
+    }
+
+    #[test]
+    fn test_state_encrypted_secrets() {
+        let v0 = include_str!("../fixtures/node_state_v0.toml");
+        let (state, _) =
+            parse_versioned_state::<NodeState>("state", v0, NODE_STATE_MIGRATIONS, None).unwrap();
+        let key = SecretKey::from_material("key").unwrap();
+
+        let encrypted =
+            serialize_versioned_state(&state, NODE_STATE_MIGRATIONS, Some(&key)).unwrap();
+        assert!(!encrypted.contains(&state.private_key));
+        assert!(!encrypted.contains(&state.peers[0].preshared_key));
+        assert!(encrypted.contains(&state.public_key));
+
+        let (restored, _) = parse_versioned_state::<NodeState>(
+            "state",
+            &encrypted,
+            NODE_STATE_MIGRATIONS,
+            Some(&key),
+        )
+        .unwrap();
+        assert_eq!(restored.private_key, state.private_key);
+        assert_eq!(
+            restored.peers[0].preshared_key,
+            state.peers[0].preshared_key
+        );
+    }
+
+    fn fixture_state() -> NodeState {
+        let v0 = include_str!("../fixtures/node_state_v0.toml");
+        parse_versioned_state::<NodeState>("state", v0, NODE_STATE_MIGRATIONS, None)
+            .unwrap()
+            .0
+    }
+
+    /// Wireguard configuration matching the fixture state, with the replaced values.
+    fn wireguard_config(replace: &[(&str, &str)]) -> WireguardConfig {
+        let mut config = r#"
+            backend = "systemd"
+            address = "10.140.0.10/24"
+            endpoint = "203.0.113.10"
+            listen_port = 52720
+            persistent_keepalive = 15
+            allowed_ips = ["10.140.0.10/32", "10.10.0.0/16"]
+            route_allowed_ips = true
+            "#
+        .to_string();
+        for (from, to) in replace {
+            config = config.replace(from, to);
+        }
+        toml::from_str(&config).unwrap()
+    }
+
+    /// Reconciles the configuration into the fixture state, asserting only the field changed.
+    fn reconcile(replace: &[(&str, &str)], field: &str) -> NodeState {
+        let mut state = fixture_state();
+        assert!(state
+            .reconcile_wireguard_config(&wireguard_config(&[]))
+            .is_empty());
+
+        let changed = state.reconcile_wireguard_config(&wireguard_config(replace));
+        assert_eq!(changed, vec![field]);
+        // keys, peers and revision are owned by the state
+        let fixture = fixture_state();
+        assert_eq!(state.private_key, fixture.private_key);
+        assert_eq!(state.public_key, fixture.public_key);
+        assert_eq!(state.peers.len(), fixture.peers.len());
+        assert_eq!(state.revision, fixture.revision);
+        state
+    }
+
+    #[test]
+    fn test_expire_peers() {
+        let mut state = fixture_state();
+        state.revision = Some(7);
+        // states saved before the time of the last pull was stored never expire
+        assert!(!state.expire_peers(10_000, 3600));
+
+        state.last_pull = Some(1_000);
+        assert!(!state.expire_peers(10_000, 0));
+        assert!(!state.expire_peers(4_599, 3600));
+        assert_eq!(state.peers.len(), 1);
+
+        assert!(state.expire_peers(4_600, 3600));
+        assert!(state.peers.is_empty());
+        assert_eq!(state.revision, None);
+        // nothing left to expire
+        assert!(!state.expire_peers(4_600, 3600));
+    }
+
+    #[test]
+    fn test_check_pull_revision() {
+        let response = |epoch: Option<u64>, revision: u64| NodePullResponse {
+            regenerate_keys: false,
+            peers: vec![],
+            revision,
+            signed_endpoint: None,
+            hostname: None,
+            expires_at: None,
+            epoch,
+        };
+        let mut state = fixture_state();
+        assert!(state.check_pull_revision(&response(Some(1), 0)).is_ok());
+
+        state.etag = Some(format_revision_etag(1, 5));
+        assert!(state.check_pull_revision(&response(Some(1), 5)).is_ok());
+        assert!(state.check_pull_revision(&response(Some(1), 6)).is_ok());
+        assert!(matches!(
+            state.check_pull_revision(&response(Some(1), 4)),
+            Err(NodeError::OutdatedRevision(4, 5))
+        ));
+        // revisions of another epoch are not comparable
+        assert!(state.check_pull_revision(&response(Some(2), 0)).is_ok());
+        // unsigned responses are not checked
+        assert!(state.check_pull_revision(&response(None, 0)).is_ok());
+    }
+
+    #[test]
+    fn test_merge_endpoint_records() {
+        let key = SigningKey::from_material("signing").unwrap();
+        let record = |hostname: &str, endpoint_host: &str, issued_at: u64| {
+            key.sign(&PeerEndpointRecord {
+                hostname: hostname.to_string(),
+                public_key: "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=".to_string(),
+                endpoint_host: endpoint_host.to_string(),
+                endpoint_port: 52720,
+                issued_at,
+            })
+            .unwrap()
+        };
+        let mut state = fixture_state();
+        state.peers[0].signed_endpoint = Some(record("node2", "node2.example.com", 100));
+        assert_eq!(state.endpoint_records().len(), 1);
+
+        // older records, unknown peers and records signed by other keys are ignored
+        let forged = SigningKey::from_material("forged")
+            .unwrap()
+            .sign(&PeerEndpointRecord {
+                hostname: "node2".to_string(),
+                public_key: "KCaw+KVpzD663MNBZl4Rio/B914T5G2z0QbG8LMSBFQ=".to_string(),
+                endpoint_host: "203.0.113.66".to_string(),
+                endpoint_port: 52720,
+                issued_at: 300,
+            })
+            .unwrap();
+        let records = vec![
+            record("node2", "203.0.113.20", 50),
+            record("node3", "203.0.113.30", 200),
+            forged,
+        ];
+        assert!(state
+            .merge_endpoint_records(&records, &key.public_key())
+            .is_empty());
+        assert_eq!(state.peers[0].endpoint_host, "node2.example.com");
+
+        let newer = record("node2", "203.0.113.20", 200);
+        let updated = state.merge_endpoint_records(std::slice::from_ref(&newer), &key.public_key());
+        assert_eq!(updated, vec!["node2"]);
+        assert_eq!(state.peers[0].endpoint_host, "203.0.113.20");
+        assert_eq!(state.peers[0].signed_endpoint, Some(newer));
+    }
+
+    #[test]
+    fn test_reconcile_address() {
+        let state = reconcile(&[("10.140.0.10/24", "10.150.0.10/24")], "address");
+        assert_eq!(state.address, "10.150.0.10/24");
+    }
+
+    #[test]
+    fn test_reconcile_endpoint() {
+        let state = reconcile(&[("203.0.113.10", "node1.example.com")], "endpoint");
+        assert_eq!(state.endpoint, "node1.example.com");
+
+        // a discovered endpoint is kept
+        let mut state = fixture_state();
+        let config = wireguard_config(&[("203.0.113.10", "discover")]);
+        assert!(state.reconcile_wireguard_config(&config).is_empty());
+        assert_eq!(state.endpoint, "203.0.113.10");
+    }
+
+    #[test]
+    fn test_reconcile_listen_port() {
+        let state = reconcile(&[("52720", "51820")], "listen_port");
+        assert_eq!(state.listen_port, 51820);
+    }
+
+    #[test]
+    fn test_reconcile_persistent_keepalive() {
+        let state = reconcile(&[("= 15", "= 25")], "persistent_keepalive");
+        assert_eq!(state.persistent_keepalive, 25);
+    }
+
+    #[test]
+    fn test_reconcile_allowed_ips() {
+        let state = reconcile(&[("10.10.0.0/16", "10.20.0.0/16")], "allowed_ips");
+        assert_eq!(state.allowed_ips, vec!["10.140.0.10/32", "10.20.0.0/16"]);
+    }
+
+    #[test]
+    fn test_reconcile_route_allowed_ips() {
+        let state = reconcile(&[("= true", "= false")], "route_allowed_ips");
+        assert!(!state.route_allowed_ips);
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use wgpull_shared::{
+    prometheus::PrometheusExport, request::NodeApplyStatus, signing::SignedDocument,
+    time::unix_timestamp, wg::WireguardInfo,
+};
+
+use super::state::NodeState;
+
+/// Status of the node, updated by the pull and metrics loop and served by the status endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct NodeStatus {
+    /// The local hostname of the node.
+    pub hostname: String,
+
+    /// Revision of the peer configuration last received from the lighthouse.
+    pub revision: Option<u64>,
+
+    /// Time of the last pull attempt.
+    pub last_pull: Option<SystemTime>,
+
+    /// Error of the last pull attempt, none if it succeeded.
+    pub last_pull_error: Option<String>,
+
+    /// Result of the last apply of the peer configuration.
+    pub last_apply: Option<NodeApplyStatus>,
+
+    /// Whether or not the last request to the lighthouse succeeded.
+    pub lighthouse_reachable: bool,
+
+    /// Time of the last successful request to the lighthouse.
+    pub lighthouse_last_contact: Option<SystemTime>,
+
+    /// Hostnames of the peers by their public key.
+    pub peer_hostnames: HashMap<String, String>,
+
+    /// Signed endpoints of the node and its peers, served to the peers by the gossip endpoint.
+    pub endpoint_records: Vec<SignedDocument>,
+}
+
+impl NodeStatus {
+    /// Creates the initial status from the loaded state, before the first pull.
+    pub fn new(state: &NodeState) -> Self {
+        Self {
+            hostname: state.hostname.clone(),
+            revision: state.revision,
+            lighthouse_last_contact: state
+                .last_pull
+                .map(|last_pull| UNIX_EPOCH + Duration::from_secs(last_pull)),
+            endpoint_records: state.endpoint_records(),
+            ..Default::default()
+        }
+    }
+
+    /// Records the result of a request to the lighthouse.
+    pub fn record_lighthouse_request(&mut self, success: bool, now: SystemTime) {
+        self.lighthouse_reachable = success;
+        if success {
+            self.lighthouse_last_contact = Some(now);
+        }
+    }
+
+    /// Returns the hostname of the peer with the public key, `unknown` if it isn't a known peer.
+    fn peer_hostname(&self, public_key: &str) -> &str {
+        self.peer_hostnames
+            .get(public_key)
+            .map(String::as_str)
+            .unwrap_or("unknown")
+    }
+}
+
+/// Wireguard status of a peer as exposed by the status endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodePeerStatusResponse {
+    pub hostname: String,
+    pub public_key: String,
+    pub endpoint: String,
+    pub latest_handshake: u64,
+    pub transfer_rx: i64,
+    pub transfer_tx: i64,
+}
+
+/// Status of the node as exposed by the status endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStatusResponse {
+    pub hostname: String,
+    pub revision: Option<u64>,
+    /// Time of the last pull attempt (unix timestamp).
+    pub last_pull: Option<u64>,
+    pub last_pull_error: Option<String>,
+    pub last_apply: Option<NodeApplyStatus>,
+    pub lighthouse_reachable: bool,
+    /// Time of the last successful request to the lighthouse (unix timestamp).
+    pub lighthouse_last_contact: Option<u64>,
+    /// The wireguard interface, none if it isn't up.
+    pub interface: Option<String>,
+    pub peers: Vec<NodePeerStatusResponse>,
+}
+
+impl NodeStatusResponse {
+    pub fn new(status: &NodeStatus, info: Option<&WireguardInfo>) -> Self {
+        Self {
+            hostname: status.hostname.clone(),
+            revision: status.revision,
+            last_pull: status.last_pull.map(unix_timestamp),
+            last_pull_error: status.last_pull_error.clone(),
+            last_apply: status.last_apply.clone(),
+            lighthouse_reachable: status.lighthouse_reachable,
+            lighthouse_last_contact: status.lighthouse_last_contact.map(unix_timestamp),
+            interface: info.map(|info| info.interface.clone()),
+            peers: info
+                .map(|info| {
+                    info.peers
+                        .iter()
+                        .map(|peer| NodePeerStatusResponse {
+                            hostname: status.peer_hostname(&peer.public_key).to_string(),
+                            public_key: peer.public_key.clone(),
+                            endpoint: peer.endpoint.clone(),
+                            latest_handshake: peer.latest_handshake,
+                            transfer_rx: peer.transfer_rx,
+                            transfer_tx: peer.transfer_tx,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Export the node status and local wireguard metrics for prometheus.
+pub fn export_prometheus(status: &NodeStatus, info: Option<&WireguardInfo>) -> String {
+    let mut export = PrometheusExport::default();
+
+    export.family(
+        "wgpull_node_lighthouse_reachable",
+        "gauge",
+        "Whether the last request to the lighthouse succeeded.",
+    );
+    export.sample(
+        "wgpull_node_lighthouse_reachable",
+        &[],
+        u8::from(status.lighthouse_reachable),
+    );
+
+    export.family(
+        "wgpull_node_lighthouse_last_contact_timestamp_seconds",
+        "gauge",
+        "Unix timestamp of the last successful request to the lighthouse.",
+    );
+    if let Some(last_contact) = status.lighthouse_last_contact {
+        export.sample(
+            "wgpull_node_lighthouse_last_contact_timestamp_seconds",
+            &[],
+            unix_timestamp(last_contact),
+        );
+    }
+
+    export.family(
+        "wgpull_node_last_pull_success",
+        "gauge",
+        "Whether the last pull of the peer configuration succeeded.",
+    );
+    if status.last_pull.is_some() {
+        export.sample(
+            "wgpull_node_last_pull_success",
+            &[],
+            u8::from(status.last_pull_error.is_none()),
+        );
+    }
+
+    export.family(
+        "wgpull_node_revision",
+        "gauge",
+        "Revision of the peer configuration last received from the lighthouse.",
+    );
+    if let Some(revision) = status.revision {
+        export.sample("wgpull_node_revision", &[], revision);
+    }
+
+    export.family(
+        "wgpull_node_apply_success",
+        "gauge",
+        "Whether the last configuration was applied successfully.",
+    );
+    if let Some(apply) = &status.last_apply {
+        export.sample(
+            "wgpull_node_apply_success",
+            &[("backend", &apply.backend)],
+            u8::from(apply.success),
+        );
+    }
+
+    export.family(
+        "wgpull_node_wireguard_up",
+        "gauge",
+        "Whether the wireguard interface is up.",
+    );
+    export.sample("wgpull_node_wireguard_up", &[], u8::from(info.is_some()));
+
+    let peers = info.map(|info| info.peers.as_slice()).unwrap_or_default();
+
+    export.family(
+        "wgpull_node_peer_latest_handshake",
+        "gauge",
+        "Unix timestamp of the latest handshake with the peer, 0 if there was none.",
+    );
+    for peer in peers {
+        export.sample(
+            "wgpull_node_peer_latest_handshake",
+            &[("peer_hostname", status.peer_hostname(&peer.public_key))],
+            peer.latest_handshake,
+        );
+    }
+
+    export.family(
+        "wgpull_node_peer_transfer_rx",
+        "counter",
+        "Bytes received from the peer.",
+    );
+    for peer in peers {
+        export.sample(
+            "wgpull_node_peer_transfer_rx",
+            &[("peer_hostname", status.peer_hostname(&peer.public_key))],
+            peer.transfer_rx,
+        );
+    }
+
+    export.family(
+        "wgpull_node_peer_transfer_tx",
+        "counter",
+        "Bytes sent to the peer.",
+    );
+    for peer in peers {
+        export.sample(
+            "wgpull_node_peer_transfer_tx",
+            &[("peer_hostname", status.peer_hostname(&peer.public_key))],
+            peer.transfer_tx,
+        );
+    }
+
+    export.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    use wgpull_shared::{
+        request::NodeApplyStatus,
+        wg::{PeerInfo, WireguardInfo},
+    };
+
+    use super::{export_prometheus, NodeStatus, NodeStatusResponse};
+
+    #[test]
+    fn test_export_node_status() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_720_000_000);
+        let mut status = NodeStatus {
+            hostname: "node1".to_string(),
+            revision: Some(4),
+            last_pull: Some(now),
+            last_pull_error: Some("lighthouse unreachable".to_string()),
+            last_apply: Some(NodeApplyStatus {
+                success: true,
+                error: None,
+                revision: Some(4),
+                backend: "systemd".to_string(),
+            }),
+            peer_hostnames: HashMap::from([("pubkey2".to_string(), "node2".to_string())]),
+            ..Default::default()
+        };
+        status.record_lighthouse_request(true, now - Duration::from_secs(60));
+        status.record_lighthouse_request(false, now);
+        let info = WireguardInfo {
+            interface: "wg0".to_string(),
+            public_key: "pubkey1".to_string(),
+            private_key: "privkey1".to_string(),
+            listening_port: 52720,
+            peers: vec![PeerInfo {
+                interface: "wg0".to_string(),
+                public_key: "pubkey2".to_string(),
+                private_key: "(none)".to_string(),
+                endpoint: "203.0.113.2:52720".to_string(),
+                allowed_ips: "10.140.0.11/32".to_string(),
+                latest_handshake: 1_719_999_990,
+                transfer_rx: 1000,
+                transfer_tx: 2000,
+                persistent_keepalive: 15,
+            }],
+        };
+
+        let export = export_prometheus(&status, Some(&info));
+        assert!(export.contains("wgpull_node_lighthouse_reachable 0\n"));
+        assert!(
+            export.contains("wgpull_node_lighthouse_last_contact_timestamp_seconds 1719999940\n")
+        );
+        assert!(export.contains("wgpull_node_last_pull_success 0\n"));
+        assert!(export.contains("wgpull_node_revision 4\n"));
+        assert!(export.contains("wgpull_node_apply_success{backend=\"systemd\"} 1\n"));
+        assert!(export.contains("wgpull_node_wireguard_up 1\n"));
+        assert!(export.contains("wgpull_node_peer_transfer_rx{peer_hostname=\"node2\"} 1000\n"));
+
+        let export = export_prometheus(&status, None);
+        assert!(export.contains("wgpull_node_wireguard_up 0\n"));
+        assert!(!export.contains("wgpull_node_peer_transfer_rx{"));
+
+        let response = NodeStatusResponse::new(&status, Some(&info));
+        assert_eq!(response.last_pull, Some(1_720_000_000));
+        assert_eq!(response.peers[0].hostname, "node2");
+    }
+}
@@ -0,0 +1,68 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{info, warn};
+use tokio::{
+    sync::{watch, Notify},
+    time::Instant,
+};
+use wgpull_shared::{client::HttpClient, request::NodeWatchRequest};
+
+use super::{agent::NodeAgent, config::NodeConfig};
+
+/// Minimum time between watch requests the lighthouse answered early without changes.
+const MIN_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watches the lighthouse for changes of the peer configuration of this node.
+///
+/// Keeps a long-poll request open to the lighthouse and notifies the node loop to pull
+/// immediately whenever the lighthouse reports a change. The revision receiver is updated
+/// by the node loop after every pull. On errors the watcher waits a pull interval before
+/// trying again, the periodic pull of the node loop is the fallback.
+pub async fn watch_lighthouse(
+    config: NodeConfig,
+    hostname: String,
+    http_client: Arc<dyn HttpClient + Send + Sync>,
+    mut revision: watch::Receiver<Option<u64>>,
+    pull_now: Arc<Notify>,
+) {
+    let retry_interval = Duration::from_secs(config.pull_interval as u64);
+
+    loop {
+        let agent = match NodeAgent::from_node_config(&config, http_client.as_ref()) {
+            Ok(agent) => agent,
+            Err(err) => {
+                warn!("Failed to create agent to watch lighthouse: {}", err);
+                return;
+            }
+        };
+
+        let request = NodeWatchRequest {
+            hostname: hostname.clone(),
+            revision: *revision.borrow_and_update(),
+            timeout: config.watch_timeout as u64,
+        };
+
+        let timeout = Duration::from_secs(request.timeout);
+        let started = Instant::now();
+        match agent.watch(request).await {
+            Ok(true) => {
+                info!("Lighthouse reported changed peers, pulling now.");
+                pull_now.notify_one();
+                // wait for the pull to finish before watching again
+                let _ = tokio::time::timeout(retry_interval, revision.changed()).await;
+            }
+            Ok(false) => {
+                // a lighthouse with a shorter watch timeout answers early, don't poll it in a
+                // tight loop that gets the node rate limited
+                let elapsed = started.elapsed();
+                if elapsed < timeout / 2 {
+                    tokio::time::sleep(MIN_WATCH_INTERVAL.saturating_sub(elapsed)).await;
+                }
+            }
+            Err(err) => {
+                warn!("Failed to watch lighthouse for changes: {}", err);
+                tokio::time::sleep(retry_interval).await;
+            }
+        }
+    }
+}
@@ -2,6 +2,8 @@ use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use sha2::{Digest, Sha256};
 
+use crate::crypto::verify_key;
+
 pub struct ChallengeResponse {
     secret: String,
     challenge: String,
@@ -36,7 +38,7 @@ impl ChallengeResponse {
     }
 
     pub fn verify(&self, response: &str) -> bool {
-        self.hash(&self.challenge) == response
+        verify_key(response, &self.hash(&self.challenge))
     }
 
     pub fn response(&self) -> String {
@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use log::debug;
 use std::process::Stdio;
-use tokio::io::{AsyncWriteExt, Error, ErrorKind, Result};
+use tokio::io::{AsyncWriteExt, Error, Result};
 use tokio::process::Command;
 
 /// CommandExecutor Return type: (stdout, stderr)
@@ -37,10 +37,7 @@ async fn decode_output(child: tokio::process::Child) -> Result<Output> {
         debug!("decode_output stderr: {:?}", stderr);
         Ok((stdout, stderr))
     } else {
-        Err(Error::new(
-            ErrorKind::Other,
-            "Command returned non-zero exit code.",
-        ))
+        Err(Error::other("Command returned non-zero exit code."))
     }
 }
 
@@ -1,8 +1,21 @@
 use serde::de::DeserializeOwned;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
+use toml::{Table, Value};
+
+use super::validation::ValidationError;
+
+/// Prefix of environment variables overriding configuration values.
+const ENV_PREFIX: &str = "WGPULL_";
+
+/// Separator of the nested keys in environment variable names, e.g. `WGPULL_NODE__NODE_KEY`.
+const ENV_SEPARATOR: &str = "__";
+
+/// Suffix of keys that read the value of a secret from a file.
+const SECRET_FILE_SUFFIX: &str = "_file";
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
@@ -12,29 +25,298 @@ pub enum ConfigError {
     ParsingConfigFile(String),
     #[error("Error discovering configuration file: {0}")]
     ConfigDiscovery(String),
+    #[error("Error reading secret {0} from file: {1}")]
+    ReadingSecretFile(String, String),
+    #[error("Invalid configuration:{}", format_problems(.0))]
+    InvalidConfig(Vec<ConfigProblem>),
+}
+
+/// A problem with a configuration value, reported with its TOML key path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigProblem {
+    /// Dotted key path of the value, e.g. `wireguard.allowed_ips[1]`.
+    pub key: String,
+    /// Line of the value in the configuration file, none if it isn't set in the file.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} (line {}): {}", self.key, line, self.message),
+            None => write!(f, "{}: {}", self.key, self.message),
+        }
+    }
+}
+
+fn format_problems(problems: &[ConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(|problem| format!("\n  {}", problem))
+        .collect()
+}
+
+/// Collects all problems found while validating a configuration.
+#[derive(Debug, Default)]
+pub struct ConfigProblems {
+    problems: Vec<ConfigProblem>,
+}
+
+impl ConfigProblems {
+    /// Adds a problem with the value of the key.
+    pub fn push(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(ConfigProblem {
+            key: key.into(),
+            line: None,
+            message: message.into(),
+        });
+    }
+
+    /// Adds the validation error of the value of the key, if any.
+    pub fn check(&mut self, key: impl Into<String>, result: Result<(), ValidationError>) {
+        if let Err(err) = result {
+            self.push(key, err.to_string());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A configuration file, loaded by `load_config`.
+pub trait ConfigFile: DeserializeOwned {
+    /// Dotted keys of secret values, each secret can also be read from the file configured
+    /// in the key with a `_file` suffix, e.g. `node.node_key_file`.
+    const SECRET_KEYS: &'static [&'static str];
+
+    /// Validates the configuration, adding every problem found with its key path.
+    fn check(&self, problems: &mut ConfigProblems);
 }
 
 /// Load the configuration from a file.
+///
+/// Values can be overridden by `WGPULL_` prefixed environment variables, with nested keys
+/// separated by two underscores (e.g. `WGPULL_NODE__LIGHTHOUSE_KEY`), secrets are read
+/// from files configured by their `_file` variant.
 pub fn load_config<T>(path: &Path) -> Result<T, ConfigError>
 where
-    T: DeserializeOwned,
+    T: ConfigFile,
 {
     let mut file = File::open(path).map_err(|e| ConfigError::LoadingConfigFile(e.to_string()))?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)
         .map_err(|e| ConfigError::LoadingConfigFile(e.to_string()))?;
 
-    let config: T =
-        toml::from_str(&contents).map_err(|e| ConfigError::ParsingConfigFile(e.to_string()))?;
+    parse_config(&contents, std::env::vars(), |path| {
+        std::fs::read_to_string(path).map_err(|err| err.to_string())
+    })
+}
+
+/// Parses and validates the configuration, all problems are reported at once with the
+/// key path and line number of the values.
+pub fn parse_config<T>(
+    contents: &str,
+    vars: impl Iterator<Item = (String, String)>,
+    read_file: impl Fn(&str) -> Result<String, String>,
+) -> Result<T, ConfigError>
+where
+    T: ConfigFile,
+{
+    let mut table: Table =
+        toml::from_str(contents).map_err(|e| ConfigError::ParsingConfigFile(e.to_string()))?;
+    apply_env_overrides(&mut table, vars);
+    resolve_secret_files(&mut table, T::SECRET_KEYS, read_file)?;
+
+    let with_lines = |problems: Vec<ConfigProblem>| {
+        let lines = toml_edit::ImDocument::parse(contents).ok();
+        problems
+            .into_iter()
+            .map(|mut problem| {
+                problem.line = lines
+                    .as_ref()
+                    .and_then(|document| config_line(contents, document.as_item(), &problem.key));
+                problem
+            })
+            .collect()
+    };
+
+    let config: T = serde_path_to_error::deserialize(Value::Table(table)).map_err(|err| {
+        let key = match err.path().iter().next() {
+            Some(_) => err.path().to_string(),
+            None => "(root)".to_string(),
+        };
+        ConfigError::InvalidConfig(with_lines(vec![ConfigProblem {
+            key,
+            line: None,
+            message: err.inner().message().trim().to_string(),
+        }]))
+    })?;
+
+    let mut problems = ConfigProblems::default();
+    config.check(&mut problems);
+    if !problems.is_empty() {
+        return Err(ConfigError::InvalidConfig(with_lines(problems.problems)));
+    }
 
     Ok(config)
 }
 
+/// Returns the line of the value of a dotted key path (e.g. `wireguard.allowed_ips[1]`),
+/// none if the key isn't set in the document.
+fn config_line(contents: &str, root: &toml_edit::Item, key: &str) -> Option<usize> {
+    let mut table = root.as_table_like();
+    let mut span = None;
+    for segment in key.split('.') {
+        let (name, index) = match segment.split_once('[') {
+            Some((name, index)) => (name, index.trim_end_matches(']').parse::<usize>().ok()),
+            None => (segment, None),
+        };
+        let (key, value) = table?.get_key_value(name)?;
+        span = key.span().or(span);
+        table = value.as_table_like();
+        if let Some(index) = index {
+            // elements of arrays of tables (`[[section]]`) can be descended into
+            match value.as_array_of_tables() {
+                Some(tables) => {
+                    let element = tables.get(index)?;
+                    span = element.span().or(span);
+                    table = Some(element);
+                }
+                None => {
+                    let element = value.as_array()?.get(index)?;
+                    span = element.span().or(span);
+                    table = element
+                        .as_inline_table()
+                        .map(|element| element as &dyn toml_edit::TableLike);
+                }
+            }
+        }
+    }
+    let start = span?.start;
+    Some(contents[..start].matches('\n').count() + 1)
+}
+
+/// Returns the nested table of the keys, missing tables are created if requested.
+fn nested_table<'a>(table: &'a mut Table, keys: &[&str], create: bool) -> Option<&'a mut Table> {
+    let Some((first, rest)) = keys.split_first() else {
+        return Some(table);
+    };
+    let entry = if create {
+        table
+            .entry(first.to_string())
+            .or_insert_with(|| Value::Table(Table::new()))
+    } else {
+        table.get_mut(*first)?
+    };
+    match entry {
+        Value::Table(table) => nested_table(table, rest, create),
+        _ => None,
+    }
+}
+
+/// Parses the value of an environment variable, existing string values stay strings,
+/// other values are parsed as TOML values (numbers, booleans, arrays) if possible.
+fn parse_env_value(existing: Option<&Value>, value: &str) -> Value {
+    if let Some(Value::String(_)) = existing {
+        return Value::String(value.to_string());
+    }
+    toml::from_str::<Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+/// Overrides configuration values with `WGPULL_` prefixed environment variables.
+///
+/// Only variables with nested keys of existing top-level sections are applied, setting a
+/// secret replaces its `_file` variant and vice versa.
+pub fn apply_env_overrides(table: &mut Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, value) in vars {
+        let Some(name) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<String> = name
+            .split(ENV_SEPARATOR)
+            .map(|key| key.to_lowercase())
+            .collect();
+        if keys.len() < 2 || keys.iter().any(|key| key.is_empty()) {
+            continue;
+        }
+        let Some(Value::Table(section)) = table.get_mut(&keys[0]) else {
+            continue;
+        };
+        let (key, parents) = keys[1..].split_last().unwrap();
+        let parents: Vec<&str> = parents.iter().map(String::as_str).collect();
+        let Some(section) = nested_table(section, &parents, true) else {
+            continue;
+        };
+
+        match key.strip_suffix(SECRET_FILE_SUFFIX) {
+            Some(secret) => section.remove(secret),
+            None => section.remove(&format!("{}{}", key, SECRET_FILE_SUFFIX)),
+        };
+        let value = parse_env_value(section.get(key), &value);
+        section.insert(key.clone(), value);
+    }
+}
+
+/// Replaces the `_file` variants of the secrets with the contents of the files.
+pub fn resolve_secret_files(
+    table: &mut Table,
+    secret_keys: &[&str],
+    read_file: impl Fn(&str) -> Result<String, String>,
+) -> Result<(), ConfigError> {
+    for secret_key in secret_keys {
+        let mut keys: Vec<&str> = secret_key.split('.').collect();
+        let key = keys.pop().unwrap();
+        let Some(section) = nested_table(table, &keys, false) else {
+            continue;
+        };
+
+        let file_key = format!("{}{}", key, SECRET_FILE_SUFFIX);
+        let Some(path) = section.remove(&file_key) else {
+            continue;
+        };
+        let Value::String(path) = path else {
+            return Err(ConfigError::ReadingSecretFile(
+                secret_key.to_string(),
+                format!("{} is not a path", file_key),
+            ));
+        };
+        if section.contains_key(key) {
+            return Err(ConfigError::ReadingSecretFile(
+                secret_key.to_string(),
+                format!("both {} and {} are set", key, file_key),
+            ));
+        }
+        let secret = read_file(&path)
+            .map_err(|err| ConfigError::ReadingSecretFile(secret_key.to_string(), err))?;
+        section.insert(key.to_string(), Value::String(secret.trim().to_string()));
+    }
+    Ok(())
+}
+
 /// Discover the location of the wgpull configuration file.
 ///
-/// Returns /etc/wgpull.conf if it exists, or ./wgpull.conf if it
+/// Returns the explicitly configured path (`--config` or `WGPULL_CONFIG`) if given,
+/// otherwise /etc/wgpull/<filename> if it exists, or ./<filename> if it
 /// exists in the current working directory.
-pub fn discover_config_path(filename: &'static str) -> Result<PathBuf, ConfigError> {
+pub fn discover_config_path(
+    filename: &'static str,
+    explicit: Option<PathBuf>,
+) -> Result<PathBuf, ConfigError> {
+    if let Some(path) = explicit {
+        if path.exists() {
+            return Ok(path);
+        }
+        return Err(ConfigError::ConfigDiscovery(format!(
+            "Could not find {}",
+            path.display()
+        )));
+    }
+
     let paths = vec!["/etc/wgpull", "."];
 
     for path in paths {
@@ -49,3 +331,202 @@ pub fn discover_config_path(filename: &'static str) -> Result<PathBuf, ConfigErr
         filename
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use toml::{Table, Value};
+
+    use serde::Deserialize;
+
+    use super::{
+        apply_env_overrides, parse_config, resolve_secret_files, ConfigError, ConfigFile,
+        ConfigProblems,
+    };
+    use crate::validation::validate_hostname;
+
+    #[derive(Debug, Deserialize)]
+    struct TestConfigFile {
+        test: TestConfig,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct TestConfig {
+        hostname: String,
+        peers: Vec<String>,
+        port: u16,
+    }
+
+    impl ConfigFile for TestConfigFile {
+        const SECRET_KEYS: &'static [&'static str] = &[];
+
+        fn check(&self, problems: &mut ConfigProblems) {
+            problems.check(
+                "test.hostname",
+                validate_hostname("hostname", &self.test.hostname),
+            );
+            for (index, peer) in self.test.peers.iter().enumerate() {
+                problems.check(
+                    format!("test.peers[{}]", index),
+                    validate_hostname("peer", peer),
+                );
+            }
+        }
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_apply_env_overrides() {
+        let mut table: Table = toml::from_str(
+            r#"
+            [node]
+            lighthouse_key = "change_me"
+            node_key_file = "/etc/wgpull/node_key"
+            pull_interval = 30
+            watch = false
+            "#,
+        )
+        .unwrap();
+
+        apply_env_overrides(
+            &mut table,
+            vars(&[
+                ("WGPULL_NODE__LIGHTHOUSE_KEY", "12345"),
+                ("WGPULL_NODE__NODE_KEY", "secret"),
+                ("WGPULL_NODE__PULL_INTERVAL", "60"),
+                ("WGPULL_NODE__WATCH", "true"),
+                (
+                    "WGPULL_NODE__LIGHTHOUSE_URLS",
+                    r#"["http://a/", "http://b/"]"#,
+                ),
+                ("WGPULL_NODE__REPLICATION__KEY", "nested"),
+                ("WGPULL_LIGHTHOUSE__PORT", "2001"),
+                ("WGPULL_CONFIG", "/etc/wgpull/node.toml"),
+                ("HOME", "/root"),
+            ]),
+        );
+
+        let node = table["node"].as_table().unwrap();
+        // existing strings stay strings, new values are parsed
+        assert_eq!(node["lighthouse_key"], Value::String("12345".to_string()));
+        assert_eq!(node["node_key"], Value::String("secret".to_string()));
+        assert!(!node.contains_key("node_key_file"));
+        assert_eq!(node["pull_interval"], Value::Integer(60));
+        assert_eq!(node["watch"], Value::Boolean(true));
+        assert_eq!(node["lighthouse_urls"].as_array().unwrap().len(), 2);
+        assert_eq!(node["replication"]["key"].as_str(), Some("nested"));
+        assert!(!table.contains_key("lighthouse"));
+        assert!(!table.contains_key("config"));
+    }
+
+    #[test]
+    fn test_resolve_secret_files() {
+        let mut table: Table = toml::from_str(
+            r#"
+            [lighthouse]
+            lighthouse_key_file = "/run/secrets/lighthouse_key"
+            node_key = "inline"
+            state_file = "/var/lib/lighthouse.state"
+
+            [lighthouse.replication]
+            key_file = "/run/secrets/replication_key"
+            "#,
+        )
+        .unwrap();
+        let read_file = |path: &str| match path {
+            "/run/secrets/lighthouse_key" => Ok("from-file\n".to_string()),
+            "/run/secrets/replication_key" => Ok("replication".to_string()),
+            _ => Err("not found".to_string()),
+        };
+
+        resolve_secret_files(
+            &mut table,
+            &[
+                "lighthouse.lighthouse_key",
+                "lighthouse.node_key",
+                "lighthouse.admin_key",
+                "lighthouse.replication.key",
+            ],
+            read_file,
+        )
+        .unwrap();
+        let lighthouse = table["lighthouse"].as_table().unwrap();
+        assert_eq!(lighthouse["lighthouse_key"].as_str(), Some("from-file"));
+        assert!(!lighthouse.contains_key("lighthouse_key_file"));
+        assert_eq!(lighthouse["node_key"].as_str(), Some("inline"));
+        assert_eq!(
+            lighthouse["state_file"].as_str(),
+            Some("/var/lib/lighthouse.state")
+        );
+        assert_eq!(
+            lighthouse["replication"]["key"].as_str(),
+            Some("replication")
+        );
+
+        let mut table: Table =
+            toml::from_str("[lighthouse]\nnode_key = \"a\"\nnode_key_file = \"/missing\"\n")
+                .unwrap();
+        assert!(matches!(
+            resolve_secret_files(&mut table, &["lighthouse.node_key"], read_file),
+            Err(ConfigError::ReadingSecretFile(_, _))
+        ));
+        let mut table: Table =
+            toml::from_str("[lighthouse]\nnode_key_file = \"/missing\"\n").unwrap();
+        assert!(resolve_secret_files(&mut table, &["lighthouse.node_key"], read_file).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_reports_problems_with_lines() {
+        let contents = r#"
+[test]
+hostname = "-invalid"
+peers = [
+    "node1",
+    "node_2",
+]
+port = 2001
+"#;
+        let no_file = |_: &str| Err("no files".to_string());
+
+        let Err(ConfigError::InvalidConfig(problems)) =
+            parse_config::<TestConfigFile>(contents, vars(&[]), no_file)
+        else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].key, "test.hostname");
+        assert_eq!(problems[0].line, Some(3));
+        assert_eq!(problems[1].key, "test.peers[1]");
+        assert_eq!(problems[1].line, Some(6));
+        assert!(problems[1]
+            .to_string()
+            .starts_with("test.peers[1] (line 6): "));
+
+        let Err(ConfigError::InvalidConfig(problems)) = parse_config::<TestConfigFile>(
+            &contents.replace("2001", "\"not a port\""),
+            vars(&[]),
+            no_file,
+        ) else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(problems[0].key, "test.port");
+        assert_eq!(problems[0].line, Some(8));
+
+        let config = parse_config::<TestConfigFile>(
+            contents,
+            vars(&[
+                ("WGPULL_TEST__HOSTNAME", "node0"),
+                ("WGPULL_TEST__PEERS", r#"["node1"]"#),
+            ]),
+            no_file,
+        )
+        .unwrap();
+        assert_eq!(config.test.hostname, "node0");
+        assert_eq!(config.test.port, 2001);
+    }
+}
@@ -0,0 +1,219 @@
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Header line of data sealed with a passphrase, identifies the format and version.
+const SEALED_HEADER: &str = "WGPULL-SEALED-V1";
+
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+
+/// Prefix of secret values encrypted at rest, followed by the base64 encoded nonce and ciphertext.
+const SECRET_PREFIX: &str = "enc:v1:";
+
+/// Length of the symmetric encryption key.
+pub const KEY_LENGTH: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum CryptoError {
+    #[error("Error deriving key from passphrase: {0}")]
+    DerivingKey(String),
+    #[error("Error encrypting data")]
+    Encrypting,
+    #[error("Error decrypting data, wrong key or passphrase or corrupted data")]
+    Decrypting,
+    #[error("Invalid encrypted data format: {0}")]
+    InvalidFormat(String),
+    #[error("Secret key is empty")]
+    EmptyKey,
+}
+
+/// Derives a symmetric key from a passphrase and salt using argon2id.
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; KEY_LENGTH], CryptoError> {
+    let mut key = [0u8; KEY_LENGTH];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| CryptoError::DerivingKey(err.to_string()))?;
+    Ok(key)
+}
+
+/// Encrypts the plaintext with ChaCha20-Poly1305, returns the random nonce followed by the
+/// ciphertext.
+pub fn encrypt(key: &[u8; KEY_LENGTH], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| CryptoError::Encrypting)?;
+
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Decrypts data created by `encrypt`, fails if the key is wrong or the data was modified.
+pub fn decrypt(key: &[u8; KEY_LENGTH], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < NONCE_LENGTH {
+        return Err(CryptoError::InvalidFormat("data too short".to_string()));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CryptoError::Decrypting)
+}
+
+/// Encrypts the plaintext with a key derived from the passphrase.
+///
+/// The result is a portable text format: a header line followed by the base64 encoded
+/// salt, nonce and ciphertext.
+pub fn seal_with_passphrase(passphrase: &str, plaintext: &[u8]) -> Result<String, CryptoError> {
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt)?;
+
+    let mut data = salt.to_vec();
+    data.extend_from_slice(&encrypt(&key, plaintext)?);
+    Ok(format!("{}\n{}\n", SEALED_HEADER, STANDARD.encode(data)))
+}
+
+/// Decrypts data created by `seal_with_passphrase`.
+pub fn open_with_passphrase(passphrase: &str, sealed: &str) -> Result<Vec<u8>, CryptoError> {
+    let mut lines = sealed.lines();
+    if lines.next().map(str::trim) != Some(SEALED_HEADER) {
+        return Err(CryptoError::InvalidFormat(format!(
+            "expected {} header",
+            SEALED_HEADER
+        )));
+    }
+    let data = STANDARD
+        .decode(lines.collect::<String>().trim())
+        .map_err(|err| CryptoError::InvalidFormat(err.to_string()))?;
+    if data.len() < SALT_LENGTH {
+        return Err(CryptoError::InvalidFormat("data too short".to_string()));
+    }
+
+    let (salt, data) = data.split_at(SALT_LENGTH);
+    let key = derive_key(passphrase, salt)?;
+    decrypt(&key, data)
+}
+
+/// Compares a received key with the expected key in constant time.
+///
+/// Both keys are hashed first, so the time also doesn't depend on the length of the keys.
+pub fn verify_key(received: &str, expected: &str) -> bool {
+    let received = Sha256::digest(received.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    received.ct_eq(&expected).into()
+}
+
+/// Returns true if the value is a secret encrypted by `SecretKey::encrypt_secret`.
+pub fn is_encrypted_secret(value: &str) -> bool {
+    value.starts_with(SECRET_PREFIX)
+}
+
+/// Key used to encrypt secret values at rest, like private and pre-shared keys in state files.
+#[derive(Clone)]
+pub struct SecretKey([u8; KEY_LENGTH]);
+
+impl SecretKey {
+    /// Creates the key from key material, which is expected to be random (e.g. the output
+    /// of `openssl rand -base64 32`), it is hashed to the key length.
+    pub fn from_material(material: &str) -> Result<Self, CryptoError> {
+        let material = material.trim();
+        if material.is_empty() {
+            return Err(CryptoError::EmptyKey);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(b"wgpull-secret-key");
+        hasher.update(material.as_bytes());
+        Ok(Self(hasher.finalize().into()))
+    }
+
+    /// Encrypts the secret value, the result is prefixed to identify encrypted values.
+    pub fn encrypt_secret(&self, secret: &str) -> Result<String, CryptoError> {
+        let data = encrypt(&self.0, secret.as_bytes())?;
+        Ok(format!("{}{}", SECRET_PREFIX, STANDARD.encode(data)))
+    }
+
+    /// Decrypts a secret value encrypted by `encrypt_secret`, plaintext values are returned
+    /// unchanged to allow encrypting existing state files.
+    pub fn decrypt_secret(&self, value: &str) -> Result<String, CryptoError> {
+        let Some(encoded) = value.strip_prefix(SECRET_PREFIX) else {
+            return Ok(value.to_string());
+        };
+        let data = STANDARD
+            .decode(encoded)
+            .map_err(|err| CryptoError::InvalidFormat(err.to_string()))?;
+        String::from_utf8(decrypt(&self.0, &data)?)
+            .map_err(|err| CryptoError::InvalidFormat(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_encrypted_secret, open_with_passphrase, seal_with_passphrase, verify_key, CryptoError,
+        SecretKey,
+    };
+
+    #[test]
+    fn test_verify_key() {
+        assert!(verify_key("change_me", "change_me"));
+        assert!(!verify_key("change_mf", "change_me"));
+        assert!(!verify_key("change", "change_me"));
+        assert!(!verify_key("", "change_me"));
+    }
+
+    #[test]
+    fn test_seal_and_open_with_passphrase() {
+        let sealed = seal_with_passphrase("secret", b"hello world").unwrap();
+        assert!(sealed.starts_with("WGPULL-SEALED-V1\n"));
+        assert!(!sealed.contains("hello"));
+        assert_eq!(
+            open_with_passphrase("secret", &sealed).unwrap(),
+            b"hello world"
+        );
+
+        assert!(matches!(
+            open_with_passphrase("wrong", &sealed),
+            Err(CryptoError::Decrypting)
+        ));
+        assert!(matches!(
+            open_with_passphrase("secret", "hello world"),
+            Err(CryptoError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_secret() {
+        let key =
+            SecretKey::from_material("5Bk2qJmXW0rS1vA9rI3L0m4eHn1tY8uQ2wE6pZxC7dI=\n").unwrap();
+        let secret = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+
+        let encrypted = key.encrypt_secret(secret).unwrap();
+        assert!(is_encrypted_secret(&encrypted));
+        assert!(!encrypted.contains(secret));
+        assert_ne!(encrypted, key.encrypt_secret(secret).unwrap());
+        assert_eq!(key.decrypt_secret(&encrypted).unwrap(), secret);
+
+        // plaintext values are passed through, other keys fail to decrypt
+        assert_eq!(key.decrypt_secret(secret).unwrap(), secret);
+        let other = SecretKey::from_material("other").unwrap();
+        assert!(matches!(
+            other.decrypt_secret(&encrypted),
+            Err(CryptoError::Decrypting)
+        ));
+        assert!(matches!(
+            SecretKey::from_material(" \n"),
+            Err(CryptoError::EmptyKey)
+        ));
+    }
+}
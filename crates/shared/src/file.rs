@@ -1,17 +1,45 @@
-use std::fs::Permissions;
+use std::{
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
 
 #[async_trait]
 pub trait FileAccessor: Send + Sync {
     async fn write(&self, path: &str, content: &str) -> Result<()>;
+    /// Writes the file atomically, either the old or the new content is present in case of a crash.
+    /// The file is only readable and writable by the owner, as it is used for files with secrets.
+    async fn write_atomic(&self, path: &str, content: &str) -> Result<()>;
     async fn read(&self, path: &str) -> Result<String>;
+    async fn remove(&self, path: &str) -> Result<()>;
+    /// Lists the paths of all files in the directory.
+    async fn list_dir(&self, path: &str) -> Result<Vec<String>>;
     async fn set_permissions(&self, path: &str, permissions: Permissions) -> Result<()>;
 }
 
 pub struct SystemFileAccessor;
 
+/// Counter for unique names of temporary files written by this process.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Creates a new file only readable and writable by the owner and syncs its content to disk.
+async fn write_new_file(path: &str, content: &str) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+    file.write_all(content.as_bytes()).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
 #[async_trait]
 impl FileAccessor for SystemFileAccessor {
     async fn write(&self, path: &str, content: &str) -> Result<()> {
@@ -19,13 +47,137 @@ impl FileAccessor for SystemFileAccessor {
         Ok(())
     }
 
+    async fn write_atomic(&self, path: &str, content: &str) -> Result<()> {
+        // write to a temporary file next to the target and rename it, the rename is atomic,
+        // the name is unique so overlapping writes of the same file don't interleave
+        let temp_path = format!(
+            "{}.{}.{}.tmp",
+            path,
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let result = match write_new_file(&temp_path, content).await {
+            Ok(()) => tokio::fs::rename(&temp_path, path)
+                .await
+                .map_err(Into::into),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(err);
+        }
+
+        // persist the rename itself
+        let directory = match Path::new(path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        tokio::fs::File::open(directory).await?.sync_all().await?;
+        Ok(())
+    }
+
     async fn read(&self, path: &str) -> Result<String> {
         let content = tokio::fs::read_to_string(path).await?;
         Ok(content)
     }
 
+    async fn remove(&self, path: &str) -> Result<()> {
+        tokio::fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    async fn list_dir(&self, path: &str) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        let mut entries = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                paths.push(entry.path().to_string_lossy().to_string());
+            }
+        }
+        Ok(paths)
+    }
+
     async fn set_permissions(&self, path: &str, permissions: Permissions) -> Result<()> {
         tokio::fs::set_permissions(path, permissions).await?;
         Ok(())
     }
 }
+
+/// Verifies a file with secrets is not readable by group or others, a missing file is
+/// accepted as it is created with restricted permissions.
+pub fn check_private_file(path: &str) -> Result<()> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mode = metadata.permissions().mode();
+    if mode & 0o044 != 0 {
+        return Err(anyhow!(
+            "File {} is readable by group or others (mode {:o}), restrict it with: chmod 600 {}",
+            path,
+            mode & 0o777,
+            path
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use super::{check_private_file, FileAccessor, SystemFileAccessor};
+
+    #[tokio::test]
+    async fn test_write_atomic_private_file() {
+        let path = std::env::temp_dir().join(format!("wgpull-file-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(check_private_file(path).is_ok());
+
+        SystemFileAccessor
+            .write_atomic(path, "secret")
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(check_private_file(path).is_ok());
+
+        std::fs::set_permissions(path, Permissions::from_mode(0o640)).unwrap();
+        assert!(check_private_file(path).is_err());
+
+        // replacing the file restricts the permissions again
+        SystemFileAccessor
+            .write_atomic(path, "secret")
+            .await
+            .unwrap();
+        assert!(check_private_file(path).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_atomic_overlapping() {
+        let directory =
+            std::env::temp_dir().join(format!("wgpull-file-overlap-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("state");
+        let path = path.to_str().unwrap();
+
+        let (first, second) = tokio::join!(
+            SystemFileAccessor.write_atomic(path, "first"),
+            SystemFileAccessor.write_atomic(path, "second")
+        );
+        first.unwrap();
+        second.unwrap();
+
+        // one of the writes wins, no temporary files are left behind
+        let content = std::fs::read_to_string(path).unwrap();
+        assert!(content == "first" || content == "second");
+        let files = SystemFileAccessor
+            .list_dir(directory.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(files, vec![path.to_string()]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,3 +1,42 @@
 pub const HEADER_LIGHTHOUSE_KEY: &str = "X-Auth";
 pub const HEADER_NODE_CHALLENGE: &str = "X-Challenge";
 pub const HEADER_NODE_RESPONSE: &str = "X-Response";
+/// Signature of the response body by the lighthouse signing key, see `SignedDocument`.
+pub const HEADER_SIGNATURE: &str = "X-Signature";
+/// Signed payload of a response without a body, see `NodePullNotModified`.
+pub const HEADER_SIGNED_PAYLOAD: &str = "X-Signed-Payload";
+
+/// Formats the epoch of the lighthouse state and a configuration revision as an ETag header
+/// value, the epoch keeps revisions of different states apart.
+pub fn format_revision_etag(epoch: u64, revision: u64) -> String {
+    format!("\"{:x}-{}\"", epoch, revision)
+}
+
+/// Parses the epoch and configuration revision from an ETag or If-None-Match header value.
+pub fn parse_revision_etag(value: &str) -> Option<(u64, u64)> {
+    let (epoch, revision) = value
+        .trim()
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .split_once('-')?;
+    Some((u64::from_str_radix(epoch, 16).ok()?, revision.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_revision_etag, parse_revision_etag};
+
+    #[test]
+    fn test_revision_etag() {
+        let etag = format_revision_etag(0xbeef, 42);
+        assert_eq!(etag, "\"beef-42\"");
+        assert_eq!(parse_revision_etag(&etag), Some((0xbeef, 42)));
+        assert_eq!(parse_revision_etag(" W/\"beef-42\" "), Some((0xbeef, 42)));
+
+        // revisions without epoch (older lighthouses) and malformed values are ignored
+        assert_eq!(parse_revision_etag("\"42\""), None);
+        assert_eq!(parse_revision_etag("\"xyz-42\""), None);
+        assert_eq!(parse_revision_etag("\"beef-\""), None);
+        assert_eq!(parse_revision_etag("*"), None);
+    }
+}
@@ -2,11 +2,16 @@ pub mod challenge;
 pub mod client;
 pub mod command;
 pub mod config;
+pub mod crypto;
 pub mod file;
 pub mod headers;
 pub mod logger;
+pub mod prometheus;
 pub mod request;
 pub mod response;
+pub mod service;
+pub mod signing;
+pub mod state;
 pub mod time;
 pub mod validation;
 pub mod wg;
@@ -0,0 +1,59 @@
+use std::fmt::Display;
+
+/// Builds an export in the prometheus text format.
+#[derive(Default)]
+pub struct PrometheusExport {
+    export: String,
+}
+
+impl PrometheusExport {
+    /// Starts a metric family, all samples of the family have to follow.
+    pub fn family(&mut self, name: &str, metric_type: &str, help: &str) {
+        self.export.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, metric_type
+        ));
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.export.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                .collect();
+            self.export.push_str(&format!("{{{}}}", labels.join(",")));
+        }
+        self.export.push_str(&format!(" {}\n", value));
+    }
+
+    pub fn finish(self) -> String {
+        self.export
+    }
+}
+
+/// Escapes backslashes, quotes and line feeds in label values.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrometheusExport;
+
+    #[test]
+    fn test_prometheus_export() {
+        let mut export = PrometheusExport::default();
+        export.family("up", "gauge", "Whether the target is up.");
+        export.sample("up", &[], 1);
+        export.sample("up", &[("name", "a\"b\\c\nd"), ("port", "1")], 0.5);
+        assert_eq!(
+            export.finish(),
+            "# HELP up Whether the target is up.\n# TYPE up gauge\nup 1\n\
+             up{name=\"a\\\"b\\\\c\\nd\",port=\"1\"} 0.5\n"
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use serde::{Deserialize, Serialize};
 
 use crate::validation::{
-    validate_cidr, validate_hostname, validate_hostname_or_ip, validate_interface_name,
-    validate_wg_key, Validated, ValidationError,
+    validate_backend_name, validate_cidr, validate_hostname, validate_hostname_or_ip,
+    validate_interface_name, validate_wg_key, Validated, ValidationError,
 };
 
 /// The request sent by a node to the lighthouse.
@@ -22,6 +22,22 @@ pub struct NodePullRequest {
     pub allowed_ips: Vec<String>,
     /// Whether or not the allowed ips should route through the wireguard interface.
     pub route_allowed_ips: bool,
+    /// Result of the last attempt to apply a pulled configuration, none before the first apply.
+    #[serde(default)]
+    pub last_apply: Option<NodeApplyStatus>,
+}
+
+/// The result of a node applying the peer configuration to its local wireguard interface.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeApplyStatus {
+    /// Whether or not the configuration was applied successfully.
+    pub success: bool,
+    /// The error message in case the apply failed.
+    pub error: Option<String>,
+    /// The configuration revision that was applied, if known.
+    pub revision: Option<u64>,
+    /// The backend used to configure the interface (systemd / uci).
+    pub backend: String,
 }
 
 impl Validated for NodePullRequest {
@@ -33,6 +49,9 @@ impl Validated for NodePullRequest {
         for allowed_ip in &self.allowed_ips {
             validate_cidr("allowed_ip[]", allowed_ip)?;
         }
+        if let Some(last_apply) = &self.last_apply {
+            validate_backend_name("last_apply.backend", &last_apply.backend)?;
+        }
         Ok(())
     }
 }
@@ -57,6 +76,14 @@ pub struct NodeMetricsPushRequestPeer {
 
     /// Persistent keepalive interval of the peer.
     pub persistent_keepalive: i64,
+
+    /// Average round trip time through the tunnel in seconds, none if not probed or lost.
+    #[serde(default)]
+    pub rtt_seconds: Option<f64>,
+
+    /// Ratio of lost probes through the tunnel (0.0 - 1.0), none if not probed.
+    #[serde(default)]
+    pub loss_ratio: Option<f64>,
 }
 
 /// Pushes wireguard metrics to the lighthouse.
@@ -83,7 +110,46 @@ impl Validated for NodeMetricsPushRequest {
         for peer in &self.peers {
             validate_hostname("hostname", &peer.hostname)?;
             validate_hostname_or_ip("endpoint", &peer.endpoint)?;
+            if peer
+                .rtt_seconds
+                .is_some_and(|rtt| !rtt.is_finite() || rtt < 0.0)
+            {
+                return Err(ValidationError::InvalidFormat(
+                    "rtt_seconds",
+                    "Round trip time must be a positive number",
+                ));
+            }
+            if peer
+                .loss_ratio
+                .is_some_and(|loss| !(0.0..=1.0).contains(&loss))
+            {
+                return Err(ValidationError::InvalidFormat(
+                    "loss_ratio",
+                    "Loss ratio must be between 0 and 1",
+                ));
+            }
         }
         Ok(())
     }
 }
+
+/// Long-poll request of a node waiting for changes of its peer configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeWatchRequest {
+    /// The hostname of the local node.
+    pub hostname: String,
+
+    /// Revision of the peer configuration the node currently has.
+    pub revision: Option<u64>,
+
+    /// Maximum time in seconds to wait for changes, capped by the lighthouse.
+    pub timeout: u64,
+}
+
+impl Validated for NodeWatchRequest {
+    /// Validates the watch request, returns true if valid, false otherwise.
+    fn validate(&self) -> Result<(), ValidationError> {
+        validate_hostname("hostname", &self.hostname)?;
+        Ok(())
+    }
+}
@@ -1,12 +1,13 @@
 use serde::{Deserialize, Serialize};
 
+use crate::signing::SignedDocument;
 use crate::validation::{
     validate_cidr, validate_hostname, validate_hostname_or_ip, validate_wg_key, Validated,
     ValidationError,
 };
 
 /// The request sent by a node to the lighthouse.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NodePullResponsePeer {
     /// The hostname of the peer.
     pub hostname: String,
@@ -32,6 +33,46 @@ pub struct NodePullResponsePeer {
     /// Whether or not the allowed ips should route through the wireguard interface.
     /// Indicates if routes should be added for each allowed_ip entry.
     pub route_allowed_ips: bool,
+
+    /// The `PeerEndpointRecord` of the peer signed by the lighthouse, if it has a signing key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_endpoint: Option<SignedDocument>,
+}
+
+/// Endpoint of a node signed by the lighthouse, nodes exchange these directly with their
+/// peers to spread endpoint changes while the lighthouse is unreachable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerEndpointRecord {
+    /// The hostname of the node.
+    pub hostname: String,
+
+    /// The public key of the node.
+    pub public_key: String,
+
+    /// The endpoint host/ip of the node.
+    pub endpoint_host: String,
+
+    /// The endpoint port of the node.
+    pub endpoint_port: u32,
+
+    /// Unix timestamp the record was signed at, newer records replace older ones.
+    pub issued_at: u64,
+}
+
+impl Validated for PeerEndpointRecord {
+    fn validate(&self) -> Result<(), ValidationError> {
+        validate_hostname("hostname", &self.hostname)?;
+        validate_wg_key("public_key", &self.public_key)?;
+        validate_hostname_or_ip("endpoint_host", &self.endpoint_host)?;
+        Ok(())
+    }
+}
+
+/// The response sent by a node to a gossip request of a peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeGossipResponse {
+    /// Signed `PeerEndpointRecord`s of the node itself and its peers.
+    pub records: Vec<SignedDocument>,
 }
 
 /// The response sent by the lighthouse to a node pull request.
@@ -42,6 +83,41 @@ pub struct NodePullResponse {
 
     /// Peer configuration for the node provided by the lighthouse.
     pub peers: Vec<NodePullResponsePeer>,
+
+    /// Revision of the peer configuration, changes only if the peers of the node change.
+    #[serde(default)]
+    pub revision: u64,
+
+    /// The `PeerEndpointRecord` of the node itself signed by the lighthouse, if it has a
+    /// signing key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_endpoint: Option<SignedDocument>,
+    /// The hostname of the node the configuration is for, set if the response is signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+
+    /// Unix timestamp after which the signed response must no longer be used, set if the
+    /// response is signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+
+    /// Epoch of the lighthouse state the revision belongs to, set if the response is signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<u64>,
+}
+
+/// Signed by the lighthouse in place of the body of a not modified pull response, confirms
+/// that the configuration identified by the ETag is still current.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodePullNotModified {
+    /// The hostname of the node the confirmation is for.
+    pub hostname: String,
+
+    /// The ETag of the configuration that is still current.
+    pub etag: String,
+
+    /// Unix timestamp after which the confirmation must no longer be used.
+    pub expires_at: u64,
 }
 
 impl Validated for NodePullResponse {
@@ -60,3 +136,10 @@ impl Validated for NodePullResponse {
         Ok(())
     }
 }
+
+/// The response sent by the lighthouse to a node watch request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeWatchResponse {
+    /// Indicates that the peer configuration of the node changed and it should pull.
+    pub changed: bool,
+}
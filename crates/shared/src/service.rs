@@ -0,0 +1,66 @@
+use std::{future::Future, time::Duration};
+
+use log::{info, warn};
+use sd_notify::NotifyState;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Sends the state to the service manager, does nothing unless started by systemd with
+/// `Type=notify` (`NOTIFY_SOCKET` is set).
+fn notify(state: NotifyState) {
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify the service manager: {}", err);
+    }
+}
+
+/// Notifies the service manager that the startup finished.
+pub fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+/// Notifies the service manager that the service is shutting down.
+pub fn notify_stopping() {
+    notify(NotifyState::Stopping);
+}
+
+/// Pings the watchdog of the service manager.
+pub fn notify_watchdog() {
+    notify(NotifyState::Watchdog);
+}
+
+/// Returns the interval to ping the watchdog in (half of the `WatchdogSec` of the unit), none if
+/// the watchdog isn't enabled.
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) && usec > 0 {
+        Some(Duration::from_micros(usec) / 2)
+    } else {
+        None
+    }
+}
+
+/// Returns a future completing once the process receives SIGTERM or SIGINT, then notifies the
+/// service manager that the service is shutting down.
+///
+/// The signal handlers are registered immediately, signals received before the future is
+/// awaited are not lost and no longer terminate the process.
+pub fn shutdown_signal() -> impl Future<Output = ()> {
+    let terminate = signal(SignalKind::terminate());
+    let interrupt = signal(SignalKind::interrupt());
+
+    async move {
+        match (terminate, interrupt) {
+            (Ok(mut terminate), Ok(mut interrupt)) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = interrupt.recv() => {}
+                }
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("Failed to listen for shutdown signals: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+        info!("Shutting down.");
+        notify_stopping();
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Display;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("Signing key is empty")]
+    EmptyKey,
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("Invalid signature format: {0}")]
+    InvalidSignature(String),
+    #[error("Signature verification failed")]
+    VerificationFailed,
+    #[error("Error serializing/unserializing a signed document: {0}")]
+    Serialization(String),
+}
+
+/// Ed25519 key the lighthouse signs documents with, nodes verify them with the public key.
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    /// Creates the key from key material, which is expected to be random (e.g. the output
+    /// of `openssl rand -base64 32`), it is hashed to the seed of the key.
+    pub fn from_material(material: &str) -> Result<Self, SigningError> {
+        let material = material.trim();
+        if material.is_empty() {
+            return Err(SigningError::EmptyKey);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(b"wgpull-signing-key");
+        hasher.update(material.as_bytes());
+        Ok(Self(ed25519_dalek::SigningKey::from_bytes(
+            &hasher.finalize().into(),
+        )))
+    }
+
+    /// Returns the public key to verify the signatures of this key.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    /// Serializes the document and signs the serialized payload.
+    pub fn sign<T: Serialize>(&self, document: &T) -> Result<SignedDocument, SigningError> {
+        let payload = serde_json::to_string(document)
+            .map_err(|err| SigningError::Serialization(err.to_string()))?;
+        let signature = self.0.sign(payload.as_bytes());
+        Ok(SignedDocument {
+            payload,
+            signature: STANDARD.encode(signature.to_bytes()),
+        })
+    }
+}
+
+/// Public key to verify signed documents, base64 encoded in configuration files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey(ed25519_dalek::VerifyingKey);
+
+impl PublicKey {
+    pub fn from_base64(encoded: &str) -> Result<Self, SigningError> {
+        let bytes: [u8; 32] = STANDARD
+            .decode(encoded.trim())
+            .map_err(|err| SigningError::InvalidPublicKey(err.to_string()))?
+            .try_into()
+            .map_err(|_| SigningError::InvalidPublicKey("expected 32 bytes".to_string()))?;
+        ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+            .map(Self)
+            .map_err(|err| SigningError::InvalidPublicKey(err.to_string()))
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", STANDARD.encode(self.0.as_bytes()))
+    }
+}
+
+/// Document signed by the lighthouse, the signature covers the exact serialized payload so the
+/// document can be relayed without depending on a canonical serialization.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedDocument {
+    /// The document serialized as JSON.
+    pub payload: String,
+    /// Base64 encoded Ed25519 signature of the payload.
+    pub signature: String,
+}
+
+impl SignedDocument {
+    /// Verifies the signature with the public key and returns the deserialized document.
+    pub fn verify<T: DeserializeOwned>(&self, key: &PublicKey) -> Result<T, SigningError> {
+        let signature = STANDARD
+            .decode(&self.signature)
+            .map_err(|err| SigningError::InvalidSignature(err.to_string()))?;
+        let signature = Signature::from_slice(&signature)
+            .map_err(|err| SigningError::InvalidSignature(err.to_string()))?;
+        key.0
+            .verify(self.payload.as_bytes(), &signature)
+            .map_err(|_| SigningError::VerificationFailed)?;
+        serde_json::from_str(&self.payload)
+            .map_err(|err| SigningError::Serialization(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PublicKey, SigningError, SigningKey};
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key =
+            SigningKey::from_material("5Bk2qJmXW0rS1vA9rI3L0m4eHn1tY8uQ2wE6pZxC7dI=\n").unwrap();
+        let public_key = PublicKey::from_base64(&key.public_key().to_string()).unwrap();
+        assert_eq!(public_key, key.public_key());
+
+        let signed = key.sign(&vec![1, 2, 3]).unwrap();
+        assert_eq!(signed.payload, "[1,2,3]");
+        assert_eq!(
+            signed.verify::<Vec<u32>>(&public_key).unwrap(),
+            vec![1, 2, 3]
+        );
+
+        // modified payloads and other keys are rejected
+        let mut modified = signed.clone();
+        modified.payload = "[1,2,4]".to_string();
+        assert!(matches!(
+            modified.verify::<Vec<u32>>(&public_key),
+            Err(SigningError::VerificationFailed)
+        ));
+        let other = SigningKey::from_material("other").unwrap().public_key();
+        assert!(matches!(
+            signed.verify::<Vec<u32>>(&other),
+            Err(SigningError::VerificationFailed)
+        ));
+
+        assert!(matches!(
+            SigningKey::from_material(" \n"),
+            Err(SigningError::EmptyKey)
+        ));
+        assert!(matches!(
+            PublicKey::from_base64("aGVsbG8="),
+            Err(SigningError::InvalidPublicKey(_))
+        ));
+    }
+}
@@ -0,0 +1,388 @@
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+use toml::{Table, Value};
+
+use crate::{
+    crypto::{is_encrypted_secret, SecretKey},
+    file::FileAccessor,
+};
+
+/// Environment variable containing the key to encrypt secrets in state files.
+pub const STATE_KEY_ENV: &str = "WGPULL_STATE_KEY";
+
+/// Name of the systemd credential containing the key to encrypt secrets in state files,
+/// read from the credentials directory (see `LoadCredential=` in systemd.exec).
+pub const STATE_KEY_CREDENTIAL: &str = "wgpull-state-key";
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("Error parsing state file {0}: {1}")]
+    ParsingStateFile(String, String),
+    #[error("State file {0} has version {1}, but only version {2} is supported")]
+    UnsupportedVersion(String, u32, u32),
+    #[error("Error migrating state file {0} from version {1}: {2}")]
+    MigratingStateFile(String, u32, String),
+    #[error("Error writing state file {0}: {1}")]
+    WritingStateFile(String, String),
+    #[error("Error serializing state: {0}")]
+    SerializingState(String),
+    #[error("Error encrypting secrets of state: {0}")]
+    EncryptingSecrets(String),
+    #[error("Error decrypting secrets of state file {0}: {1}")]
+    DecryptingSecrets(String, String),
+    #[error("State file {0} contains encrypted secrets, but no state key is configured")]
+    MissingStateKey(String),
+    #[error("Error reading state key {0}: {1}")]
+    ReadingStateKey(String, String),
+}
+
+/// A state with secret fields, the secrets are encrypted in the state file if a state key
+/// is configured.
+pub trait SecretFields {
+    /// Returns all secret fields of the state.
+    fn secrets_mut(&mut self) -> Vec<&mut String>;
+
+    /// Replaces all secret fields, used to display the state without leaking secrets.
+    fn redact_secrets(&mut self) {
+        for secret in self.secrets_mut() {
+            *secret = REDACTED_SECRET.to_string();
+        }
+    }
+}
+
+/// Placeholder of secret values in redacted states.
+pub const REDACTED_SECRET: &str = "(redacted)";
+
+/// Migrates the state from the previous version to the next version.
+///
+/// The migration at index `n` of a migration chain migrates from version `n` to `n + 1`,
+/// the current version of a state is the length of its migration chain.
+pub type StateMigration = fn(&mut Table) -> Result<(), String>;
+
+/// The state file envelope, storing the state with its version.
+#[derive(Serialize)]
+struct StateEnvelope<'a, T> {
+    version: u32,
+    state: &'a T,
+}
+
+/// Serializes the state in TOML format, wrapped in an envelope with the current version.
+///
+/// The secret fields of the state are encrypted if a key is given.
+pub fn serialize_versioned_state<T: Serialize + SecretFields + Clone>(
+    state: &T,
+    migrations: &[StateMigration],
+    key: Option<&SecretKey>,
+) -> Result<String, StateError> {
+    let encrypted;
+    let state = match key {
+        Some(key) => {
+            let mut copy = state.clone();
+            for secret in copy.secrets_mut() {
+                *secret = key
+                    .encrypt_secret(secret)
+                    .map_err(|err| StateError::EncryptingSecrets(err.to_string()))?;
+            }
+            encrypted = copy;
+            &encrypted
+        }
+        None => state,
+    };
+
+    toml::to_string(&StateEnvelope {
+        version: migrations.len() as u32,
+        state,
+    })
+    .map_err(|err| StateError::SerializingState(err.to_string()))
+}
+
+/// Parses a state in TOML format, migrating it from older versions.
+///
+/// State files without version are unversioned files written before the envelope was
+/// introduced (version 0). Encrypted secrets are decrypted with the key, plaintext secrets
+/// are accepted either way. Returns the state and the version of the parsed file.
+pub fn parse_versioned_state<T: DeserializeOwned + SecretFields>(
+    path: &str,
+    contents: &str,
+    migrations: &[StateMigration],
+    key: Option<&SecretKey>,
+) -> Result<(T, u32), StateError> {
+    let current_version = migrations.len() as u32;
+    let mut document: Table = toml::from_str(contents)
+        .map_err(|err| StateError::ParsingStateFile(path.to_string(), err.to_string()))?;
+
+    let version = match document.get("version") {
+        None => 0,
+        Some(Value::Integer(version)) if *version >= 0 => *version as u32,
+        Some(_) => {
+            return Err(StateError::ParsingStateFile(
+                path.to_string(),
+                "version must be a positive integer".to_string(),
+            ))
+        }
+    };
+    if version > current_version {
+        return Err(StateError::UnsupportedVersion(
+            path.to_string(),
+            version,
+            current_version,
+        ));
+    }
+
+    let mut state = if version == 0 {
+        document
+    } else {
+        match document.remove("state") {
+            Some(Value::Table(state)) => state,
+            _ => {
+                return Err(StateError::ParsingStateFile(
+                    path.to_string(),
+                    "missing [state] table".to_string(),
+                ))
+            }
+        }
+    };
+
+    for (from_version, migration) in migrations.iter().enumerate().skip(version as usize) {
+        migration(&mut state).map_err(|err| {
+            StateError::MigratingStateFile(path.to_string(), from_version as u32, err)
+        })?;
+    }
+
+    let mut state = T::deserialize(state)
+        .map_err(|err| StateError::ParsingStateFile(path.to_string(), err.to_string()))?;
+
+    for secret in state.secrets_mut() {
+        if !is_encrypted_secret(secret) {
+            continue;
+        }
+        let Some(key) = key else {
+            return Err(StateError::MissingStateKey(path.to_string()));
+        };
+        *secret = key
+            .decrypt_secret(secret)
+            .map_err(|err| StateError::DecryptingSecrets(path.to_string(), err.to_string()))?;
+    }
+
+    Ok((state, version))
+}
+
+/// Reads a versioned state file, returns none if the file doesn't exist.
+///
+/// Files of older versions are migrated, the old file is kept as a backup next to the
+/// state file (e.g. `<path>.v0.bak`) before it is replaced by the migrated state.
+pub async fn read_versioned_state_file<T: DeserializeOwned + Serialize + SecretFields + Clone>(
+    path: &str,
+    accessor: &dyn FileAccessor,
+    migrations: &[StateMigration],
+    key: Option<&SecretKey>,
+) -> Result<Option<T>, StateError> {
+    let contents = match accessor.read(path).await {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None),
+    };
+
+    let (state, version) = parse_versioned_state::<T>(path, &contents, migrations, key)?;
+
+    if version < migrations.len() as u32 {
+        let backup_path = format!("{}.v{}.bak", path, version);
+        info!(
+            "Migrating state file {} from version {} to {}, backup in {}",
+            path,
+            version,
+            migrations.len(),
+            backup_path
+        );
+        // the backup contains the same secrets as the state file, it is created with the
+        // same restricted permissions
+        accessor
+            .write_atomic(&backup_path, &contents)
+            .await
+            .map_err(|err| StateError::WritingStateFile(backup_path.clone(), err.to_string()))?;
+
+        let migrated = serialize_versioned_state(&state, migrations, key)?;
+        accessor
+            .write_atomic(path, &migrated)
+            .await
+            .map_err(|err| StateError::WritingStateFile(path.to_string(), err.to_string()))?;
+    }
+
+    Ok(Some(state))
+}
+
+/// Loads the key to encrypt secrets in state files, returns none if no key is configured.
+///
+/// The key is read from the key file if configured, otherwise from the `WGPULL_STATE_KEY`
+/// environment variable or the `wgpull-state-key` systemd credential.
+pub async fn load_state_key(
+    key_file: Option<&str>,
+    accessor: &dyn FileAccessor,
+) -> Result<Option<SecretKey>, StateError> {
+    let (source, material) = if let Some(key_file) = key_file {
+        let material = accessor
+            .read(key_file)
+            .await
+            .map_err(|err| StateError::ReadingStateKey(key_file.to_string(), err.to_string()))?;
+        (key_file.to_string(), material)
+    } else if let Ok(material) = std::env::var(STATE_KEY_ENV) {
+        (STATE_KEY_ENV.to_string(), material)
+    } else if let Ok(directory) = std::env::var("CREDENTIALS_DIRECTORY") {
+        let path = format!("{}/{}", directory, STATE_KEY_CREDENTIAL);
+        match accessor.read(&path).await {
+            Ok(material) => (path, material),
+            Err(_) => return Ok(None),
+        }
+    } else {
+        return Ok(None);
+    };
+
+    info!("Encrypting secrets in state files with key from {}", source);
+    SecretKey::from_material(&material)
+        .map(Some)
+        .map_err(|err| StateError::ReadingStateKey(source, err.to_string()))
+}
+
+/// Returns the table stored under the key, errors if the value is not a table.
+pub fn table_mut<'a>(table: &'a mut Table, key: &str) -> Result<Option<&'a mut Table>, String> {
+    match table.get_mut(key) {
+        None => Ok(None),
+        Some(Value::Table(table)) => Ok(Some(table)),
+        Some(_) => Err(format!("{} is not a table", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{
+        parse_versioned_state, read_versioned_state_file, serialize_versioned_state, SecretFields,
+        StateError, StateMigration,
+    };
+    use crate::{crypto::SecretKey, file::SystemFileAccessor};
+    use serde::{Deserialize, Serialize};
+    use toml::Value;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct TestState {
+        name: String,
+        count: i64,
+    }
+
+    impl SecretFields for TestState {
+        fn secrets_mut(&mut self) -> Vec<&mut String> {
+            vec![&mut self.name]
+        }
+    }
+
+    const MIGRATIONS: &[StateMigration] = &[|state| {
+        state.insert("count".to_string(), Value::Integer(0));
+        Ok(())
+    }];
+
+    #[test]
+    fn test_parse_unversioned_state() {
+        let (state, version) =
+            parse_versioned_state::<TestState>("test", "name = \"a\"\n", MIGRATIONS, None).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(
+            state,
+            TestState {
+                name: "a".to_string(),
+                count: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migration_backup_private_file() {
+        let path = std::env::temp_dir().join(format!("wgpull-state-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "name = \"secret\"\n").unwrap();
+
+        let state =
+            read_versioned_state_file::<TestState>(path, &SystemFileAccessor, MIGRATIONS, None)
+                .await
+                .unwrap();
+        assert_eq!(state.unwrap().count, 0);
+
+        // the backup of the unversioned file holds the plaintext secrets
+        let backup_path = format!("{}.v0.bak", path);
+        let mode = std::fs::metadata(&backup_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_file(&backup_path).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_serialize_and_parse_versioned_state() {
+        let state = TestState {
+            name: "b".to_string(),
+            count: 3,
+        };
+        let contents = serialize_versioned_state(&state, MIGRATIONS, None).unwrap();
+        assert!(contents.starts_with("version = 1\n"));
+        let (parsed, version) =
+            parse_versioned_state::<TestState>("test", &contents, MIGRATIONS, None).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(parsed, state);
+    }
+
+    #[test]
+    fn test_parse_state_errors() {
+        let result = parse_versioned_state::<TestState>("test", "version = 2\n", MIGRATIONS, None);
+        assert!(matches!(
+            result,
+            Err(StateError::UnsupportedVersion(_, 2, 1))
+        ));
+
+        // parse errors report the location of the error
+        let result = parse_versioned_state::<TestState>("test", "name = \n", MIGRATIONS, None);
+        let Err(StateError::ParsingStateFile(_, message)) = result else {
+            panic!("expected parse error");
+        };
+        assert!(message.contains("line 1"));
+
+        let result = parse_versioned_state::<TestState>(
+            "test",
+            "version = 1\n[state]\nname = 1\n",
+            MIGRATIONS,
+            None,
+        );
+        assert!(matches!(result, Err(StateError::ParsingStateFile(_, _))));
+    }
+
+    #[test]
+    fn test_encrypted_secrets() {
+        let key = SecretKey::from_material("key").unwrap();
+        let state = TestState {
+            name: "secret".to_string(),
+            count: 3,
+        };
+        let contents = serialize_versioned_state(&state, MIGRATIONS, Some(&key)).unwrap();
+        assert!(!contents.contains("secret"));
+        assert!(contents.contains("enc:v1:"));
+
+        let (parsed, _) =
+            parse_versioned_state::<TestState>("test", &contents, MIGRATIONS, Some(&key)).unwrap();
+        assert_eq!(parsed, state);
+
+        // encrypted secrets can't be read without the key or with another key
+        let result = parse_versioned_state::<TestState>("test", &contents, MIGRATIONS, None);
+        assert!(matches!(result, Err(StateError::MissingStateKey(_))));
+        let other = SecretKey::from_material("other").unwrap();
+        let result =
+            parse_versioned_state::<TestState>("test", &contents, MIGRATIONS, Some(&other));
+        assert!(matches!(result, Err(StateError::DecryptingSecrets(_, _))));
+
+        // plaintext secrets are read with a key
+        let contents = serialize_versioned_state(&state, MIGRATIONS, None).unwrap();
+        let (parsed, _) =
+            parse_versioned_state::<TestState>("test", &contents, MIGRATIONS, Some(&key)).unwrap();
+        assert_eq!(parsed, state);
+    }
+}
@@ -1,5 +1,5 @@
 use chrono::{DateTime, Local};
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Trait for getting the current time.
 /// This is used to allow mocking the time in tests.
@@ -13,6 +13,13 @@ pub trait CurrentTime {
     }
 }
 
+/// Converts a system time to a unix timestamp in seconds.
+pub fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
 #[derive(Default)]
 pub struct SystemCurrentTime;
 
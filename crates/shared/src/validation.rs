@@ -164,3 +164,22 @@ pub fn validate_interface_name(name: &'static str, interface: &str) -> Result<()
 
     Ok(())
 }
+
+pub fn validate_backend_name(name: &'static str, backend: &str) -> Result<(), ValidationError> {
+    if backend.is_empty() {
+        return Err(ValidationError::EmptyValue(name));
+    }
+
+    if backend.len() > 32
+        || !backend
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(ValidationError::InvalidFormat(
+            name,
+            "Backend name contains invalid characters",
+        ));
+    }
+
+    Ok(())
+}